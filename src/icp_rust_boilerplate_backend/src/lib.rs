@@ -1,14 +1,108 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+use candid::{Decode, Encode, Principal};
+use chrono::{DateTime, Datelike, Months, SecondsFormat};
+#[cfg(not(test))]
 use ic_cdk::api::time;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use std::{borrow::Cow, cell::RefCell, time::Duration};
+#[cfg(test)]
+use tests::time;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
 
+const NANOS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+const NANOS_PER_HOUR: u64 = 60 * NANOS_PER_MINUTE;
+const NANOS_PER_DAY: u64 = 24 * NANOS_PER_HOUR;
+
+// Longest consultation series that can be booked ahead of time
+const MAX_SERIES_OCCURRENCES: u32 = 24;
+// Longest gap between the consultations of a series, in weeks or months
+const MAX_RECURRENCE_INTERVAL: u32 = 52;
+// Most status changes a consultation records; reschedules stop early enough to
+// leave room for the CONSULTATION_LIFECYCLE_CHANGES still to come
+const MAX_CONSULTATION_STATUS_CHANGES: usize = 16;
+const CONSULTATION_LIFECYCLE_CHANGES: usize = 3;
+// Longest single consultation, in minutes
+const MAX_CONSULTATION_MINUTES: u32 = 8 * 60;
+// Length given to consultations migrated from before consultations had an end time
+const LEGACY_CONSULTATION_MINUTES: u64 = 30;
+
+// Domain part of calendar event UIDs, so calendar apps can match events on re-import
+const ICS_UID_DOMAIN: &str = "elderly-care-platform";
+// Length of medication dose events in exported calendars, in minutes
+const ICS_DOSE_EVENT_MINUTES: u64 = 15;
+
+// Limits on the free-text fields of health records, which are quoted in alerts
+const MAX_BLOOD_PRESSURE_LENGTH: usize = 20;
+const MAX_ACTIVITY_LEVEL_LENGTH: usize = 100;
+
+// Longest comment accepted with consultation feedback, in bytes
+const MAX_FEEDBACK_COMMENT_LENGTH: usize = 1000;
+
+// Limits that keep medication reminders and the notifications quoting them
+// within their storage bounds
+const MAX_MEDICATION_NAME_LENGTH: usize = 100;
+const MAX_DOSE_TIMES_PER_DAY: usize = 24;
+const MIN_DOSAGE_QUANTITY: f64 = 0.001;
+const MAX_DOSAGE_QUANTITY: f64 = 100_000.0;
+
+// Longest window an adherence report covers
+const MAX_ADHERENCE_WINDOW_DAYS: u64 = 90;
+
+// Limits that keep drug interactions and the warnings copied onto medication
+// reminders within their storage bounds
+const MAX_DRUG_NAME_LENGTH: usize = 100;
+const MAX_INTERACTION_DESCRIPTION_LENGTH: usize = 500;
+const MAX_INTERACTION_WARNINGS: usize = 32;
+
+// Longest reason that can be given for a status change
+const MAX_STATUS_REASON_LENGTH: usize = 500;
+// Most status changes a prescription records; the last one is kept for discontinuing it
+const MAX_PRESCRIPTION_STATUS_CHANGES: usize = 20;
+
+// Limits that keep allergies and the warnings copied onto records within their storage bounds
+const MAX_ALLERGEN_LENGTH: usize = 100;
+const MAX_ALLERGY_REACTION_LENGTH: usize = 200;
+const MAX_ALLERGY_WARNINGS: usize = 16;
+
+// Limits that keep consultation notes within their storage bound, in bytes
+const MAX_NOTE_SECTION_LENGTH: usize = 1500;
+const MAX_FOLLOW_UP_ACTIONS: usize = 10;
+const MAX_FOLLOW_UP_DESCRIPTION_LENGTH: usize = 150;
+
+// Limits that keep provider profiles within their storage bound, in bytes
+const MAX_PROVIDER_BIO_LENGTH: usize = 2000;
+const MAX_PROVIDER_LANGUAGES: usize = 10;
+const MAX_LANGUAGE_LENGTH: usize = 40;
+const MAX_LICENCE_NUMBER_LENGTH: usize = 50;
+const MAX_CURRENCY_LENGTH: usize = 10;
+
+// Most records that can be attached to a referral
+const MAX_REFERRAL_ATTACHMENTS: usize = 50;
+
+// Limits that keep diet records, meal plans and the food catalogue within
+// their storage bounds
+const MAX_MEAL_ITEMS: usize = 12;
+const MAX_PLANNED_MEAL_ITEMS: usize = 6;
+const MAX_FOOD_NAME_LENGTH: usize = 60;
+const MAX_SERVING_DESCRIPTION_LENGTH: usize = 100;
+// Most servings of one item in a meal, which keeps nutrient totals finite
+const MAX_SERVINGS: f64 = 50.0;
+// Largest nutrient amount per serving, which keeps totals and the figures
+// quoted in dietary conflicts short
+const MAX_NUTRIENT_AMOUNT: f64 = 100_000.0;
+// Most dietary conflicts kept for one entry; the rest are counted in a last one
+const MAX_DIETARY_CONFLICTS: usize = 8;
+
+// Per-meal limits used to flag meals against therapeutic diets
+const DIABETIC_MAX_SUGAR_GRAMS_PER_MEAL: f64 = 15.0;
+const LOW_SODIUM_MAX_SODIUM_MILLIGRAMS_PER_MEAL: f64 = 600.0;
+const RENAL_MAX_SODIUM_MILLIGRAMS_PER_MEAL: f64 = 700.0;
+const RENAL_MAX_PROTEIN_GRAMS_PER_MEAL: f64 = 25.0;
+
 // UserType enum
 #[derive(
     candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
@@ -85,6 +179,370 @@ enum StressLevel {
     High,
 }
 
+// DoseStatus enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum DoseStatus {
+    #[default]
+    Taken,
+    Skipped,
+    Missed,
+    Late,
+}
+
+// NotificationType enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum NotificationType {
+    #[default]
+    DoseDue,
+    DoseMissed,
+    Alert,
+    DietReview,
+}
+
+// InteractionSeverity enum, from least to most severe
+#[derive(
+    candid::CandidType,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Default,
+    Debug,
+)]
+enum InteractionSeverity {
+    #[default]
+    Minor,
+    Moderate,
+    Major,
+    Contraindicated,
+}
+
+// PrescriptionStatus enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum PrescriptionStatus {
+    #[default]
+    Active,
+    Paused,
+    Discontinued,
+}
+
+// ConsultationStatus enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum ConsultationStatus {
+    #[default]
+    Requested,
+    Confirmed,
+    InProgress,
+    Completed,
+    Cancelled,
+    NoShow,
+}
+
+// RecurrenceFrequency enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum RecurrenceFrequency {
+    #[default]
+    Weekly,
+    Monthly,
+}
+
+// ProviderSpecialty enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum ProviderSpecialty {
+    #[default]
+    GeneralPractice,
+    Geriatrics,
+    Physiotherapy,
+    OccupationalTherapy,
+    Cardiology,
+    Neurology,
+    Psychiatry,
+    Nutrition,
+    Nursing,
+    Other,
+}
+
+// ReferralStatus enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum ReferralStatus {
+    #[default]
+    Sent,
+    Accepted,
+    Declined,
+    Completed,
+}
+
+// ReferralRecordType enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum ReferralRecordType {
+    #[default]
+    HealthRecord,
+    MentalHealthRecord,
+    MedicationReminder,
+    Prescription,
+    ConsultationNote,
+}
+
+// Nutrient enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum Nutrient {
+    #[default]
+    Calories,
+    Protein,
+    Carbohydrate,
+    Fat,
+    Sodium,
+    Sugar,
+}
+
+// SummaryPeriod enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum SummaryPeriod {
+    #[default]
+    Daily,
+    Weekly,
+}
+
+// TargetStatus enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum TargetStatus {
+    #[default]
+    Met,
+    Under,
+    Over,
+}
+
+// IddsiLevel enum, the IDDSI framework levels for texture-modified foods (3-7)
+// and thickened drinks (0-4)
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum IddsiLevel {
+    Thin,             // 0
+    SlightlyThick,    // 1
+    MildlyThick,      // 2
+    ModeratelyThick,  // 3, also liquidised food
+    ExtremelyThick,   // 4, also pureed food
+    MincedAndMoist,   // 5
+    SoftAndBiteSized, // 6
+    #[default]
+    Regular, // 7
+}
+
+// DietType enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum DietType {
+    #[default]
+    Diabetic,
+    Renal,
+    LowSodium,
+}
+
+// DietEntryType enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum DietEntryType {
+    #[default]
+    DietRecord,
+    MealPlan,
+}
+
+// BeverageType enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum BeverageType {
+    #[default]
+    Water,
+    Tea,
+    Coffee,
+    Juice,
+    Milk,
+    Soup,
+    OralSupplement,
+    Other,
+}
+
+// AllergyCategory enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum AllergyCategory {
+    #[default]
+    Medication,
+    Food,
+    Environmental,
+}
+
+// AllergySeverity enum, from least to most severe
+#[derive(
+    candid::CandidType,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Default,
+    Debug,
+)]
+enum AllergySeverity {
+    #[default]
+    Mild,
+    Moderate,
+    Severe,
+}
+
+// DoseUnit enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum DoseUnit {
+    #[default]
+    Mg,
+    Mcg,
+    G,
+    Ml,
+    Iu,
+    Count, // A number of the dosage form, e.g. 2 tablets or 2 puffs
+}
+
+// DosageForm enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum DosageForm {
+    #[default]
+    Tablet,
+    Capsule,
+    Liquid,
+    Puff,
+    Injection,
+    Patch,
+    Drops,
+    Cream,
+    Other,
+}
+
+// Weekday enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum Weekday {
+    #[default]
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+// TimeOfDay struct, in the user's local time
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+struct TimeOfDay {
+    hour: u8,
+    minute: u8,
+}
+
+// ScheduleFrequency enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, PartialEq, Eq, Default, Debug)]
+enum ScheduleFrequency {
+    #[default]
+    AsNeeded,
+    FixedTimes {
+        times: Vec<TimeOfDay>,
+    },
+    EveryNHours {
+        interval_hours: u32,
+    },
+    Weekdays {
+        days: Vec<Weekday>,
+        times: Vec<TimeOfDay>,
+    },
+}
+
+// InteractionWarning struct, attached to a medication reminder when it was
+// created; the description is read from the drug interaction table by ID
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Debug)]
+struct InteractionWarning {
+    drug_interaction_id: u64,
+    interacting_medication_reminder_id: u64,
+    severity: InteractionSeverity,
+}
+
+// AllergyWarning struct, attached to a record that matched a recorded
+// allergen; the substance and reaction are read from the allergy by ID
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Debug)]
+struct AllergyWarning {
+    allergy_id: u64,
+    severity: AllergySeverity,
+}
+
+// Dosage struct, the amount given per dose
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Debug, PartialEq)]
+struct Dosage {
+    quantity: f64,
+    unit: DoseUnit,
+    form: DosageForm,
+}
+
+// MedicationSchedule struct
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Debug)]
+struct MedicationSchedule {
+    frequency: ScheduleFrequency,
+    start_date: u64,
+    end_date: Option<u64>,
+}
+
+// AlertType enum
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug,
+)]
+enum AlertType {
+    #[default]
+    CriticalHealthStatus,
+    LowMedicationSupply,
+    LowFluidIntake,
+    RapidWeightGain,
+}
+
 // User struct
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct User {
@@ -92,6 +550,10 @@ struct User {
     name: String,
     contact: String,
     user_type: UserType,
+    utc_offset_minutes: Option<i32>, // Unset for users created before time zones; read as UTC
+    height_cm: Option<f64>,
+    weight_kg: Option<f64>,   // Latest recorded weight
+    owner: Option<Principal>, // Principal that created the user; unset for users created before it was recorded
     created_at: u64,
 }
 
@@ -113,20 +575,80 @@ struct MedicationReminder {
     id: u64,
     user_id: u64,
     medication_name: String,
-    dosage: String,
-    schedule: String,
+    dosage: Dosage,
+    schedule: MedicationSchedule,
+    interaction_warnings: Vec<InteractionWarning>,
+    allergy_warnings: Vec<AllergyWarning>,
+    prescription_id: Option<u64>,
+    legacy_entry: Option<String>, // Original free text of a migrated reminder that could not be fully parsed
     created_at: u64,
 }
 
+// ConsultationStatusChange struct, one entry per status transition
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ConsultationStatusChange {
+    status: ConsultationStatus,
+    reason: String,
+    changed_by: u64,
+    changed_at: u64,
+}
+
 // VirtualConsultation struct
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct VirtualConsultation {
     id: u64,
     user_id: u64,
     provider_id: u64,
+    booked_by: u64,
+    slot_id: u64,
+    series_id: Option<u64>,
     scheduled_at: u64,
-    status: String,
+    ends_at: u64,
+    status: ConsultationStatus,
+    status_history: Vec<ConsultationStatusChange>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+// Nutrients struct, amounts per serving or totals for a meal
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Debug, PartialEq)]
+struct Nutrients {
+    calories: f64,
+    protein_grams: f64,
+    carbohydrate_grams: f64,
+    fat_grams: f64,
+    sodium_milligrams: f64,
+    sugar_grams: f64,
+}
+
+// FoodItem struct, an entry in the food catalogue
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct FoodItem {
+    id: u64,
+    name: String,
+    serving_description: String, // e.g. "1 cup (240 ml)"
+    nutrients_per_serving: Nutrients,
+    is_drink: Option<bool>,
+    iddsi_level: Option<IddsiLevel>,
     created_at: u64,
+    updated_at: u64,
+}
+
+// DietRecordItem struct, a catalogue item eaten in a meal; nutrients are
+// copied at recording time so later catalogue edits do not rewrite history
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DietRecordItem {
+    food_item_id: u64,
+    food_name: String,
+    servings: f64,
+    nutrients: Nutrients,
+}
+
+// DietaryConflict struct, a way in which a meal breaks a dietary restriction
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DietaryConflict {
+    restriction: String,
+    detail: String,
 }
 
 // DietRecord struct
@@ -135,8 +657,10 @@ struct DietRecord {
     id: u64,
     user_id: u64,
     meal_type: MealType,
-    food_items: String, // Comma-separated list of food items
-    calories: u32,
+    items: Vec<DietRecordItem>,
+    nutrients: Nutrients,
+    allergy_warnings: Vec<AllergyWarning>,
+    dietary_conflicts: Option<Vec<DietaryConflict>>,
     recorded_at: u64,
 }
 
@@ -181,53 +705,403 @@ struct FitnessChallengeParticipant {
     updated_at: u64,
 }
 
-impl Storable for User {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// Alert struct
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Alert {
+    id: u64,
+    user_id: u64,
+    alert_type: AlertType,
+    message: String,
+    acknowledged_at: Option<u64>,
+    created_at: u64,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// CaregiverLink struct
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CaregiverLink {
+    id: u64,
+    elderly_id: u64,
+    caregiver_id: u64,
+    created_at: u64,
 }
 
-impl BoundedStorable for User {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// DoseEvent struct
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DoseEvent {
+    id: u64,
+    medication_reminder_id: u64,
+    user_id: u64,
+    scheduled_for: u64,
+    status: DoseStatus,
+    recorded_by: Option<u64>, // None when marked missed by the reminder timer
+    recorded_at: u64,
 }
 
-impl Storable for HealthRecord {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// Notification struct
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Notification {
+    id: u64,
+    recipient_id: u64,
+    user_id: u64,
+    notification_type: NotificationType,
+    reference_id: Option<u64>, // ID of the record the notification is about
+    message: String,
+    created_at: u64,
+    read_at: Option<u64>,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// DrugInteraction struct
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DrugInteraction {
+    id: u64,
+    drug_a: String,
+    drug_b: String,
+    severity: InteractionSeverity,
+    description: String,
+    created_at: u64,
+    updated_at: u64,
 }
 
-impl BoundedStorable for HealthRecord {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// PrescriptionStatusChange struct, one entry per status transition
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PrescriptionStatusChange {
+    status: PrescriptionStatus,
+    reason: String,
+    changed_by: u64,
+    changed_at: u64,
 }
 
-impl Storable for MedicationReminder {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// Prescription struct
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Prescription {
+    id: u64,
+    patient_id: u64,
+    provider_id: u64,
+    medication_reminder_id: u64,
+    medication_name: String,
+    dosage: Dosage,
+    indication: String, // Why the medication was prescribed
+    start_date: u64,
+    end_date: Option<u64>,
+    refills_allowed: u32,
+    refills_used: u32,
+    status: PrescriptionStatus,
+    status_history: Vec<PrescriptionStatusChange>,
+    created_at: u64,
+    updated_at: u64,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// MedicationInventory struct, keyed by the medication reminder it tracks
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct MedicationInventory {
+    medication_reminder_id: u64,
+    user_id: u64,
+    quantity_on_hand: u32,
+    units_per_dose: u32,
+    refill_threshold_days: u32,
+    estimated_days_of_supply: Option<u32>, // None for as-needed medications
+    low_supply_alerted: bool,
+    updated_at: u64,
 }
 
-impl BoundedStorable for MedicationReminder {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// Allergy struct, covering both allergies and intolerances
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Allergy {
+    id: u64,
+    user_id: u64,
+    substance: String,
+    category: AllergyCategory,
+    is_intolerance: bool,
+    reaction: String,
+    severity: AllergySeverity,
+    recorded_at: u64,
 }
 
-impl Storable for VirtualConsultation {
-    fn to_bytes(&self) -> Cow<[u8]> {
+// DrugDoseLimit struct, the maximum amount of a drug allowed in 24 hours
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DrugDoseLimit {
+    id: u64,
+    drug_name: String,
+    max_daily_quantity: f64,
+    unit: DoseUnit,
+    updated_at: u64,
+}
+
+// AvailabilitySlot struct, a period a provider has published as open for consultations
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct AvailabilitySlot {
+    id: u64,
+    provider_id: u64,
+    start_at: u64,
+    end_at: u64,
+    consultation_id: Option<u64>,
+    created_at: u64,
+}
+
+// FollowUpAction struct, an action agreed at the end of a consultation
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct FollowUpAction {
+    description: String,
+    due_at: Option<u64>,
+}
+
+// ConsultationNote struct, clinical notes for a consultation in SOAP format
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ConsultationNote {
+    id: u64,
+    consultation_id: u64,
+    user_id: u64,
+    provider_id: u64,
+    subjective: String,
+    objective: String,
+    assessment: String,
+    plan: String,
+    follow_up_actions: Vec<FollowUpAction>,
+    signed_off_at: Option<u64>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+// ConsultationRecurrence struct, e.g. every 1 month for 6 occurrences
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ConsultationRecurrence {
+    frequency: RecurrenceFrequency,
+    interval: u32,
+    occurrences: u32,
+}
+
+// ConsultationSeries struct, recurring consultations between a patient and a provider
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ConsultationSeries {
+    id: u64,
+    user_id: u64,
+    provider_id: u64,
+    recurrence: ConsultationRecurrence,
+    start_at: u64,
+    duration_minutes: u32,
+    consultation_ids: Vec<u64>,
+    cancelled_at: Option<u64>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+// ProviderProfile struct, keyed by the provider's user ID
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ProviderProfile {
+    provider_id: u64,
+    specialties: Vec<ProviderSpecialty>,
+    languages: Vec<String>,
+    licence_number: String,
+    bio: String,
+    consultation_fee: u64,
+    currency: String,
+    rating_count: u32,
+    average_rating: Option<f64>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+// ReferralAttachment struct, a patient record shared with the receiving provider
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+struct ReferralAttachment {
+    record_type: ReferralRecordType,
+    record_id: u64,
+}
+
+// ReferralStatusChange struct, one entry per status transition
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ReferralStatusChange {
+    status: ReferralStatus,
+    note: String,
+    changed_by: u64,
+    changed_at: u64,
+}
+
+// Referral struct
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Referral {
+    id: u64,
+    user_id: u64,
+    referring_provider_id: u64,
+    receiving_provider_id: u64,
+    reason: String,
+    attachments: Vec<ReferralAttachment>,
+    status: ReferralStatus,
+    status_history: Vec<ReferralStatusChange>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+// ConsultationFeedback struct, a patient's rating of a completed consultation
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ConsultationFeedback {
+    id: u64,
+    consultation_id: u64,
+    provider_id: u64,
+    user_id: u64,
+    submitted_by: u64,
+    rating: u8,
+    comment: String,
+    hidden_at: Option<u64>,
+    moderation_reason: String,
+    created_at: u64,
+}
+
+// NutritionTarget struct, a daily range for one nutrient; either bound may be open
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct NutritionTarget {
+    nutrient: Nutrient,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+}
+
+// NutritionTargets struct, keyed by the patient's user ID
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct NutritionTargets {
+    user_id: u64,
+    targets: Vec<NutritionTarget>,
+    set_by: u64,
+    updated_at: u64,
+}
+
+// PlannedMealItem struct, a catalogue item planned for a meal
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PlannedMealItem {
+    food_item_id: u64,
+    food_name: String,
+    servings: f64,
+}
+
+// PlannedMeal struct, the items planned for one meal slot of the week
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PlannedMeal {
+    day: Weekday,
+    meal_type: MealType,
+    items: Vec<PlannedMealItem>,
+}
+
+// MealPlan struct, a week of planned meals for an elderly user
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct MealPlan {
+    id: u64,
+    user_id: u64,
+    created_by: u64,
+    week_start: u64,
+    meals: Vec<PlannedMeal>,
+    dietary_conflicts: Option<Vec<DietaryConflict>>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+// DietaryRestrictions struct, keyed by the user's ID
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DietaryRestrictions {
+    user_id: u64,
+    diets: Vec<DietType>,
+    max_food_texture: Option<IddsiLevel>,
+    min_drink_thickness: Option<IddsiLevel>,
+    set_by: u64,
+    updated_at: u64,
+}
+
+// DietReviewFlag struct, a diet entry that conflicts with the user's restrictions
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DietReviewFlag {
+    id: u64,
+    user_id: u64,
+    entry_type: DietEntryType,
+    entry_id: u64,
+    conflicts: Vec<DietaryConflict>,
+    reviewed_by: Option<u64>,
+    review_note: String,
+    reviewed_at: Option<u64>,
+    created_at: u64,
+}
+
+// FluidIntake struct, one drink logged for a user
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct FluidIntake {
+    id: u64,
+    user_id: u64,
+    beverage_type: BeverageType,
+    volume_ml: u32,
+    recorded_by: u64,
+    taken_at: u64,
+    created_at: u64,
+}
+
+// HydrationGoal struct, keyed by the user's ID
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct HydrationGoal {
+    user_id: u64,
+    daily_goal_ml: u32,
+    check_time: TimeOfDay, // Local time at which intake is checked against the pace
+    expected_percent_by_check_time: u32,
+    last_checked_day: u64, // Start of the last local day that was checked
+    set_by: u64,
+    updated_at: u64,
+}
+
+// WeightEntry struct, one weighing of a user
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct WeightEntry {
+    id: u64,
+    user_id: u64,
+    weight_kg: f64,
+    bmi: Option<f64>,      // From the height on the profile at the time, if known
+    alert_id: Option<u64>, // Set when this entry triggered a rapid weight gain alert
+    recorded_by: u64,
+    measured_at: u64,
+    created_at: u64,
+}
+
+// WeightGain struct, a rise between two weighings found by the rapid weight gain rule
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct WeightGain {
+    from_kg: f64,
+    from_at: u64,
+    to_kg: f64,
+    to_at: u64,
+}
+
+// LegacyMedicationReminder struct, the free-text layout reminders were stored
+// in before schedules were structured; only read to migrate old records
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct LegacyMedicationReminder {
+    id: u64,
+    user_id: u64,
+    medication_name: String,
+    dosage: String,
+    schedule: String,
+    created_at: u64,
+}
+
+// LegacyDietRecord struct, the free-text layout diet records were stored in
+// before the food catalogue; only read to migrate old records
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct LegacyDietRecord {
+    id: u64,
+    user_id: u64,
+    meal_type: MealType,
+    food_items: String, // Comma-separated list of food items
+    calories: u32,
+    recorded_at: u64,
+}
+
+// LegacyVirtualConsultation struct, the layout consultations were stored in
+// before booking against availability slots; only read to migrate old records
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct LegacyVirtualConsultation {
+    id: u64,
+    user_id: u64,
+    provider_id: u64,
+    scheduled_at: u64,
+    status: String,
+    created_at: u64,
+}
+
+impl Storable for User {
+    fn to_bytes(&self) -> Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -236,11 +1110,56 @@ impl Storable for VirtualConsultation {
     }
 }
 
-impl BoundedStorable for VirtualConsultation {
+impl BoundedStorable for User {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for HealthRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for HealthRecord {
     const MAX_SIZE: u32 = 512;
     const IS_FIXED_SIZE: bool = false;
 }
 
+impl Storable for MedicationReminder {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for MedicationReminder {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for VirtualConsultation {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for VirtualConsultation {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
 impl Storable for DietRecord {
     fn to_bytes(&self) -> Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
@@ -252,7 +1171,7 @@ impl Storable for DietRecord {
 }
 
 impl BoundedStorable for DietRecord {
-    const MAX_SIZE: u32 = 512;
+    const MAX_SIZE: u32 = 4096;
     const IS_FIXED_SIZE: bool = false;
 }
 
@@ -316,679 +1235,8740 @@ impl BoundedStorable for FitnessChallengeParticipant {
     const IS_FIXED_SIZE: bool = false;
 }
 
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
+impl Storable for Alert {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create a counter")
-    );
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
 
-    static USERS_STORAGE: RefCell<StableBTreeMap<u64, User, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
+impl BoundedStorable for Alert {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    static HEALTH_RECORDS_STORAGE: RefCell<StableBTreeMap<u64, HealthRecord, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
-    ));
+impl Storable for CaregiverLink {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-    static MEDICATION_REMINDERS_STORAGE: RefCell<StableBTreeMap<u64, MedicationReminder, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
-    ));
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
 
-    static VIRTUAL_CONSULTATIONS_STORAGE: RefCell<StableBTreeMap<u64, VirtualConsultation, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
-    ));
+impl BoundedStorable for CaregiverLink {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    static DIET_RECORDS_STORAGE: RefCell<StableBTreeMap<u64, DietRecord, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
-    ));
+impl Storable for DoseEvent {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-    static EXERCISE_RECOMMENDATIONS_STORAGE: RefCell<StableBTreeMap<u64, ExerciseRecommendation, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
-    ));
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
 
-    static MENTAL_HEALTH_RECORDS_STORAGE: RefCell<StableBTreeMap<u64, MentalHealthRecord, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
-    ));
+impl BoundedStorable for DoseEvent {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    static FITNESS_CHALLENGES_STORAGE: RefCell<StableBTreeMap<u64, FitnessChallenge, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
-    ));
+impl Storable for Notification {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-    static FITNESS_CHALLENGE_PARTICIPANTS_STORAGE: RefCell<StableBTreeMap<u64, FitnessChallengeParticipant, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
-    ));
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-// User Payload
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct UserPayload {
-    name: String,
-    contact: String,
-    user_type: UserType,
+impl BoundedStorable for Notification {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-// HealthRecord Payload
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct HealthRecordPayload {
-    user_id: u64,
-    heart_rate: u8,
-    blood_pressure: String,
-    activity_level: String,
-    status: HealthStatus,
-}
+impl Storable for DrugInteraction {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-// MedicationReminder Payload
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct MedicationReminderPayload {
-    user_id: u64,
-    medication_name: String,
-    dosage: String,
-    schedule: String,
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-// VirtualConsultation Payload
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct VirtualConsultationPayload {
-    user_id: u64,
-    provider_id: u64,
-    scheduled_at: u64,
-    status: String,
+impl BoundedStorable for DrugInteraction {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-// DietRecord Payload
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct DietRecordPayload {
-    user_id: u64,
-    meal_type: MealType,
-    food_items: String,
-    calories: u32,
-}
+impl Storable for Prescription {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-// ExerciseRecommendation Payload
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct ExerciseRecommendationPayload {
-    user_id: u64,
-    exercise_type: ExerciseType,
-    duration: u32,
-    intensity: Intensity,
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-// MentalHealthRecord Payload
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct MentalHealthRecordPayload {
-    user_id: u64,
-    mood: Mood,
-    stress_level: StressLevel,
-    notes: String,
+impl BoundedStorable for Prescription {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-// FitnessChallenge Payload
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct FitnessChallengePayload {
-    name: String,
-    description: String,
-    start_date: u64,
-    end_date: u64,
+impl Storable for MedicationInventory {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-// FitnessChallengeParticipant Payload
-#[derive(candid::CandidType, Deserialize, Serialize)]
-struct FitnessChallengeParticipantPayload {
-    challenge_id: u64,
-    user_id: u64,
-    progress: u32,
+impl BoundedStorable for MedicationInventory {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-// Function to create a new user
-#[ic_cdk::update]
-fn create_user(payload: UserPayload) -> Result<User, String> {
-    // Ensure name and contact are not empty
-    if payload.name.is_empty() || payload.contact.is_empty() {
-        return Err("Name and contact cannot be empty".to_string());
+impl Storable for Allergy {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
     }
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
 
-    let user = User {
-        id,
-        name: payload.name,
-        contact: payload.contact,
-        user_type: payload.user_type,
-        created_at: time(),
-    };
+impl BoundedStorable for Allergy {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    USERS_STORAGE.with(|storage| storage.borrow_mut().insert(id, user.clone()));
-    Ok(user)
+impl Storable for DrugDoseLimit {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-// Function to retrieve all users
-#[ic_cdk::query]
-fn get_all_users() -> Result<Vec<User>, String> {
-    USERS_STORAGE.with(|storage| {
-        let stable_btree_map = &*storage.borrow();
-        let records: Vec<User> = stable_btree_map
-            .iter()
-            .map(|(_, record)| record.clone())
-            .collect();
-        if records.is_empty() {
-            Err("No users found.".to_string())
-        } else {
-            Ok(records)
-        }
-    })
+impl BoundedStorable for DrugDoseLimit {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-// Function to create a new health record
-#[ic_cdk::update]
-fn create_health_record(payload: HealthRecordPayload) -> Result<HealthRecord, String> {
-    // Ensure all fields are provided
-    if payload.blood_pressure.is_empty() || payload.activity_level.is_empty() {
-        return Err("All fields must be provided.".to_string());
+impl Storable for AvailabilitySlot {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
     }
 
-    // Ensure user ID exists
-    let user_exists = USERS_STORAGE.with(|storage| storage.borrow().contains_key(&payload.user_id));
-    if !user_exists {
-        return Err("User ID does not exist.".to_string());
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
     }
+}
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+impl BoundedStorable for AvailabilitySlot {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    let health_record = HealthRecord {
-        id,
-        user_id: payload.user_id,
-        heart_rate: payload.heart_rate,
-        blood_pressure: payload.blood_pressure,
-        activity_level: payload.activity_level,
-        status: payload.status,
-        recorded_at: time(),
-    };
+impl Storable for ConsultationNote {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-    HEALTH_RECORDS_STORAGE.with(|storage| storage.borrow_mut().insert(id, health_record.clone()));
-    Ok(health_record)
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-// Function to retrieve all health records
-#[ic_cdk::query]
-fn get_all_health_records() -> Result<Vec<HealthRecord>, String> {
-    HEALTH_RECORDS_STORAGE.with(|storage| {
-        let stable_btree_map = &*storage.borrow();
-        let records: Vec<HealthRecord> = stable_btree_map
-            .iter()
-            .map(|(_, record)| record.clone())
-            .collect();
-        if records.is_empty() {
-            Err("No health records found.".to_string())
-        } else {
-            Ok(records)
-        }
-    })
+impl BoundedStorable for ConsultationNote {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-// Function to create a new medication reminder
-#[ic_cdk::update]
-fn create_medication_reminder(
-    payload: MedicationReminderPayload,
-) -> Result<MedicationReminder, String> {
-    // Ensure all fields are provided
-    if payload.medication_name.is_empty()
-        || payload.dosage.is_empty()
-        || payload.schedule.is_empty()
-    {
-        return Err("All fields must be provided.".to_string());
+impl Storable for ConsultationSeries {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
     }
 
-    // Ensure user ID exists
-    let user_exists = USERS_STORAGE.with(|storage| storage.borrow().contains_key(&payload.user_id));
-    if !user_exists {
-        return Err("User ID does not exist.".to_string());
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
     }
+}
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+impl BoundedStorable for ConsultationSeries {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    let medication_reminder = MedicationReminder {
-        id,
-        user_id: payload.user_id,
-        medication_name: payload.medication_name,
-        dosage: payload.dosage,
-        schedule: payload.schedule,
-        created_at: time(),
-    };
+impl Storable for ProviderProfile {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-    MEDICATION_REMINDERS_STORAGE
-        .with(|storage| storage.borrow_mut().insert(id, medication_reminder.clone()));
-    Ok(medication_reminder)
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-// Function to retrieve all medication reminders
-#[ic_cdk::query]
-fn get_all_medication_reminders() -> Result<Vec<MedicationReminder>, String> {
-    MEDICATION_REMINDERS_STORAGE.with(|storage| {
-        let stable_btree_map = &*storage.borrow();
-        let records: Vec<MedicationReminder> = stable_btree_map
-            .iter()
-            .map(|(_, record)| record.clone())
-            .collect();
-        if records.is_empty() {
-            Err("No medication reminders found.".to_string())
-        } else {
-            Ok(records)
-        }
-    })
+impl BoundedStorable for ProviderProfile {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-// Function to create a new virtual consultation
-#[ic_cdk::update]
-fn create_virtual_consultation(
-    payload: VirtualConsultationPayload,
-) -> Result<VirtualConsultation, String> {
-    // Ensure all fields are provided
-    if payload.status.is_empty() {
-        return Err("All fields must be provided.".to_string());
-    }
-
-    // Ensure user ID and provider ID exist
-    let user_exists = USERS_STORAGE.with(|storage| storage.borrow().contains_key(&payload.user_id));
-    if !user_exists {
-        return Err("User ID does not exist.".to_string());
+impl Storable for Referral {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
     }
 
-    let provider_exists =
-        USERS_STORAGE.with(|storage| storage.borrow().contains_key(&payload.provider_id));
-    if !provider_exists {
-        return Err("Provider ID does not exist.".to_string());
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
     }
+}
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+impl BoundedStorable for Referral {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    let virtual_consultation = VirtualConsultation {
-        id,
-        user_id: payload.user_id,
-        provider_id: payload.provider_id,
-        scheduled_at: payload.scheduled_at,
-        status: payload.status,
-        created_at: time(),
-    };
+impl Storable for ConsultationFeedback {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-    VIRTUAL_CONSULTATIONS_STORAGE.with(|storage| {
-        storage
-            .borrow_mut()
-            .insert(id, virtual_consultation.clone())
-    });
-    Ok(virtual_consultation)
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-// Function to retrieve all virtual consultations
-#[ic_cdk::query]
-fn get_all_virtual_consultations() -> Result<Vec<VirtualConsultation>, String> {
-    VIRTUAL_CONSULTATIONS_STORAGE.with(|storage| {
-        let stable_btree_map = &*storage.borrow();
-        let records: Vec<VirtualConsultation> = stable_btree_map
-            .iter()
-            .map(|(_, record)| record.clone())
-            .collect();
-        if records.is_empty() {
-            Err("No virtual consultations found.".to_string())
-        } else {
-            Ok(records)
-        }
-    })
+impl BoundedStorable for ConsultationFeedback {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-// Function to create a new diet record
-#[ic_cdk::update]
-fn create_diet_record(payload: DietRecordPayload) -> Result<DietRecord, String> {
-    // Ensure all fields are provided
-    if payload.food_items.is_empty() {
-        return Err("All fields must be provided.".to_string());
+impl Storable for FoodItem {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
     }
 
-    // Ensure user ID exists
-    let user_exists = USERS_STORAGE.with(|storage| storage.borrow().contains_key(&payload.user_id));
-    if !user_exists {
-        return Err("User ID does not exist.".to_string());
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
     }
+}
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+impl BoundedStorable for FoodItem {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    let diet_record = DietRecord {
-        id,
-        user_id: payload.user_id,
-        meal_type: payload.meal_type,
-        food_items: payload.food_items,
-        calories: payload.calories,
-        recorded_at: time(),
-    };
+impl Storable for NutritionTargets {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-    DIET_RECORDS_STORAGE.with(|storage| storage.borrow_mut().insert(id, diet_record.clone()));
-    Ok(diet_record)
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-// Function to retrieve diet records by user ID
-#[ic_cdk::query]
-fn get_diet_records_by_user_id(user_id: u64) -> Result<Vec<DietRecord>, String> {
-    DIET_RECORDS_STORAGE.with(|storage| {
-        let stable_btree_map = &*storage.borrow();
-        let records: Vec<DietRecord> = stable_btree_map
-            .iter()
-            .filter(|(_, record)| record.user_id == user_id)
-            .map(|(_, record)| record.clone())
-            .collect();
-        if records.is_empty() {
-            Err("No diet records found.".to_string())
-        } else {
-            Ok(records)
-        }
-    })
+impl BoundedStorable for NutritionTargets {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-// Function to retrieve all diet records
-#[ic_cdk::query]
-fn get_all_diet_records() -> Result<Vec<DietRecord>, String> {
-    DIET_RECORDS_STORAGE.with(|storage| {
-        let stable_btree_map = &*storage.borrow();
-        let records: Vec<DietRecord> = stable_btree_map
-            .iter()
-            .map(|(_, record)| record.clone())
-            .collect();
-        if records.is_empty() {
-            Err("No diet records found.".to_string())
-        } else {
-            Ok(records)
-        }
-    })
+impl Storable for MealPlan {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-// Function to create a new exercise recommendation
-#[ic_cdk::update]
-fn create_exercise_recommendation(
-    payload: ExerciseRecommendationPayload,
-) -> Result<ExerciseRecommendation, String> {
-    // Ensure all fields are provided
-    if payload.duration == 0 {
-        return Err("All fields must be provided.".to_string());
+impl BoundedStorable for MealPlan {
+    const MAX_SIZE: u32 = 16384;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for DietaryRestrictions {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
     }
 
-    // Ensure user ID exists
-    let user_exists = USERS_STORAGE.with(|storage| storage.borrow().contains_key(&payload.user_id));
-    if !user_exists {
-        return Err("User ID does not exist.".to_string());
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
     }
+}
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+impl BoundedStorable for DietaryRestrictions {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    let recommendation = ExerciseRecommendation {
-        id,
-        user_id: payload.user_id,
-        exercise_type: payload.exercise_type,
-        duration: payload.duration,
-        intensity: payload.intensity,
-        recommended_at: time(),
-    };
+impl Storable for DietReviewFlag {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-    EXERCISE_RECOMMENDATIONS_STORAGE
-        .with(|storage| storage.borrow_mut().insert(id, recommendation.clone()));
-    Ok(recommendation)
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-// Function to retrieve exercise recommendations by user ID
-#[ic_cdk::query]
-fn get_exercise_recommendations_by_user_id(
-    user_id: u64,
-) -> Result<Vec<ExerciseRecommendation>, String> {
-    EXERCISE_RECOMMENDATIONS_STORAGE.with(|storage| {
-        let stable_btree_map = &*storage.borrow();
-        let records: Vec<ExerciseRecommendation> = stable_btree_map
-            .iter()
-            .filter(|(_, record)| record.user_id == user_id)
-            .map(|(_, record)| record.clone())
-            .collect();
-        if records.is_empty() {
-            Err("No exercise recommendations found.".to_string())
-        } else {
-            Ok(records)
-        }
-    })
+impl BoundedStorable for DietReviewFlag {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-// Function to retrieve all exercise recommendations
-#[ic_cdk::query]
-fn get_all_exercise_recommendations() -> Result<Vec<ExerciseRecommendation>, String> {
-    EXERCISE_RECOMMENDATIONS_STORAGE.with(|storage| {
-        let stable_btree_map = &*storage.borrow();
-        let records: Vec<ExerciseRecommendation> = stable_btree_map
-            .iter()
-            .map(|(_, record)| record.clone())
-            .collect();
-        if records.is_empty() {
-            Err("No exercise recommendations found.".to_string())
-        } else {
-            Ok(records)
-        }
-    })
+impl Storable for FluidIntake {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-// Function to create a new mental health record
-#[ic_cdk::update]
-fn create_mental_health_record(
-    payload: MentalHealthRecordPayload,
-) -> Result<MentalHealthRecord, String> {
-    // Ensure all fields are provided
-    if payload.notes.is_empty() {
-        return Err("All fields must be provided.".to_string());
+impl BoundedStorable for FluidIntake {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for HydrationGoal {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
     }
 
-    // Ensure user ID exists
-    let user_exists = USERS_STORAGE.with(|storage| storage.borrow().contains_key(&payload.user_id));
-    if !user_exists {
-        return Err("User ID does not exist.".to_string());
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
     }
+}
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
+impl BoundedStorable for HydrationGoal {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for WeightEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for WeightEntry {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for LegacyMedicationReminder {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LegacyMedicationReminder {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for LegacyDietRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LegacyDietRecord {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for LegacyVirtualConsultation {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LegacyVirtualConsultation {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static USERS_STORAGE: RefCell<StableBTreeMap<u64, User, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    ));
+
+    static HEALTH_RECORDS_STORAGE: RefCell<StableBTreeMap<u64, HealthRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+    ));
+
+    // Reminders in the original free-text layout, emptied by the migration in post_upgrade
+    static LEGACY_MEDICATION_REMINDERS_STORAGE: RefCell<StableBTreeMap<u64, LegacyMedicationReminder, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    // Consultations in the original layout, emptied by the migration in post_upgrade
+    static LEGACY_VIRTUAL_CONSULTATIONS_STORAGE: RefCell<StableBTreeMap<u64, LegacyVirtualConsultation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    ));
+
+    // Diet records in the original free-text layout, emptied by the migration in post_upgrade
+    static LEGACY_DIET_RECORDS_STORAGE: RefCell<StableBTreeMap<u64, LegacyDietRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+
+    static EXERCISE_RECOMMENDATIONS_STORAGE: RefCell<StableBTreeMap<u64, ExerciseRecommendation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+
+    static MENTAL_HEALTH_RECORDS_STORAGE: RefCell<StableBTreeMap<u64, MentalHealthRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+
+    static FITNESS_CHALLENGES_STORAGE: RefCell<StableBTreeMap<u64, FitnessChallenge, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    static FITNESS_CHALLENGE_PARTICIPANTS_STORAGE: RefCell<StableBTreeMap<u64, FitnessChallengeParticipant, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    static ALERTS_STORAGE: RefCell<StableBTreeMap<u64, Alert, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+
+    static CAREGIVER_LINKS_STORAGE: RefCell<StableBTreeMap<u64, CaregiverLink, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+
+    static DOSE_EVENTS_STORAGE: RefCell<StableBTreeMap<u64, DoseEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    static NOTIFICATIONS_STORAGE: RefCell<StableBTreeMap<u64, Notification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+    ));
+
+    static REMINDER_DISPATCH_CURSOR: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))), 0)
+            .expect("Cannot create the reminder dispatch cursor")
+    );
+
+    static DRUG_INTERACTIONS_STORAGE: RefCell<StableBTreeMap<u64, DrugInteraction, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+
+    static PRESCRIPTIONS_STORAGE: RefCell<StableBTreeMap<u64, Prescription, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    static MEDICATION_INVENTORY_STORAGE: RefCell<StableBTreeMap<u64, MedicationInventory, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    static ALLERGIES_STORAGE: RefCell<StableBTreeMap<u64, Allergy, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    static DRUG_DOSE_LIMITS_STORAGE: RefCell<StableBTreeMap<u64, DrugDoseLimit, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+
+    static AVAILABILITY_SLOTS_STORAGE: RefCell<StableBTreeMap<u64, AvailabilitySlot, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+
+    static CONSULTATION_NOTES_STORAGE: RefCell<StableBTreeMap<u64, ConsultationNote, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    static CONSULTATION_SERIES_STORAGE: RefCell<StableBTreeMap<u64, ConsultationSeries, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+
+    static PROVIDER_PROFILES_STORAGE: RefCell<StableBTreeMap<u64, ProviderProfile, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    static REFERRALS_STORAGE: RefCell<StableBTreeMap<u64, Referral, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+
+    static CONSULTATION_FEEDBACK_STORAGE: RefCell<StableBTreeMap<u64, ConsultationFeedback, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+
+    static FOOD_ITEMS_STORAGE: RefCell<StableBTreeMap<u64, FoodItem, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+    ));
+
+    static NUTRITION_TARGETS_STORAGE: RefCell<StableBTreeMap<u64, NutritionTargets, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    static MEAL_PLANS_STORAGE: RefCell<StableBTreeMap<u64, MealPlan, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    static DIETARY_RESTRICTIONS_STORAGE: RefCell<StableBTreeMap<u64, DietaryRestrictions, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+
+    static DIET_REVIEW_FLAGS_STORAGE: RefCell<StableBTreeMap<u64, DietReviewFlag, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    static FLUID_INTAKE_STORAGE: RefCell<StableBTreeMap<u64, FluidIntake, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+
+    static HYDRATION_GOALS_STORAGE: RefCell<StableBTreeMap<u64, HydrationGoal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+
+    static WEIGHT_ENTRIES_STORAGE: RefCell<StableBTreeMap<u64, WeightEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+    ));
+
+    static MEDICATION_REMINDERS_STORAGE: RefCell<StableBTreeMap<u64, MedicationReminder, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+    ));
+
+    // Dose event IDs by medication reminder ID and scheduled time
+    static DOSE_EVENT_INDEX: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
+
+    static DIET_RECORDS_STORAGE: RefCell<StableBTreeMap<u64, DietRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+    ));
+
+    static VIRTUAL_CONSULTATIONS_STORAGE: RefCell<StableBTreeMap<u64, VirtualConsultation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+    ));
+}
+
+// User Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct UserPayload {
+    name: String,
+    contact: String,
+    user_type: UserType,
+    utc_offset_minutes: Option<i32>,
+    height_cm: Option<f64>,
+    weight_kg: Option<f64>, // Recorded as the user's first weight entry
+}
+
+// HealthRecord Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct HealthRecordPayload {
+    user_id: u64,
+    heart_rate: u8,
+    blood_pressure: String,
+    activity_level: String,
+    status: HealthStatus,
+}
+
+// MedicationReminder Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct MedicationReminderPayload {
+    user_id: u64,
+    medication_name: String,
+    dosage: Dosage,
+    schedule: MedicationSchedule,
+}
+
+// VirtualConsultation Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct VirtualConsultationPayload {
+    user_id: u64,
+    booked_by: u64,
+    slot_id: u64,
+}
+
+// AvailabilitySlot Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct AvailabilitySlotPayload {
+    provider_id: u64,
+    start_at: u64,
+    end_at: u64,
+}
+
+// ConsultationTransition Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ConsultationTransitionPayload {
+    actor_id: u64,
+    reason: String,
+}
+
+// DietRecord Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DietRecordPayload {
+    user_id: u64,
+    meal_type: MealType,
+    items: Vec<DietRecordItemPayload>,
+}
+
+// DietRecordItem Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DietRecordItemPayload {
+    food_item_id: u64,
+    servings: f64,
+}
+
+// FoodItem Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct FoodItemPayload {
+    name: String,
+    serving_description: String,
+    nutrients_per_serving: Nutrients,
+    is_drink: bool,
+    iddsi_level: IddsiLevel,
+}
+
+// ExerciseRecommendation Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ExerciseRecommendationPayload {
+    user_id: u64,
+    exercise_type: ExerciseType,
+    duration: u32,
+    intensity: Intensity,
+}
+
+// MentalHealthRecord Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct MentalHealthRecordPayload {
+    user_id: u64,
+    mood: Mood,
+    stress_level: StressLevel,
+    notes: String,
+}
+
+// FitnessChallenge Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct FitnessChallengePayload {
+    name: String,
+    description: String,
+    start_date: u64,
+    end_date: u64,
+}
+
+// FitnessChallengeParticipant Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct FitnessChallengeParticipantPayload {
+    challenge_id: u64,
+    user_id: u64,
+    progress: u32,
+}
+
+// CaregiverLink Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct CaregiverLinkPayload {
+    elderly_id: u64,
+    caregiver_id: u64,
+}
+
+// DoseEvent Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DoseEventPayload {
+    medication_reminder_id: u64,
+    scheduled_for: u64,
+    status: DoseStatus,
+    recorded_by: u64,
+}
+
+// DrugInteraction Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DrugInteractionPayload {
+    drug_a: String,
+    drug_b: String,
+    severity: InteractionSeverity,
+    description: String,
+}
+
+// Prescription Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct PrescriptionPayload {
+    patient_id: u64,
+    provider_id: u64,
+    medication_name: String,
+    dosage: Dosage,
+    indication: String,
+    frequency: ScheduleFrequency,
+    start_date: u64,
+    end_date: Option<u64>,
+    duration_days: Option<u32>,
+    refills_allowed: u32,
+}
+
+// PrescriptionStatus Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct PrescriptionStatusPayload {
+    provider_id: u64,
+    status: PrescriptionStatus,
+    reason: String,
+}
+
+// MedicationInventory Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct MedicationInventoryPayload {
+    medication_reminder_id: u64,
+    recorded_by: u64,
+    quantity_on_hand: u32,
+    units_per_dose: u32,
+    refill_threshold_days: u32,
+}
+
+// Allergy Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct AllergyPayload {
+    user_id: u64,
+    recorded_by: u64,
+    substance: String,
+    category: AllergyCategory,
+    is_intolerance: bool,
+    reaction: String,
+    severity: AllergySeverity,
+}
+
+// DrugDoseLimit Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DrugDoseLimitPayload {
+    drug_name: String,
+    max_daily_quantity: f64,
+    unit: DoseUnit,
+}
+
+// ConsultationNote Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ConsultationNotePayload {
+    provider_id: u64,
+    subjective: String,
+    objective: String,
+    assessment: String,
+    plan: String,
+    follow_up_actions: Vec<FollowUpAction>,
+}
+
+// ConsultationSeries Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ConsultationSeriesPayload {
+    user_id: u64,
+    provider_id: u64,
+    start_at: u64,
+    duration_minutes: u32,
+    recurrence: ConsultationRecurrence,
+}
+
+// ConsultationReschedule Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ConsultationReschedulePayload {
+    actor_id: u64,
+    start_at: u64,
+}
+
+// ProviderProfile Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ProviderProfilePayload {
+    provider_id: u64,
+    specialties: Vec<ProviderSpecialty>,
+    languages: Vec<String>,
+    licence_number: String,
+    bio: String,
+    consultation_fee: u64,
+    currency: String,
+}
+
+// ProviderSearch query
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ProviderSearchQuery {
+    specialty: Option<ProviderSpecialty>,
+    language: Option<String>,
+}
+
+// ProviderSearchResult response
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ProviderSearchResult {
+    name: String,
+    contact: String,
+    profile: ProviderProfile,
+}
+
+// Referral Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ReferralPayload {
+    user_id: u64,
+    referring_provider_id: u64,
+    receiving_provider_id: u64,
+    reason: String,
+    attachments: Vec<ReferralAttachment>,
+}
+
+// ReferralTransition Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ReferralTransitionPayload {
+    provider_id: u64,
+    note: String,
+}
+
+// ReferralRecords response, the attached records visible to the receiving provider
+#[derive(candid::CandidType, Deserialize, Serialize, Default)]
+struct ReferralRecords {
+    referral_id: u64,
+    health_records: Vec<HealthRecord>,
+    mental_health_records: Vec<MentalHealthRecord>,
+    medication_reminders: Vec<MedicationReminder>,
+    prescriptions: Vec<Prescription>,
+    consultation_notes: Vec<ConsultationNote>,
+}
+
+// ConsultationFeedback Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ConsultationFeedbackPayload {
+    submitted_by: u64,
+    rating: u8,
+    comment: String,
+}
+
+// FeedbackModeration Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct FeedbackModerationPayload {
+    hidden: bool,
+    reason: String,
+}
+
+// NutritionTargets Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct NutritionTargetsPayload {
+    user_id: u64,
+    provider_id: u64,
+    targets: Vec<NutritionTarget>,
+}
+
+// NutritionTargetResult response, how the daily intake compares with a target
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct NutritionTargetResult {
+    nutrient: Nutrient,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    daily_average: f64,
+    status: TargetStatus,
+}
+
+// NutritionSummary response
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct NutritionSummary {
+    user_id: u64,
+    period: SummaryPeriod,
+    period_start: u64,
+    period_end: u64,
+    days_counted: u32,
+    meals_logged: u32,
+    totals: Nutrients,
+    daily_average: Nutrients,
+    targets: Vec<NutritionTargetResult>,
+}
+
+// PlannedMeal Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct PlannedMealPayload {
+    day: Weekday,
+    meal_type: MealType,
+    items: Vec<DietRecordItemPayload>,
+}
+
+// MealPlan Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct MealPlanPayload {
+    user_id: u64,
+    created_by: u64,
+    week_of: u64, // Any time in the planned week
+    meals: Vec<PlannedMealPayload>,
+}
+
+// MealSlot response
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct MealSlot {
+    day: Weekday,
+    meal_type: MealType,
+}
+
+// MealSubstitution response, a planned meal eaten with different items
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct MealSubstitution {
+    day: Weekday,
+    meal_type: MealType,
+    skipped_items: Vec<String>,
+    substituted_items: Vec<String>,
+}
+
+// MealPlanCompliance response
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct MealPlanCompliance {
+    meal_plan_id: u64,
+    user_id: u64,
+    week_start: u64,
+    meals_planned: u32,
+    meals_followed: u32,
+    meals_substituted: u32,
+    meals_missed: u32,
+    meals_pending: u32,
+    compliance_percent: u32,
+    missed_meals: Vec<MealSlot>,
+    substitutions: Vec<MealSubstitution>,
+}
+
+// DietaryRestrictions Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DietaryRestrictionsPayload {
+    user_id: u64,
+    set_by: u64,
+    diets: Vec<DietType>,
+    max_food_texture: Option<IddsiLevel>,
+    min_drink_thickness: Option<IddsiLevel>,
+}
+
+// DietReview Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DietReviewPayload {
+    reviewer_id: u64,
+    note: String,
+}
+
+// FluidIntake Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct FluidIntakePayload {
+    user_id: u64,
+    recorded_by: u64,
+    beverage_type: BeverageType,
+    volume_ml: u32,
+    taken_at: Option<u64>, // Defaults to now
+}
+
+// HydrationGoal Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct HydrationGoalPayload {
+    user_id: u64,
+    set_by: u64,
+    daily_goal_ml: u32,
+    check_time: TimeOfDay,
+    expected_percent_by_check_time: u32,
+}
+
+// BeverageTotal response
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct BeverageTotal {
+    beverage_type: BeverageType,
+    volume_ml: u32,
+}
+
+// DailyHydration response
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DailyHydration {
+    user_id: u64,
+    day_start: u64,
+    total_ml: u32,
+    goal_ml: Option<u32>,
+    percent_of_goal: Option<u32>,
+    by_beverage: Vec<BeverageTotal>,
+    intakes: Vec<FluidIntake>,
+}
+
+// WeightEntry Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct WeightEntryPayload {
+    user_id: u64,
+    recorded_by: u64,
+    weight_kg: f64,
+    measured_at: Option<u64>, // Defaults to now
+}
+
+// UserRecordsCsv response, one CSV document per record type
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct UserRecordsCsv {
+    user_id: u64,
+    health_records: String,
+    diet_records: String,
+    exercise_recommendations: String,
+    mental_health_records: String,
+    medication_reminders: String,
+}
+
+// MedicationAdherence report for one medication over a time window
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct MedicationAdherence {
+    medication_reminder_id: u64,
+    medication_name: String,
+    expected_doses: u32,
+    taken: u32,
+    late: u32,
+    skipped: u32,
+    missed: u32,
+    adherence_percent: u32,
+}
+
+// UserAdherenceReport across all of a user's medications over a time window
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct UserAdherenceReport {
+    user_id: u64,
+    from: u64,
+    to: u64,
+    medications: Vec<MedicationAdherence>,
+    expected_doses: u32,
+    taken: u32,
+    late: u32,
+    skipped: u32,
+    missed: u32,
+    adherence_percent: u32,
+}
+
+// NextDose response
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct NextDose {
+    medication_reminder_id: u64,
+    medication_name: String,
+    dosage: Dosage,
+    due_at: u64,
+}
+
+// DietAdherence summary over the summary window
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct DietAdherence {
+    meals_logged: u32,
+    meals_expected: u32,
+    adherence_percent: u32,
+    average_daily_calories: u32,
+}
+
+// ExerciseAdherence summary over the summary window
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct ExerciseAdherence {
+    recommendations: u32,
+    recommended_minutes: u32,
+    active_challenges: u32,
+}
+
+// PatientSummary response for pre-consultation review
+#[derive(candid::CandidType, Deserialize, Serialize)]
+struct PatientSummary {
+    user: User,
+    latest_health_record: Option<HealthRecord>,
+    active_medications: Vec<MedicationReminder>,
+    upcoming_consultations: Vec<VirtualConsultation>,
+    recent_mental_health_records: Vec<MentalHealthRecord>,
+    diet_adherence: DietAdherence,
+    exercise_adherence: ExerciseAdherence,
+    open_alerts: Vec<Alert>,
+    generated_at: u64,
+}
+
+// Function to create a new user
+#[ic_cdk::update]
+fn create_user(payload: UserPayload) -> Result<User, String> {
+    // Ensure name and contact are not empty
+    if payload.name.is_empty() || payload.contact.is_empty() {
+        return Err("Name and contact cannot be empty".to_string());
+    }
+
+    let utc_offset_minutes = payload.utc_offset_minutes.unwrap_or(0);
+    validate_utc_offset(utc_offset_minutes)?;
+    if let Some(height_cm) = payload.height_cm {
+        validate_height(height_cm)?;
+    }
+    if let Some(weight_kg) = payload.weight_kg {
+        validate_weight(weight_kg)?;
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let user = User {
+        id,
+        name: payload.name,
+        contact: payload.contact,
+        user_type: payload.user_type,
+        utc_offset_minutes: Some(utc_offset_minutes),
+        height_cm: payload.height_cm,
+        weight_kg: None,
+        owner: Some(ic_cdk::caller()),
+        created_at: time(),
+    };
+
+    USERS_STORAGE.with(|storage| storage.borrow_mut().insert(id, user.clone()));
+
+    match payload.weight_kg {
+        Some(weight_kg) => {
+            insert_weight_entry(id, id, weight_kg, user.created_at);
+            Ok(USERS_STORAGE
+                .with(|storage| storage.borrow().get(&id))
+                .unwrap_or(user))
+        }
+        None => Ok(user),
+    }
+}
+
+// Function to update a user's time zone, as a fixed offset from UTC in minutes
+#[ic_cdk::update]
+fn update_user_utc_offset(user_id: u64, utc_offset_minutes: i32) -> Result<User, String> {
+    validate_utc_offset(utc_offset_minutes)?;
+
+    USERS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let mut user = storage
+            .get(&user_id)
+            .ok_or("User ID does not exist.".to_string())?;
+        user.utc_offset_minutes = Some(utc_offset_minutes);
+        storage.insert(user_id, user.clone());
+        Ok(user)
+    })
+}
+
+// Function for an admin to set the principal that acts for a user, e.g. for
+// users created before owners were recorded
+#[ic_cdk::update]
+fn set_user_owner(user_id: u64, owner: Principal) -> Result<User, String> {
+    if !is_admin() {
+        return Err("Only an admin can change the owner of a user.".to_string());
+    }
+
+    USERS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let mut user = storage
+            .get(&user_id)
+            .ok_or("User ID does not exist.".to_string())?;
+        user.owner = Some(owner);
+        storage.insert(user_id, user.clone());
+        Ok(user)
+    })
+}
+
+// Helper to check that the caller acts for a user: the principal that created
+// the user, or an admin
+fn check_caller_is_user(user_id: u64) -> Result<User, String> {
+    let user = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .ok_or("User ID does not exist.".to_string())?;
+    if user.owner != Some(ic_cdk::caller()) && !is_admin() {
+        return Err(format!("The caller cannot act for user {}.", user_id));
+    }
+    Ok(user)
+}
+
+// Helper to validate a UTC offset (UTC-12:00 to UTC+14:00)
+fn validate_utc_offset(utc_offset_minutes: i32) -> Result<(), String> {
+    if !(-12 * 60..=14 * 60).contains(&utc_offset_minutes) {
+        return Err("UTC offset must be between -720 and 840 minutes.".to_string());
+    }
+    Ok(())
+}
+
+// Function to update a user's height, used for BMI on later weight entries
+#[ic_cdk::update]
+fn update_user_height(user_id: u64, height_cm: f64, recorded_by: u64) -> Result<User, String> {
+    check_measurement_recorder(user_id, recorded_by, "update height")?;
+    validate_height(height_cm)?;
+
+    USERS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let mut user = storage
+            .get(&user_id)
+            .ok_or("User ID does not exist.".to_string())?;
+        user.height_cm = Some(height_cm);
+        storage.insert(user_id, user.clone());
+        Ok(user)
+    })
+}
+
+// Helper to validate a height in centimetres
+fn validate_height(height_cm: f64) -> Result<(), String> {
+    if height_cm.is_nan() || !(MIN_HEIGHT_CM..=MAX_HEIGHT_CM).contains(&height_cm) {
+        return Err(format!(
+            "Height must be between {} and {} cm.",
+            MIN_HEIGHT_CM, MAX_HEIGHT_CM
+        ));
+    }
+    Ok(())
+}
+
+// Helper to validate a weight in kilograms
+fn validate_weight(weight_kg: f64) -> Result<(), String> {
+    if weight_kg.is_nan() || !(MIN_WEIGHT_KG..=MAX_WEIGHT_KG).contains(&weight_kg) {
+        return Err(format!(
+            "Weight must be between {} and {} kg.",
+            MIN_WEIGHT_KG, MAX_WEIGHT_KG
+        ));
+    }
+    Ok(())
+}
+
+// Function to link a caregiver to an elderly user
+#[ic_cdk::update]
+fn link_caregiver(payload: CaregiverLinkPayload) -> Result<CaregiverLink, String> {
+    // Only the elderly user (or an admin) can grant a caregiver access
+    let elderly = check_caller_is_user(payload.elderly_id)?;
+    if elderly.user_type != UserType::Elderly {
+        return Err("Caregivers can only be linked to elderly users.".to_string());
+    }
+
+    let caregiver = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&payload.caregiver_id))
+        .ok_or("Caregiver ID does not exist.".to_string())?;
+    if caregiver.user_type != UserType::Caregiver {
+        return Err("Linked user must be a caregiver.".to_string());
+    }
+
+    if is_linked_caregiver(payload.elderly_id, payload.caregiver_id) {
+        return Err("Caregiver is already linked to this user.".to_string());
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let link = CaregiverLink {
+        id,
+        elderly_id: payload.elderly_id,
+        caregiver_id: payload.caregiver_id,
+        created_at: time(),
+    };
+
+    CAREGIVER_LINKS_STORAGE.with(|storage| storage.borrow_mut().insert(id, link.clone()));
+    Ok(link)
+}
+
+// Function for the elderly user or the caregiver to remove a caregiver link
+#[ic_cdk::update]
+fn unlink_caregiver(link_id: u64) -> Result<CaregiverLink, String> {
+    let link = CAREGIVER_LINKS_STORAGE
+        .with(|storage| storage.borrow().get(&link_id))
+        .ok_or("Caregiver link ID does not exist.".to_string())?;
+    check_caller_is_user(link.elderly_id).or_else(|_| check_caller_is_user(link.caregiver_id))?;
+
+    CAREGIVER_LINKS_STORAGE.with(|storage| storage.borrow_mut().remove(&link_id));
+    Ok(link)
+}
+
+// Function to retrieve the caregivers linked to an elderly user
+#[ic_cdk::query]
+fn get_caregivers_by_user_id(user_id: u64) -> Result<Vec<User>, String> {
+    let caregivers: Vec<User> = linked_caregiver_ids(user_id)
+        .iter()
+        .filter_map(|caregiver_id| USERS_STORAGE.with(|storage| storage.borrow().get(caregiver_id)))
+        .collect();
+    if caregivers.is_empty() {
+        Err("No caregivers found.".to_string())
+    } else {
+        Ok(caregivers)
+    }
+}
+
+// Helper to list the IDs of the caregivers linked to an elderly user
+fn linked_caregiver_ids(elderly_id: u64) -> Vec<u64> {
+    CAREGIVER_LINKS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, link)| link.elderly_id == elderly_id)
+            .map(|(_, link)| link.caregiver_id)
+            .collect()
+    })
+}
+
+// Helper to check whether a caregiver is linked to an elderly user
+fn is_linked_caregiver(elderly_id: u64, caregiver_id: u64) -> bool {
+    linked_caregiver_ids(elderly_id).contains(&caregiver_id)
+}
+
+// Function to retrieve all users
+#[ic_cdk::query]
+fn get_all_users() -> Result<Vec<User>, String> {
+    if !is_admin() {
+        return Err("Only an admin can list every user.".to_string());
+    }
+    USERS_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<User> = stable_btree_map
+            .iter()
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No users found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to create a new health record
+#[ic_cdk::update]
+fn create_health_record(payload: HealthRecordPayload) -> Result<HealthRecord, String> {
+    // Ensure all fields are provided
+    if payload.blood_pressure.is_empty() || payload.activity_level.is_empty() {
+        return Err("All fields must be provided.".to_string());
+    }
+    if payload.blood_pressure.len() > MAX_BLOOD_PRESSURE_LENGTH
+        || payload.activity_level.len() > MAX_ACTIVITY_LEVEL_LENGTH
+    {
+        return Err(format!(
+            "Blood pressure can be at most {} bytes and activity level {} bytes long.",
+            MAX_BLOOD_PRESSURE_LENGTH, MAX_ACTIVITY_LEVEL_LENGTH
+        ));
+    }
+
+    // Ensure user ID exists
+    let user_exists = USERS_STORAGE.with(|storage| storage.borrow().contains_key(&payload.user_id));
+    if !user_exists {
+        return Err("User ID does not exist.".to_string());
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let health_record = HealthRecord {
+        id,
+        user_id: payload.user_id,
+        heart_rate: payload.heart_rate,
+        blood_pressure: payload.blood_pressure,
+        activity_level: payload.activity_level,
+        status: payload.status,
+        recorded_at: time(),
+    };
+
+    HEALTH_RECORDS_STORAGE.with(|storage| storage.borrow_mut().insert(id, health_record.clone()));
+
+    // Raise an alert when the recorded status is critical
+    if health_record.status == HealthStatus::Critical {
+        raise_alert(
+            health_record.user_id,
+            AlertType::CriticalHealthStatus,
+            critical_health_message(&health_record),
+        );
+    }
+
+    Ok(health_record)
+}
+
+// Helper to write the alert message for a critical health record
+fn critical_health_message(record: &HealthRecord) -> String {
+    format!(
+        "Critical health status recorded (heart rate {}, blood pressure {}).",
+        record.heart_rate, record.blood_pressure
+    )
+}
+
+// Function to retrieve all health records
+#[ic_cdk::query]
+fn get_all_health_records() -> Result<Vec<HealthRecord>, String> {
+    if !is_admin() {
+        return Err("Only an admin can list every user's records.".to_string());
+    }
+    HEALTH_RECORDS_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<HealthRecord> = stable_btree_map
+            .iter()
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No health records found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to record an allergy or intolerance for a user
+#[ic_cdk::update]
+fn create_allergy(payload: AllergyPayload) -> Result<Allergy, String> {
+    if payload.substance.trim().is_empty() || payload.reaction.is_empty() {
+        return Err("All fields must be provided.".to_string());
+    }
+    if payload.substance.trim().len() > MAX_ALLERGEN_LENGTH
+        || payload.reaction.len() > MAX_ALLERGY_REACTION_LENGTH
+    {
+        return Err(format!(
+            "Substances can be at most {} bytes and reactions {} bytes long.",
+            MAX_ALLERGEN_LENGTH, MAX_ALLERGY_REACTION_LENGTH
+        ));
+    }
+
+    check_measurement_recorder(payload.user_id, payload.recorded_by, "record allergies")?;
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let allergy = Allergy {
+        id,
+        user_id: payload.user_id,
+        substance: payload.substance.trim().to_string(),
+        category: payload.category,
+        is_intolerance: payload.is_intolerance,
+        reaction: payload.reaction,
+        severity: payload.severity,
+        recorded_at: time(),
+    };
+
+    ALLERGIES_STORAGE.with(|storage| storage.borrow_mut().insert(id, allergy.clone()));
+    Ok(allergy)
+}
+
+// Function for the user, a linked caregiver or a healthcare provider to remove
+// an allergy record
+#[ic_cdk::update]
+fn remove_allergy(allergy_id: u64, removed_by: u64) -> Result<Allergy, String> {
+    let allergy = ALLERGIES_STORAGE
+        .with(|storage| storage.borrow().get(&allergy_id))
+        .ok_or("Allergy ID does not exist.".to_string())?;
+    check_measurement_recorder(allergy.user_id, removed_by, "remove allergies")?;
+
+    ALLERGIES_STORAGE.with(|storage| storage.borrow_mut().remove(&allergy_id));
+    Ok(allergy)
+}
+
+// Function to retrieve allergies by user ID
+#[ic_cdk::query]
+fn get_allergies_by_user_id(user_id: u64) -> Result<Vec<Allergy>, String> {
+    ALLERGIES_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<Allergy> = stable_btree_map
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id)
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No allergies found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Helper to match items against a user's recorded allergens of the given
+// categories; a severe allergy rejects the entry, anything else is a warning.
+// At most MAX_ALLERGY_WARNINGS are kept, most severe first.
+fn check_allergies(
+    user_id: u64,
+    categories: &[AllergyCategory],
+    items: &[&str],
+) -> Result<Vec<AllergyWarning>, String> {
+    let allergies: Vec<Allergy> = ALLERGIES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, allergy)| {
+                allergy.user_id == user_id && categories.contains(&allergy.category)
+            })
+            .map(|(_, allergy)| allergy)
+            .collect()
+    });
+
+    let mut warnings = Vec::new();
+    for allergy in allergies {
+        let Some(item) = items
+            .iter()
+            .find(|item| allergen_matches(&allergy.substance, item))
+        else {
+            continue;
+        };
+
+        if allergy.severity == AllergySeverity::Severe && !allergy.is_intolerance {
+            return Err(format!(
+                "{} matches a severe {} allergy ({}).",
+                item.trim(),
+                allergy.substance,
+                allergy.reaction
+            ));
+        }
+        warnings.push(AllergyWarning {
+            allergy_id: allergy.id,
+            severity: allergy.severity,
+        });
+    }
+    warnings.sort_by_key(|warning| std::cmp::Reverse(warning.severity));
+    warnings.truncate(MAX_ALLERGY_WARNINGS);
+    Ok(warnings)
+}
+
+// Helper to check whether an item names an allergen: the allergen's words must
+// appear as whole words, in order, so "peanut" matches "Peanut butter" but
+// "pen" does not match "pentoxifylline"
+fn allergen_matches(substance: &str, item: &str) -> bool {
+    let words = |name: &str| -> Vec<String> {
+        name.split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_lowercase())
+            .collect()
+    };
+    let substance = words(substance);
+    let item = words(item);
+    !substance.is_empty()
+        && item
+            .windows(substance.len())
+            .any(|window| window == substance.as_slice())
+}
+
+// Function to create a new medication reminder
+#[ic_cdk::update]
+fn create_medication_reminder(
+    payload: MedicationReminderPayload,
+) -> Result<MedicationReminder, String> {
+    store_medication_reminder(payload, None)
+}
+
+// Helper to validate and store a medication reminder, optionally backing a prescription
+fn store_medication_reminder(
+    payload: MedicationReminderPayload,
+    prescription_id: Option<u64>,
+) -> Result<MedicationReminder, String> {
+    validate_medication_name(&payload.medication_name)?;
+    validate_dosage(&payload.dosage)?;
+    validate_medication_schedule(&payload.schedule)?;
+
+    // Ensure user ID exists
+    let user = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&payload.user_id))
+        .ok_or("User ID does not exist.".to_string())?;
+
+    check_scheduled_daily_dose(&user, &payload)?;
+
+    let allergy_warnings = check_allergies(
+        payload.user_id,
+        &[AllergyCategory::Medication],
+        &[&payload.medication_name],
+    )?;
+
+    // Check the new medication against the user's other active medications
+    let interaction_warnings =
+        check_drug_interactions(payload.user_id, &payload.medication_name, time());
+    if let Some(blocking) = interaction_warnings
+        .iter()
+        .find(|warning| warning.severity == InteractionSeverity::Contraindicated)
+    {
+        let interacting_medication = MEDICATION_REMINDERS_STORAGE
+            .with(|storage| {
+                storage
+                    .borrow()
+                    .get(&blocking.interacting_medication_reminder_id)
+            })
+            .map_or(String::new(), |reminder| reminder.medication_name);
+        let description = DRUG_INTERACTIONS_STORAGE
+            .with(|storage| storage.borrow().get(&blocking.drug_interaction_id))
+            .map_or(String::new(), |interaction| interaction.description);
+        return Err(format!(
+            "{} is contraindicated with {}: {}",
+            payload.medication_name, interacting_medication, description
+        ));
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let medication_reminder = MedicationReminder {
+        id,
+        user_id: payload.user_id,
+        medication_name: payload.medication_name,
+        dosage: payload.dosage,
+        schedule: payload.schedule,
+        interaction_warnings,
+        allergy_warnings,
+        prescription_id,
+        legacy_entry: None,
+        created_at: time(),
+    };
+
+    MEDICATION_REMINDERS_STORAGE
+        .with(|storage| storage.borrow_mut().insert(id, medication_reminder.clone()));
+    Ok(medication_reminder)
+}
+
+// Helper to validate a structured medication schedule
+fn validate_medication_schedule(schedule: &MedicationSchedule) -> Result<(), String> {
+    let valid_times = |times: &Vec<TimeOfDay>| {
+        !times.is_empty()
+            && times.len() <= MAX_DOSE_TIMES_PER_DAY
+            && times
+                .iter()
+                .all(|time_of_day| time_of_day.hour < 24 && time_of_day.minute < 60)
+    };
+
+    match &schedule.frequency {
+        ScheduleFrequency::AsNeeded => {}
+        ScheduleFrequency::FixedTimes { times } => {
+            if !valid_times(times) {
+                return Err(format!(
+                    "Fixed times must be between 1 and {} valid times of day.",
+                    MAX_DOSE_TIMES_PER_DAY
+                ));
+            }
+        }
+        ScheduleFrequency::EveryNHours { interval_hours } => {
+            if !(1..=168).contains(interval_hours) {
+                return Err("Dose interval must be between 1 and 168 hours.".to_string());
+            }
+        }
+        ScheduleFrequency::Weekdays { days, times } => {
+            if days.is_empty() || days.len() > ALL_WEEKDAYS.len() || !valid_times(times) {
+                return Err(format!(
+                    "Weekday schedules need 1 to 7 days and 1 to {} valid times.",
+                    MAX_DOSE_TIMES_PER_DAY
+                ));
+            }
+        }
+    }
+
+    if schedule.start_date == 0 {
+        return Err("Schedule start date must be provided.".to_string());
+    }
+    if let Some(end_date) = schedule.end_date {
+        if end_date <= schedule.start_date {
+            return Err("Schedule end date must be after the start date.".to_string());
+        }
+    }
+    Ok(())
+}
+
+// Helper to check whether a reminder is still in effect: its schedule has not
+// ended and any prescription behind it is neither paused nor discontinued
+fn reminder_is_active(reminder: &MedicationReminder, now: u64) -> bool {
+    if !schedule_is_active(&reminder.schedule, now) {
+        return false;
+    }
+    match reminder.prescription_id {
+        Some(prescription_id) => PRESCRIPTIONS_STORAGE
+            .with(|storage| storage.borrow().get(&prescription_id))
+            .map(|prescription| prescription.status == PrescriptionStatus::Active)
+            .unwrap_or(true),
+        None => true,
+    }
+}
+
+// Helper to check whether a schedule has not yet reached its end date
+fn schedule_is_active(schedule: &MedicationSchedule, now: u64) -> bool {
+    match schedule.end_date {
+        Some(end_date) => end_date > now,
+        None => true,
+    }
+}
+
+// Helper to list the dose times of a schedule that fall within [from, to)
+fn dose_times_between(
+    schedule: &MedicationSchedule,
+    utc_offset_minutes: i32,
+    from: u64,
+    to: u64,
+) -> Vec<u64> {
+    let from = from.max(schedule.start_date);
+    let to = schedule.end_date.map_or(to, |end_date| to.min(end_date));
+    if from >= to {
+        return Vec::new();
+    }
+
+    match &schedule.frequency {
+        ScheduleFrequency::AsNeeded => Vec::new(),
+        ScheduleFrequency::EveryNHours { interval_hours } => {
+            let interval = *interval_hours as u64 * NANOS_PER_HOUR;
+            let elapsed = from - schedule.start_date;
+            let mut due_at = schedule.start_date + elapsed.div_ceil(interval) * interval;
+            let mut doses = Vec::new();
+            while due_at < to {
+                doses.push(due_at);
+                due_at += interval;
+            }
+            doses
+        }
+        ScheduleFrequency::FixedTimes { times } => {
+            local_dose_times_between(times, &ALL_WEEKDAYS, utc_offset_minutes, from, to)
+        }
+        ScheduleFrequency::Weekdays { days, times } => {
+            local_dose_times_between(times, days, utc_offset_minutes, from, to)
+        }
+    }
+}
+
+const ALL_WEEKDAYS: [Weekday; 7] = [
+    Weekday::Monday,
+    Weekday::Tuesday,
+    Weekday::Wednesday,
+    Weekday::Thursday,
+    Weekday::Friday,
+    Weekday::Saturday,
+    Weekday::Sunday,
+];
+
+// Helper to expand local times of day on the given weekdays into UTC dose times
+fn local_dose_times_between(
+    times: &[TimeOfDay],
+    days: &[Weekday],
+    utc_offset_minutes: i32,
+    from: u64,
+    to: u64,
+) -> Vec<u64> {
+    let offset = utc_offset_minutes as i64 * NANOS_PER_MINUTE as i64;
+    let day = NANOS_PER_DAY as i64;
+    let local_from = from as i64 + offset;
+    let local_to = to as i64 + offset;
+
+    let mut times = times.to_vec();
+    times.sort_by_key(|time_of_day| (time_of_day.hour, time_of_day.minute));
+
+    let mut doses = Vec::new();
+    let mut local_midnight = local_from.div_euclid(day) * day;
+    while local_midnight < local_to {
+        if days.contains(&weekday_of(local_midnight)) {
+            for time_of_day in &times {
+                let local_due_at = local_midnight
+                    + time_of_day.hour as i64 * NANOS_PER_HOUR as i64
+                    + time_of_day.minute as i64 * NANOS_PER_MINUTE as i64;
+                if local_due_at >= local_from && local_due_at < local_to {
+                    doses.push((local_due_at - offset) as u64);
+                }
+            }
+        }
+        local_midnight += day;
+    }
+    doses
+}
+
+// Helper to find the weekday of a nanosecond timestamp
+fn weekday_of(nanos: i64) -> Weekday {
+    match DateTime::from_timestamp_nanos(nanos).weekday() {
+        chrono::Weekday::Mon => Weekday::Monday,
+        chrono::Weekday::Tue => Weekday::Tuesday,
+        chrono::Weekday::Wed => Weekday::Wednesday,
+        chrono::Weekday::Thu => Weekday::Thursday,
+        chrono::Weekday::Fri => Weekday::Friday,
+        chrono::Weekday::Sat => Weekday::Saturday,
+        chrono::Weekday::Sun => Weekday::Sunday,
+    }
+}
+
+// Helper to render a schedule as a short human readable description
+fn describe_schedule(schedule: &MedicationSchedule) -> String {
+    let format_times = |times: &Vec<TimeOfDay>| {
+        times
+            .iter()
+            .map(|time_of_day| format!("{:02}:{:02}", time_of_day.hour, time_of_day.minute))
+            .collect::<Vec<String>>()
+            .join(" ")
+    };
+
+    match &schedule.frequency {
+        ScheduleFrequency::AsNeeded => "as needed".to_string(),
+        ScheduleFrequency::FixedTimes { times } => format!("daily at {}", format_times(times)),
+        ScheduleFrequency::EveryNHours { interval_hours } => {
+            format!("every {} hours", interval_hours)
+        }
+        ScheduleFrequency::Weekdays { days, times } => format!(
+            "{} at {}",
+            days.iter()
+                .map(|day| format!("{:?}", day))
+                .collect::<Vec<String>>()
+                .join(" "),
+            format_times(times)
+        ),
+    }
+}
+
+// Function to retrieve the next due dose of each of a user's scheduled medications
+#[ic_cdk::query]
+fn get_next_doses_by_user_id(user_id: u64) -> Result<Vec<NextDose>, String> {
+    let user = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .ok_or("User ID does not exist.".to_string())?;
+
+    let now = time();
+    // A week and a day covers every weekday and interval schedule
+    let horizon = now + 8 * NANOS_PER_DAY;
+
+    let mut doses: Vec<NextDose> = MEDICATION_REMINDERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id && reminder_is_active(record, now))
+            .filter_map(|(_, record)| {
+                dose_times_between(
+                    &record.schedule,
+                    user.utc_offset_minutes.unwrap_or(0),
+                    now,
+                    horizon,
+                )
+                .first()
+                .map(|due_at| NextDose {
+                    medication_reminder_id: record.id,
+                    medication_name: record.medication_name.clone(),
+                    dosage: record.dosage.clone(),
+                    due_at: *due_at,
+                })
+            })
+            .collect()
+    });
+    doses.sort_by_key(|dose| dose.due_at);
+
+    if doses.is_empty() {
+        Err("No upcoming doses found.".to_string())
+    } else {
+        Ok(doses)
+    }
+}
+
+// Function to retrieve all medication reminders
+#[ic_cdk::query]
+fn get_all_medication_reminders() -> Result<Vec<MedicationReminder>, String> {
+    if !is_admin() {
+        return Err("Only an admin can list every user's records.".to_string());
+    }
+    MEDICATION_REMINDERS_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<MedicationReminder> = stable_btree_map
+            .iter()
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No medication reminders found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Helper to check whether the caller is an admin (a controller of the canister)
+fn is_admin() -> bool {
+    ic_cdk::api::is_controller(&ic_cdk::caller())
+}
+
+// Function to add an entry to the drug interaction table (admin only)
+#[ic_cdk::update]
+fn add_drug_interaction(payload: DrugInteractionPayload) -> Result<DrugInteraction, String> {
+    if !is_admin() {
+        return Err("Only an admin can manage drug interactions.".to_string());
+    }
+    let (drug_a, drug_b) = validate_drug_interaction(&payload, None)?;
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let interaction = DrugInteraction {
+        id,
+        drug_a,
+        drug_b,
+        severity: payload.severity,
+        description: payload.description,
+        created_at: time(),
+        updated_at: time(),
+    };
+
+    DRUG_INTERACTIONS_STORAGE.with(|storage| storage.borrow_mut().insert(id, interaction.clone()));
+    Ok(interaction)
+}
+
+// Function to update an entry in the drug interaction table (admin only)
+#[ic_cdk::update]
+fn update_drug_interaction(
+    interaction_id: u64,
+    payload: DrugInteractionPayload,
+) -> Result<DrugInteraction, String> {
+    if !is_admin() {
+        return Err("Only an admin can manage drug interactions.".to_string());
+    }
+    let (drug_a, drug_b) = validate_drug_interaction(&payload, Some(interaction_id))?;
+
+    DRUG_INTERACTIONS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let mut interaction = storage
+            .get(&interaction_id)
+            .ok_or("Drug interaction ID does not exist.".to_string())?;
+        interaction.drug_a = drug_a;
+        interaction.drug_b = drug_b;
+        interaction.severity = payload.severity;
+        interaction.description = payload.description;
+        interaction.updated_at = time();
+        storage.insert(interaction_id, interaction.clone());
+        Ok(interaction)
+    })
+}
+
+// Function to remove an entry from the drug interaction table (admin only)
+#[ic_cdk::update]
+fn remove_drug_interaction(interaction_id: u64) -> Result<DrugInteraction, String> {
+    if !is_admin() {
+        return Err("Only an admin can manage drug interactions.".to_string());
+    }
+
+    DRUG_INTERACTIONS_STORAGE
+        .with(|storage| storage.borrow_mut().remove(&interaction_id))
+        .ok_or("Drug interaction ID does not exist.".to_string())
+}
+
+// Function to retrieve the drug interaction table
+#[ic_cdk::query]
+fn get_all_drug_interactions() -> Result<Vec<DrugInteraction>, String> {
+    DRUG_INTERACTIONS_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<DrugInteraction> = stable_btree_map
+            .iter()
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No drug interactions found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Helper to validate a drug interaction payload and return the normalized drug
+// names; a pair of drugs can only be in the table once, other than as the
+// interaction being updated
+fn validate_drug_interaction(
+    payload: &DrugInteractionPayload,
+    interaction_id: Option<u64>,
+) -> Result<(String, String), String> {
+    let drug_a = normalize_drug_name(&payload.drug_a);
+    let drug_b = normalize_drug_name(&payload.drug_b);
+    if drug_a.is_empty() || drug_b.is_empty() || payload.description.is_empty() {
+        return Err("All fields must be provided.".to_string());
+    }
+    if drug_a.len() > MAX_DRUG_NAME_LENGTH || drug_b.len() > MAX_DRUG_NAME_LENGTH {
+        return Err(format!(
+            "Drug names can be at most {} bytes long.",
+            MAX_DRUG_NAME_LENGTH
+        ));
+    }
+    if payload.description.len() > MAX_INTERACTION_DESCRIPTION_LENGTH {
+        return Err(format!(
+            "Interaction descriptions can be at most {} bytes long.",
+            MAX_INTERACTION_DESCRIPTION_LENGTH
+        ));
+    }
+    if drug_a == drug_b {
+        return Err("An interaction needs two different drugs.".to_string());
+    }
+
+    let duplicate = DRUG_INTERACTIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().any(|(id, interaction)| {
+            Some(id) != interaction_id && interaction_matches(&interaction, &drug_a, &drug_b)
+        })
+    });
+    if duplicate {
+        return Err("An interaction between these drugs already exists.".to_string());
+    }
+    Ok((drug_a, drug_b))
+}
+
+// Helper to normalize a drug name for comparisons
+fn normalize_drug_name(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+// Helper to check whether an interaction applies to a pair of normalized drug names
+fn interaction_matches(interaction: &DrugInteraction, drug_a: &str, drug_b: &str) -> bool {
+    (interaction.drug_a == drug_a && interaction.drug_b == drug_b)
+        || (interaction.drug_a == drug_b && interaction.drug_b == drug_a)
+}
+
+// Helper to find interactions between a medication and a user's active
+// medications, most severe first; at most MAX_INTERACTION_WARNINGS are kept
+fn check_drug_interactions(
+    user_id: u64,
+    medication_name: &str,
+    now: u64,
+) -> Vec<InteractionWarning> {
+    let new_drug = normalize_drug_name(medication_name);
+    let active_medications: Vec<MedicationReminder> =
+        MEDICATION_REMINDERS_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .filter(|(_, record)| record.user_id == user_id && reminder_is_active(record, now))
+                .map(|(_, record)| record)
+                .collect()
+        });
+
+    DRUG_INTERACTIONS_STORAGE.with(|storage| {
+        let interactions = storage.borrow();
+        let mut warnings = Vec::new();
+        for medication in &active_medications {
+            let existing_drug = normalize_drug_name(&medication.medication_name);
+            for (_, interaction) in interactions.iter() {
+                if interaction_matches(&interaction, &new_drug, &existing_drug) {
+                    warnings.push(InteractionWarning {
+                        drug_interaction_id: interaction.id,
+                        interacting_medication_reminder_id: medication.id,
+                        severity: interaction.severity,
+                    });
+                }
+            }
+        }
+        warnings.sort_by_key(|warning| std::cmp::Reverse(warning.severity));
+        warnings.truncate(MAX_INTERACTION_WARNINGS);
+        warnings
+    })
+}
+
+// Function to set the maximum daily dose of a drug (admin only)
+#[ic_cdk::update]
+fn set_drug_dose_limit(payload: DrugDoseLimitPayload) -> Result<DrugDoseLimit, String> {
+    if !is_admin() {
+        return Err("Only an admin can manage drug dose limits.".to_string());
+    }
+
+    let drug_name = normalize_drug_name(&payload.drug_name);
+    if drug_name.is_empty() {
+        return Err("All fields must be provided.".to_string());
+    }
+    if !payload.max_daily_quantity.is_finite() || payload.max_daily_quantity <= 0.0 {
+        return Err("Maximum daily dose must be greater than zero.".to_string());
+    }
+
+    // Setting a limit for a drug that already has one replaces it
+    let id = match find_drug_dose_limit(&drug_name) {
+        Some(existing) => existing.id,
+        None => ID_COUNTER
+            .with(|counter| {
+                let current_value = *counter.borrow().get();
+                counter.borrow_mut().set(current_value + 1)
+            })
+            .expect("Cannot increment ID counter"),
+    };
+
+    let limit = DrugDoseLimit {
+        id,
+        drug_name,
+        max_daily_quantity: payload.max_daily_quantity,
+        unit: payload.unit,
+        updated_at: time(),
+    };
+
+    DRUG_DOSE_LIMITS_STORAGE.with(|storage| storage.borrow_mut().insert(id, limit.clone()));
+    Ok(limit)
+}
+
+// Function to remove the maximum daily dose of a drug (admin only)
+#[ic_cdk::update]
+fn remove_drug_dose_limit(limit_id: u64) -> Result<DrugDoseLimit, String> {
+    if !is_admin() {
+        return Err("Only an admin can manage drug dose limits.".to_string());
+    }
+
+    DRUG_DOSE_LIMITS_STORAGE
+        .with(|storage| storage.borrow_mut().remove(&limit_id))
+        .ok_or("Drug dose limit ID does not exist.".to_string())
+}
+
+// Function to retrieve all drug dose limits
+#[ic_cdk::query]
+fn get_all_drug_dose_limits() -> Result<Vec<DrugDoseLimit>, String> {
+    DRUG_DOSE_LIMITS_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<DrugDoseLimit> = stable_btree_map
+            .iter()
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No drug dose limits found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Helper to find the dose limit of a normalized drug name
+fn find_drug_dose_limit(drug_name: &str) -> Option<DrugDoseLimit> {
+    DRUG_DOSE_LIMITS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .find(|(_, limit)| limit.drug_name == drug_name)
+            .map(|(_, limit)| limit)
+    })
+}
+
+// Helper to validate a dosage
+fn validate_dosage(dosage: &Dosage) -> Result<(), String> {
+    if !(MIN_DOSAGE_QUANTITY..=MAX_DOSAGE_QUANTITY).contains(&dosage.quantity) {
+        return Err(format!(
+            "Dosage quantity must be between {} and {}.",
+            MIN_DOSAGE_QUANTITY, MAX_DOSAGE_QUANTITY
+        ));
+    }
+    Ok(())
+}
+
+// Helper to validate a medication name
+fn validate_medication_name(medication_name: &str) -> Result<(), String> {
+    if medication_name.is_empty() {
+        return Err("All fields must be provided.".to_string());
+    }
+    if medication_name.len() > MAX_MEDICATION_NAME_LENGTH {
+        return Err(format!(
+            "Medication names can be at most {} bytes long.",
+            MAX_MEDICATION_NAME_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+// Helper to read a free-text dosage such as "500mg", "1,000 IU", "10 ml",
+// "250 mcg capsule" or "2 puffs"
+fn parse_dosage(text: &str) -> Option<Dosage> {
+    let text = text.trim().to_lowercase();
+    let number_end = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == ','))
+        .unwrap_or(text.len());
+    let quantity: f64 = text[..number_end].replace(',', "").parse().ok()?;
+    if !quantity.is_finite() || quantity <= 0.0 {
+        return None;
+    }
+
+    let mut words = text[number_end..].split_whitespace();
+    let (unit, form) = match words.next()? {
+        "mg" | "milligram" | "milligrams" => (DoseUnit::Mg, None),
+        "mcg" | "µg" | "ug" | "microgram" | "micrograms" => (DoseUnit::Mcg, None),
+        "g" | "gram" | "grams" => (DoseUnit::G, None),
+        "ml" | "millilitre" | "millilitres" | "milliliter" | "milliliters" => {
+            (DoseUnit::Ml, Some(DosageForm::Liquid))
+        }
+        "iu" | "unit" | "units" => (DoseUnit::Iu, None),
+        word => (DoseUnit::Count, Some(parse_dosage_form(word)?)),
+    };
+    // A form may follow a unit, as in "500 mg tablet"; anything else is not a dosage
+    let form = match (words.next(), words.next()) {
+        (None, _) => form.unwrap_or(DosageForm::Other),
+        (Some(word), None) if unit != DoseUnit::Count => parse_dosage_form(word)?,
+        _ => return None,
+    };
+    Some(Dosage {
+        quantity,
+        unit,
+        form,
+    })
+}
+
+// Helper to read a dosage form word, singular or plural
+fn parse_dosage_form(word: &str) -> Option<DosageForm> {
+    match word {
+        "tablet" | "tablets" | "tab" | "tabs" => Some(DosageForm::Tablet),
+        "capsule" | "capsules" | "cap" | "caps" => Some(DosageForm::Capsule),
+        "liquid" | "solution" | "syrup" => Some(DosageForm::Liquid),
+        "puff" | "puffs" => Some(DosageForm::Puff),
+        "injection" | "injections" => Some(DosageForm::Injection),
+        "patch" | "patches" => Some(DosageForm::Patch),
+        "drop" | "drops" => Some(DosageForm::Drops),
+        "cream" => Some(DosageForm::Cream),
+        _ => None,
+    }
+}
+
+// Helper to render a dosage, e.g. "500 mg tablet" or "2 puff"
+fn format_dosage(dosage: &Dosage) -> String {
+    let form = format!("{:?}", dosage.form).to_lowercase();
+    match dosage.unit {
+        DoseUnit::Count => format!("{} {}", dosage.quantity, form),
+        unit => format!("{} {} {}", dosage.quantity, dose_unit_label(unit), form),
+    }
+}
+
+// Helper to render a dose unit
+fn dose_unit_label(unit: DoseUnit) -> &'static str {
+    match unit {
+        DoseUnit::Mg => "mg",
+        DoseUnit::Mcg => "mcg",
+        DoseUnit::G => "g",
+        DoseUnit::Ml => "ml",
+        DoseUnit::Iu => "IU",
+        DoseUnit::Count => "units",
+    }
+}
+
+// Helper to express an amount in the base unit of its kind (mg for mass), so
+// that doses in compatible units can be added up and compared
+fn to_base_unit(quantity: f64, unit: DoseUnit) -> (f64, DoseUnit) {
+    match unit {
+        DoseUnit::Mcg => (quantity / 1000.0, DoseUnit::Mg),
+        DoseUnit::G => (quantity * 1000.0, DoseUnit::Mg),
+        _ => (quantity, unit),
+    }
+}
+
+// Helper to find the largest total amount given within any 24 hour window
+fn max_amount_in_any_day(mut doses: Vec<(u64, f64)>) -> f64 {
+    doses.sort_by_key(|(given_at, _)| *given_at);
+    let mut max_amount: f64 = 0.0;
+    for (index, (window_start, _)) in doses.iter().enumerate() {
+        let amount: f64 = doses[index..]
+            .iter()
+            .take_while(|(given_at, _)| *given_at < window_start + NANOS_PER_DAY)
+            .map(|(_, quantity)| quantity)
+            .sum();
+        max_amount = max_amount.max(amount);
+    }
+    max_amount
+}
+
+// Helper to compare the doses of a drug against its maximum daily dose; a dose
+// in a unit that cannot be converted to the limit's unit cannot be checked,
+// so it is rejected rather than left out of the total
+fn check_max_daily_dose(
+    medication_name: &str,
+    doses: Vec<(u64, Dosage)>,
+    action: &str,
+) -> Result<(), String> {
+    let Some(limit) = find_drug_dose_limit(&normalize_drug_name(medication_name)) else {
+        return Ok(());
+    };
+    let (max_quantity, limit_unit) = to_base_unit(limit.max_daily_quantity, limit.unit);
+
+    let mut amounts: Vec<(u64, f64)> = Vec::new();
+    for (given_at, dosage) in doses {
+        let (quantity, unit) = to_base_unit(dosage.quantity, dosage.unit);
+        if unit != limit_unit {
+            return Err(format!(
+                "{} includes a dose of {} in {}, which cannot be checked against its maximum daily dose in {}; give the dose in {}.",
+                action,
+                medication_name,
+                dose_unit_label(dosage.unit),
+                dose_unit_label(limit.unit),
+                dose_unit_label(limit.unit)
+            ));
+        }
+        amounts.push((given_at, quantity));
+    }
+
+    let max_amount = max_amount_in_any_day(amounts);
+    if max_amount > max_quantity {
+        return Err(format!(
+            "{} would give {} {} of {} within 24 hours, above the maximum daily dose of {} {}.",
+            action,
+            max_amount,
+            dose_unit_label(limit_unit),
+            medication_name,
+            max_quantity,
+            dose_unit_label(limit_unit)
+        ));
+    }
+    Ok(())
+}
+
+// Helper to reject a new schedule that, together with the user's other active
+// reminders for the same drug, would exceed its maximum daily dose
+fn check_scheduled_daily_dose(
+    user: &User,
+    payload: &MedicationReminderPayload,
+) -> Result<(), String> {
+    let now = time();
+    let from = now.max(payload.schedule.start_date);
+    // A week and a day covers every weekday and interval schedule
+    let to = from + 8 * NANOS_PER_DAY;
+    let drug_name = normalize_drug_name(&payload.medication_name);
+
+    let mut doses: Vec<(u64, Dosage)> = dose_times_between(
+        &payload.schedule,
+        user.utc_offset_minutes.unwrap_or(0),
+        from,
+        to,
+    )
+    .into_iter()
+    .map(|due_at| (due_at, payload.dosage.clone()))
+    .collect();
+
+    MEDICATION_REMINDERS_STORAGE.with(|storage| {
+        for (_, reminder) in storage.borrow().iter() {
+            if reminder.user_id != user.id
+                || normalize_drug_name(&reminder.medication_name) != drug_name
+                || !reminder_is_active(&reminder, now)
+            {
+                continue;
+            }
+            for due_at in dose_times_between(
+                &reminder.schedule,
+                user.utc_offset_minutes.unwrap_or(0),
+                from,
+                to,
+            ) {
+                doses.push((due_at, reminder.dosage.clone()));
+            }
+        }
+    });
+
+    check_max_daily_dose(&payload.medication_name, doses, "This schedule")
+}
+
+// Helper to reject recording a taken dose that, together with the other doses
+// of the same drug taken around it, would exceed its maximum daily dose
+fn check_recorded_daily_dose(reminder: &MedicationReminder, given_at: u64) -> Result<(), String> {
+    let drug_name = normalize_drug_name(&reminder.medication_name);
+    let reminders: Vec<MedicationReminder> = MEDICATION_REMINDERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| {
+                record.user_id == reminder.user_id
+                    && normalize_drug_name(&record.medication_name) == drug_name
+            })
+            .map(|(_, record)| record)
+            .collect()
+    });
+
+    let mut doses = vec![(given_at, reminder.dosage.clone())];
+    DOSE_EVENTS_STORAGE.with(|storage| {
+        for (_, event) in storage.borrow().iter() {
+            let within_a_day = event.scheduled_for.abs_diff(given_at) < NANOS_PER_DAY;
+            let taken = matches!(event.status, DoseStatus::Taken | DoseStatus::Late);
+            if !within_a_day || !taken {
+                continue;
+            }
+            if let Some(record) = reminders
+                .iter()
+                .find(|record| record.id == event.medication_reminder_id)
+            {
+                doses.push((event.scheduled_for, record.dosage.clone()));
+            }
+        }
+    });
+
+    check_max_daily_dose(&reminder.medication_name, doses, "This dose")
+}
+
+// Function for a healthcare provider to issue a prescription, which also
+// creates the medication reminder that drives dose scheduling
+#[ic_cdk::update]
+fn create_prescription(payload: PrescriptionPayload) -> Result<Prescription, String> {
+    if payload.indication.is_empty() {
+        return Err("All fields must be provided.".to_string());
+    }
+    validate_medication_name(&payload.medication_name)?;
+    validate_status_reason(&payload.indication)?;
+
+    let provider = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&payload.provider_id))
+        .ok_or("Provider ID does not exist.".to_string())?;
+    if provider.user_type != UserType::HealthcareProvider {
+        return Err("Only healthcare providers can issue prescriptions.".to_string());
+    }
+    check_caller_is_user(payload.provider_id)?;
+    if payload.provider_id == payload.patient_id {
+        return Err("Providers cannot prescribe for themselves.".to_string());
+    }
+
+    let end_date =
+        prescription_end_date(payload.start_date, payload.end_date, payload.duration_days)?;
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let reminder = store_medication_reminder(
+        MedicationReminderPayload {
+            user_id: payload.patient_id,
+            medication_name: payload.medication_name.clone(),
+            dosage: payload.dosage.clone(),
+            schedule: MedicationSchedule {
+                frequency: payload.frequency,
+                start_date: payload.start_date,
+                end_date,
+            },
+        },
+        Some(id),
+    )?;
+
+    let prescription = Prescription {
+        id,
+        patient_id: payload.patient_id,
+        provider_id: payload.provider_id,
+        medication_reminder_id: reminder.id,
+        medication_name: payload.medication_name,
+        dosage: payload.dosage,
+        indication: payload.indication.clone(),
+        start_date: payload.start_date,
+        end_date,
+        refills_allowed: payload.refills_allowed,
+        refills_used: 0,
+        status: PrescriptionStatus::Active,
+        status_history: vec![PrescriptionStatusChange {
+            status: PrescriptionStatus::Active,
+            reason: payload.indication,
+            changed_by: payload.provider_id,
+            changed_at: time(),
+        }],
+        created_at: time(),
+        updated_at: time(),
+    };
+
+    PRESCRIPTIONS_STORAGE.with(|storage| storage.borrow_mut().insert(id, prescription.clone()));
+    Ok(prescription)
+}
+
+// Helper to work out when a prescription ends, from an end date or a duration in days
+fn prescription_end_date(
+    start_date: u64,
+    end_date: Option<u64>,
+    duration_days: Option<u32>,
+) -> Result<Option<u64>, String> {
+    match (end_date, duration_days) {
+        (Some(_), Some(_)) => {
+            Err("Provide either an end date or a duration, not both.".to_string())
+        }
+        (Some(end_date), None) => Ok(Some(end_date)),
+        (None, Some(duration_days)) => (duration_days as u64)
+            .checked_mul(NANOS_PER_DAY)
+            .and_then(|duration| start_date.checked_add(duration))
+            .map(Some)
+            .ok_or("The prescription duration is too long.".to_string()),
+        (None, None) => Ok(None),
+    }
+}
+
+// Helper to check the length of the reason given for a status change
+fn validate_status_reason(reason: &str) -> Result<(), String> {
+    if reason.len() > MAX_STATUS_REASON_LENGTH {
+        return Err(format!(
+            "Reasons can be at most {} bytes long.",
+            MAX_STATUS_REASON_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+// Function for a healthcare provider to pause, resume or discontinue a prescription
+#[ic_cdk::update]
+fn update_prescription_status(
+    prescription_id: u64,
+    payload: PrescriptionStatusPayload,
+) -> Result<Prescription, String> {
+    let provider = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&payload.provider_id))
+        .ok_or("Provider ID does not exist.".to_string())?;
+    if provider.user_type != UserType::HealthcareProvider {
+        return Err("Only healthcare providers can change a prescription.".to_string());
+    }
+    check_caller_is_user(payload.provider_id)?;
+
+    let mut prescription = PRESCRIPTIONS_STORAGE
+        .with(|storage| storage.borrow().get(&prescription_id))
+        .ok_or("Prescription ID does not exist.".to_string())?;
+
+    match (prescription.status, payload.status) {
+        (PrescriptionStatus::Discontinued, _) => {
+            return Err("A discontinued prescription cannot be changed.".to_string())
+        }
+        (current, requested) if current == requested => {
+            return Err(format!("Prescription is already {:?}.", current))
+        }
+        _ => {}
+    }
+    if payload.status != PrescriptionStatus::Active && payload.reason.is_empty() {
+        return Err("A reason is required to pause or discontinue a prescription.".to_string());
+    }
+    validate_status_reason(&payload.reason)?;
+    if payload.status != PrescriptionStatus::Discontinued
+        && prescription.status_history.len() + 1 >= MAX_PRESCRIPTION_STATUS_CHANGES
+    {
+        return Err(
+            "This prescription has no status changes left; discontinue it and issue a new one."
+                .to_string(),
+        );
+    }
+
+    let now = time();
+    prescription.status = payload.status;
+    prescription.status_history.push(PrescriptionStatusChange {
+        status: payload.status,
+        reason: payload.reason,
+        changed_by: payload.provider_id,
+        changed_at: now,
+    });
+    prescription.updated_at = now;
+
+    // Discontinuing ends the reminder schedule so no further doses fall due
+    if payload.status == PrescriptionStatus::Discontinued {
+        prescription.end_date = Some(now);
+        MEDICATION_REMINDERS_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            if let Some(mut reminder) = storage.get(&prescription.medication_reminder_id) {
+                reminder.schedule.end_date = Some(now);
+                storage.insert(reminder.id, reminder);
+            }
+        });
+    }
+
+    PRESCRIPTIONS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(prescription_id, prescription.clone())
+    });
+    Ok(prescription)
+}
+
+// Function for the patient, a linked caregiver or a healthcare provider to
+// record that a prescription has been refilled
+#[ic_cdk::update]
+fn record_prescription_refill(
+    prescription_id: u64,
+    recorded_by: u64,
+) -> Result<Prescription, String> {
+    PRESCRIPTIONS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let mut prescription = storage
+            .get(&prescription_id)
+            .ok_or("Prescription ID does not exist.".to_string())?;
+        check_measurement_recorder(prescription.patient_id, recorded_by, "record a refill")?;
+        if prescription.status != PrescriptionStatus::Active {
+            return Err("Only active prescriptions can be refilled.".to_string());
+        }
+        if prescription.refills_used >= prescription.refills_allowed {
+            return Err("No refills remaining on this prescription.".to_string());
+        }
+        prescription.refills_used += 1;
+        prescription.updated_at = time();
+        storage.insert(prescription_id, prescription.clone());
+        Ok(prescription)
+    })
+}
+
+// Function to retrieve prescriptions by patient ID
+#[ic_cdk::query]
+fn get_prescriptions_by_user_id(user_id: u64) -> Result<Vec<Prescription>, String> {
+    PRESCRIPTIONS_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<Prescription> = stable_btree_map
+            .iter()
+            .filter(|(_, record)| record.patient_id == user_id)
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No prescriptions found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to set the on-hand quantity of a medication and its refill threshold
+#[ic_cdk::update]
+fn set_medication_inventory(
+    payload: MedicationInventoryPayload,
+) -> Result<MedicationInventory, String> {
+    if payload.units_per_dose == 0 {
+        return Err("Units per dose must be greater than zero.".to_string());
+    }
+
+    let reminder = MEDICATION_REMINDERS_STORAGE
+        .with(|storage| storage.borrow().get(&payload.medication_reminder_id))
+        .ok_or("Medication reminder ID does not exist.".to_string())?;
+    check_inventory_recorder(reminder.user_id, payload.recorded_by)?;
+
+    let inventory = MedicationInventory {
+        medication_reminder_id: reminder.id,
+        user_id: reminder.user_id,
+        quantity_on_hand: payload.quantity_on_hand,
+        units_per_dose: payload.units_per_dose,
+        refill_threshold_days: payload.refill_threshold_days,
+        estimated_days_of_supply: None,
+        low_supply_alerted: false,
+        updated_at: time(),
+    };
+
+    Ok(save_medication_inventory(&reminder, inventory))
+}
+
+// Function to add newly dispensed units to a medication's on-hand quantity
+#[ic_cdk::update]
+fn restock_medication_inventory(
+    medication_reminder_id: u64,
+    quantity_added: u32,
+    recorded_by: u64,
+) -> Result<MedicationInventory, String> {
+    let reminder = MEDICATION_REMINDERS_STORAGE
+        .with(|storage| storage.borrow().get(&medication_reminder_id))
+        .ok_or("Medication reminder ID does not exist.".to_string())?;
+    check_inventory_recorder(reminder.user_id, recorded_by)?;
+    let mut inventory = MEDICATION_INVENTORY_STORAGE
+        .with(|storage| storage.borrow().get(&medication_reminder_id))
+        .ok_or("No inventory is recorded for this medication.".to_string())?;
+
+    inventory.quantity_on_hand = inventory.quantity_on_hand.saturating_add(quantity_added);
+    inventory.updated_at = time();
+    Ok(save_medication_inventory(&reminder, inventory))
+}
+
+// Helper to check that a medication's inventory is kept by the user or a
+// linked caregiver, acting as the caller
+fn check_inventory_recorder(user_id: u64, recorded_by: u64) -> Result<(), String> {
+    if recorded_by != user_id && !is_linked_caregiver(user_id, recorded_by) {
+        return Err(
+            "Only the user or a linked caregiver can update medication inventory.".to_string(),
+        );
+    }
+    check_caller_is_user(recorded_by)?;
+    Ok(())
+}
+
+// Function to retrieve the inventory of a medication
+#[ic_cdk::query]
+fn get_medication_inventory(medication_reminder_id: u64) -> Result<MedicationInventory, String> {
+    MEDICATION_INVENTORY_STORAGE
+        .with(|storage| storage.borrow().get(&medication_reminder_id))
+        .ok_or("No inventory is recorded for this medication.".to_string())
+}
+
+// Helper to take one dose out of a medication's inventory, if it is tracked
+fn consume_medication_inventory(reminder: &MedicationReminder) {
+    let Some(mut inventory) =
+        MEDICATION_INVENTORY_STORAGE.with(|storage| storage.borrow().get(&reminder.id))
+    else {
+        return;
+    };
+
+    inventory.quantity_on_hand = inventory
+        .quantity_on_hand
+        .saturating_sub(inventory.units_per_dose);
+    inventory.updated_at = time();
+    save_medication_inventory(reminder, inventory);
+}
+
+// Helper to recompute the days of supply, raise a refill alert when it first
+// drops below the threshold and store the inventory
+fn save_medication_inventory(
+    reminder: &MedicationReminder,
+    mut inventory: MedicationInventory,
+) -> MedicationInventory {
+    inventory.estimated_days_of_supply = estimate_days_of_supply(reminder, &inventory);
+
+    match inventory.estimated_days_of_supply {
+        Some(days) if days < inventory.refill_threshold_days => {
+            if !inventory.low_supply_alerted {
+                raise_alert(
+                    reminder.user_id,
+                    AlertType::LowMedicationSupply,
+                    low_supply_message(reminder, inventory.quantity_on_hand, days),
+                );
+                inventory.low_supply_alerted = true;
+            }
+        }
+        _ => inventory.low_supply_alerted = false,
+    }
+
+    MEDICATION_INVENTORY_STORAGE
+        .with(|storage| storage.borrow_mut().insert(reminder.id, inventory.clone()));
+    inventory
+}
+
+// Helper to write the alert message for a medication running low
+fn low_supply_message(reminder: &MedicationReminder, quantity_on_hand: u32, days: u32) -> String {
+    format!(
+        "{} is running low: {} units left, about {} days of supply.",
+        reminder.medication_name, quantity_on_hand, days
+    )
+}
+
+// Helper to estimate how many days the on-hand quantity lasts, based on the
+// number of doses the schedule calls for over the coming week
+fn estimate_days_of_supply(
+    reminder: &MedicationReminder,
+    inventory: &MedicationInventory,
+) -> Option<u32> {
+    let utc_offset_minutes = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&reminder.user_id))
+        .map_or(0, |user| user.utc_offset_minutes.unwrap_or(0));
+    let now = time();
+    let doses_per_week = dose_times_between(
+        &reminder.schedule,
+        utc_offset_minutes,
+        now,
+        now + 7 * NANOS_PER_DAY,
+    )
+    .len() as u64;
+    if doses_per_week == 0 {
+        return None;
+    }
+
+    let units_per_week = doses_per_week * inventory.units_per_dose as u64;
+    Some((inventory.quantity_on_hand as u64 * 7 / units_per_week) as u32)
+}
+
+// Function to record whether a scheduled dose was taken, skipped, missed or late
+#[ic_cdk::update]
+fn record_dose_event(payload: DoseEventPayload) -> Result<DoseEvent, String> {
+    let reminder = MEDICATION_REMINDERS_STORAGE
+        .with(|storage| storage.borrow().get(&payload.medication_reminder_id))
+        .ok_or("Medication reminder ID does not exist.".to_string())?;
+
+    // Only the elderly user or one of their caregivers may record doses
+    if payload.recorded_by != reminder.user_id
+        && !is_linked_caregiver(reminder.user_id, payload.recorded_by)
+    {
+        return Err("Only the user or a linked caregiver can record doses.".to_string());
+    }
+    check_caller_is_user(payload.recorded_by)?;
+
+    let utc_offset_minutes = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&reminder.user_id))
+        .map_or(0, |user| user.utc_offset_minutes.unwrap_or(0));
+    if !is_scheduled_dose_time(
+        &reminder.schedule,
+        utc_offset_minutes,
+        payload.scheduled_for,
+    ) {
+        return Err(format!(
+            "No dose of {} is scheduled at {}.",
+            reminder.medication_name,
+            format_timestamp(payload.scheduled_for)
+        ));
+    }
+
+    if matches!(payload.status, DoseStatus::Taken | DoseStatus::Late) {
+        check_recorded_daily_dose(&reminder, payload.scheduled_for)?;
+    }
+
+    // Doses marked missed by the reminder timer can still be corrected afterwards
+    let id = match find_dose_event(payload.medication_reminder_id, payload.scheduled_for) {
+        Some(event) if event.recorded_by.is_none() => event.id,
+        Some(_) => return Err("A dose event has already been recorded for this dose.".to_string()),
+        None => ID_COUNTER
+            .with(|counter| {
+                let current_value = *counter.borrow().get();
+                counter.borrow_mut().set(current_value + 1)
+            })
+            .expect("Cannot increment ID counter"),
+    };
+
+    let dose_event = DoseEvent {
+        id,
+        medication_reminder_id: payload.medication_reminder_id,
+        user_id: reminder.user_id,
+        scheduled_for: payload.scheduled_for,
+        status: payload.status,
+        recorded_by: Some(payload.recorded_by),
+        recorded_at: time(),
+    };
+
+    insert_dose_event(&dose_event);
+
+    // A dose that was taken, even late, comes out of the on-hand supply
+    if matches!(dose_event.status, DoseStatus::Taken | DoseStatus::Late) {
+        consume_medication_inventory(&reminder);
+    }
+
+    Ok(dose_event)
+}
+
+// Helper to check whether a schedule has a dose due at the given time; as-needed
+// doses can be taken at any time while the schedule runs
+fn is_scheduled_dose_time(schedule: &MedicationSchedule, utc_offset_minutes: i32, at: u64) -> bool {
+    match schedule.frequency {
+        ScheduleFrequency::AsNeeded => {
+            at >= schedule.start_date && schedule_is_active(schedule, at)
+        }
+        _ => dose_times_between(schedule, utc_offset_minutes, at, at.saturating_add(1)) == [at],
+    }
+}
+
+// Helper to find the dose event recorded for a scheduled dose
+fn find_dose_event(medication_reminder_id: u64, scheduled_for: u64) -> Option<DoseEvent> {
+    let id = DOSE_EVENT_INDEX
+        .with(|index| index.borrow().get(&(medication_reminder_id, scheduled_for)))?;
+    DOSE_EVENTS_STORAGE.with(|storage| storage.borrow().get(&id))
+}
+
+// Helper to store a dose event and index it by reminder and scheduled time
+fn insert_dose_event(event: &DoseEvent) {
+    DOSE_EVENTS_STORAGE.with(|storage| storage.borrow_mut().insert(event.id, event.clone()));
+    DOSE_EVENT_INDEX.with(|index| {
+        index.borrow_mut().insert(
+            (event.medication_reminder_id, event.scheduled_for),
+            event.id,
+        )
+    });
+}
+
+// Helper to index dose events recorded before the index existed
+fn index_dose_events() {
+    let indexed = DOSE_EVENT_INDEX.with(|index| index.borrow().len());
+    let recorded = DOSE_EVENTS_STORAGE.with(|storage| storage.borrow().len());
+    if indexed == recorded {
+        return;
+    }
+    DOSE_EVENTS_STORAGE.with(|storage| {
+        DOSE_EVENT_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for (id, event) in storage.borrow().iter() {
+                index.insert((event.medication_reminder_id, event.scheduled_for), id);
+            }
+        })
+    });
+}
+
+// Function to retrieve dose events by medication reminder ID
+#[ic_cdk::query]
+fn get_dose_events_by_medication_reminder_id(
+    medication_reminder_id: u64,
+) -> Result<Vec<DoseEvent>, String> {
+    DOSE_EVENTS_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<DoseEvent> = stable_btree_map
+            .iter()
+            .filter(|(_, record)| record.medication_reminder_id == medication_reminder_id)
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No dose events found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to compute adherence for one medication over [from, to)
+#[ic_cdk::query]
+fn get_medication_adherence(
+    medication_reminder_id: u64,
+    from: u64,
+    to: u64,
+) -> Result<MedicationAdherence, String> {
+    validate_adherence_window(from, to)?;
+
+    let reminder = MEDICATION_REMINDERS_STORAGE
+        .with(|storage| storage.borrow().get(&medication_reminder_id))
+        .ok_or("Medication reminder ID does not exist.".to_string())?;
+    let user = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&reminder.user_id))
+        .ok_or("User ID does not exist.".to_string())?;
+
+    Ok(medication_adherence(
+        &reminder,
+        user.utc_offset_minutes.unwrap_or(0),
+        from,
+        to,
+    ))
+}
+
+// Function to compute adherence across all of a user's medications over [from, to)
+#[ic_cdk::query]
+fn get_user_adherence_report(
+    user_id: u64,
+    from: u64,
+    to: u64,
+) -> Result<UserAdherenceReport, String> {
+    validate_adherence_window(from, to)?;
+
+    let user = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .ok_or("User ID does not exist.".to_string())?;
+
+    let medications: Vec<MedicationAdherence> = MEDICATION_REMINDERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id)
+            .map(|(_, record)| {
+                medication_adherence(&record, user.utc_offset_minutes.unwrap_or(0), from, to)
+            })
+            .collect()
+    });
+    if medications.is_empty() {
+        return Err("No medication reminders found.".to_string());
+    }
+
+    let expected_doses = medications.iter().map(|m| m.expected_doses).sum();
+    let taken = medications.iter().map(|m| m.taken).sum();
+    let late = medications.iter().map(|m| m.late).sum();
+    let skipped = medications.iter().map(|m| m.skipped).sum();
+    let missed = medications.iter().map(|m| m.missed).sum();
+
+    Ok(UserAdherenceReport {
+        user_id,
+        from,
+        to,
+        medications,
+        expected_doses,
+        taken,
+        late,
+        skipped,
+        missed,
+        adherence_percent: percent(taken + late, expected_doses),
+    })
+}
+
+// Helper to check an adherence window, which is capped so a report walks a
+// bounded number of scheduled doses
+fn validate_adherence_window(from: u64, to: u64) -> Result<(), String> {
+    if from >= to {
+        return Err("Start of the window must be before its end.".to_string());
+    }
+    if to - from > MAX_ADHERENCE_WINDOW_DAYS * NANOS_PER_DAY {
+        return Err(format!(
+            "Adherence windows can span at most {} days.",
+            MAX_ADHERENCE_WINDOW_DAYS
+        ));
+    }
+    Ok(())
+}
+
+// Helper to tally a medication's dose events against its schedule over [from, to)
+fn medication_adherence(
+    reminder: &MedicationReminder,
+    utc_offset_minutes: i32,
+    from: u64,
+    to: u64,
+) -> MedicationAdherence {
+    let events: Vec<DoseEvent> = DOSE_EVENTS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, event)| {
+                event.medication_reminder_id == reminder.id
+                    && event.scheduled_for >= from
+                    && event.scheduled_for < to
+            })
+            .map(|(_, event)| event)
+            .collect()
+    });
+    let count = |status: DoseStatus| events.iter().filter(|e| e.status == status).count() as u32;
+    let taken = count(DoseStatus::Taken);
+    let late = count(DoseStatus::Late);
+
+    // As-needed medications have no schedule, so every recorded event counts as expected
+    let scheduled_doses =
+        dose_times_between(&reminder.schedule, utc_offset_minutes, from, to).len();
+    let expected_doses = (scheduled_doses as u32).max(events.len() as u32);
+
+    MedicationAdherence {
+        medication_reminder_id: reminder.id,
+        medication_name: reminder.medication_name.clone(),
+        expected_doses,
+        taken,
+        late,
+        skipped: count(DoseStatus::Skipped),
+        missed: count(DoseStatus::Missed),
+        adherence_percent: percent(taken + late, expected_doses),
+    }
+}
+
+// Helper to compute a whole-number percentage, treating an empty total as 100%
+fn percent(part: u32, total: u32) -> u32 {
+    (part as u64 * 100)
+        .checked_div(total as u64)
+        .map_or(100, |percent| u32::try_from(percent).unwrap_or(u32::MAX))
+}
+
+// Function to retrieve notifications by recipient ID
+#[ic_cdk::query]
+fn get_notifications_by_recipient_id(recipient_id: u64) -> Result<Vec<Notification>, String> {
+    NOTIFICATIONS_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<Notification> = stable_btree_map
+            .iter()
+            .filter(|(_, record)| record.recipient_id == recipient_id)
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No notifications found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to mark a notification as read
+#[ic_cdk::update]
+fn mark_notification_read(notification_id: u64) -> Result<Notification, String> {
+    NOTIFICATIONS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let mut notification = storage
+            .get(&notification_id)
+            .ok_or("Notification ID does not exist.".to_string())?;
+        if notification.read_at.is_none() {
+            notification.read_at = Some(time());
+            storage.insert(notification_id, notification.clone());
+        }
+        Ok(notification)
+    })
+}
+
+// Helper to send the same notification to a user and each of their linked caregivers
+fn notify_user_and_caregivers(
+    user_id: u64,
+    notification_type: NotificationType,
+    reference_id: Option<u64>,
+    message: String,
+) {
+    let mut recipients = vec![user_id];
+    recipients.extend(linked_caregiver_ids(user_id));
+
+    for recipient_id in recipients {
+        let id = ID_COUNTER
+            .with(|counter| {
+                let current_value = *counter.borrow().get();
+                counter.borrow_mut().set(current_value + 1)
+            })
+            .expect("Cannot increment ID counter");
+
+        let notification = Notification {
+            id,
+            recipient_id,
+            user_id,
+            notification_type,
+            reference_id,
+            message: message.clone(),
+            created_at: time(),
+            read_at: None,
+        };
+
+        NOTIFICATIONS_STORAGE.with(|storage| storage.borrow_mut().insert(id, notification));
+    }
+}
+
+// How often the reminder timer walks the medication schedules
+const REMINDER_DISPATCH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// How long after a dose is due before it is automatically marked missed
+const DOSE_GRACE_PERIOD_NANOS: u64 = 60 * NANOS_PER_MINUTE;
+
+// How often hydration goals are checked against the expected pace
+const HYDRATION_CHECK_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// Largest single fluid intake that can be logged
+const MAX_FLUID_INTAKE_ML: u32 = 2000;
+// Largest daily fluid goal that can be set
+const MAX_DAILY_FLUID_GOAL_ML: u32 = 10_000;
+// Plausible ranges for body measurements
+const MIN_HEIGHT_CM: f64 = 50.0;
+const MAX_HEIGHT_CM: f64 = 250.0;
+const MIN_WEIGHT_KG: f64 = 20.0;
+const MAX_WEIGHT_KG: f64 = 350.0;
+// A gain of more than this many kg within the window is an early warning sign of heart failure
+const WEIGHT_GAIN_ALERT_KG: f64 = 2.0;
+const WEIGHT_GAIN_WINDOW_DAYS: u64 = 3;
+
+#[ic_cdk::init]
+fn init() {
+    start_timers();
+}
+
+// Records stored in an older layout are migrated before the timers are
+// re-armed, as timers do not survive an upgrade
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    migrate_legacy_medication_reminders();
+    migrate_legacy_diet_records();
+    migrate_legacy_virtual_consultations();
+    index_dose_events();
+    start_timers();
+}
+
+// Helper to move reminders stored in the original free-text layout into the
+// structured reminder store
+fn migrate_legacy_medication_reminders() {
+    let records: Vec<LegacyMedicationReminder> = LEGACY_MEDICATION_REMINDERS_STORAGE
+        .with(|storage| storage.borrow().iter().map(|(_, record)| record).collect());
+    for record in records {
+        let id = record.id;
+        let reminder = convert_legacy_medication_reminder(record);
+        MEDICATION_REMINDERS_STORAGE.with(|storage| storage.borrow_mut().insert(id, reminder));
+        LEGACY_MEDICATION_REMINDERS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    }
+}
+
+// Helper to move diet records stored in the original free-text layout into the
+// catalogue-based diet record store
+fn migrate_legacy_diet_records() {
+    let records: Vec<LegacyDietRecord> = LEGACY_DIET_RECORDS_STORAGE
+        .with(|storage| storage.borrow().iter().map(|(_, record)| record).collect());
+    for record in records {
+        let id = record.id;
+        let diet_record = convert_legacy_diet_record(record);
+        DIET_RECORDS_STORAGE.with(|storage| storage.borrow_mut().insert(id, diet_record));
+        LEGACY_DIET_RECORDS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    }
+}
+
+// Helper to convert a free-text diet record into a single item holding the
+// original text and calories. Its food item ID of 0 never names a catalogue
+// item, as ID 0 went to the first record created before the catalogue existed.
+fn convert_legacy_diet_record(record: LegacyDietRecord) -> DietRecord {
+    let nutrients = Nutrients {
+        calories: record.calories as f64,
+        ..Default::default()
+    };
+    DietRecord {
+        id: record.id,
+        user_id: record.user_id,
+        meal_type: record.meal_type,
+        items: vec![DietRecordItem {
+            food_item_id: 0,
+            food_name: record.food_items,
+            servings: 1.0,
+            nutrients: nutrients.clone(),
+        }],
+        nutrients,
+        allergy_warnings: Vec::new(),
+        dietary_conflicts: None,
+        recorded_at: record.recorded_at,
+    }
+}
+
+// Helper to move consultations stored in the original layout into the
+// slot-based consultation store
+fn migrate_legacy_virtual_consultations() {
+    let records: Vec<LegacyVirtualConsultation> = LEGACY_VIRTUAL_CONSULTATIONS_STORAGE
+        .with(|storage| storage.borrow().iter().map(|(_, record)| record).collect());
+    for record in records {
+        let id = record.id;
+        let consultation = convert_legacy_virtual_consultation(record);
+        VIRTUAL_CONSULTATIONS_STORAGE.with(|storage| storage.borrow_mut().insert(id, consultation));
+        LEGACY_VIRTUAL_CONSULTATIONS_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    }
+}
+
+// Helper to convert a consultation with a free-text status into the slot-based
+// layout. Old consultations had no length or slot: they are given
+// LEGACY_CONSULTATION_MINUTES and slot ID 0, which no slot has, as ID 0 went
+// to the first record created before slots existed.
+fn convert_legacy_virtual_consultation(record: LegacyVirtualConsultation) -> VirtualConsultation {
+    let status = parse_legacy_consultation_status(&record.status);
+    VirtualConsultation {
+        id: record.id,
+        user_id: record.user_id,
+        provider_id: record.provider_id,
+        booked_by: record.user_id,
+        slot_id: 0,
+        series_id: None,
+        scheduled_at: record.scheduled_at,
+        ends_at: record
+            .scheduled_at
+            .saturating_add(LEGACY_CONSULTATION_MINUTES * NANOS_PER_MINUTE),
+        status,
+        status_history: vec![ConsultationStatusChange {
+            status,
+            reason: format!("Migrated with status \"{}\"", record.status),
+            changed_by: record.user_id,
+            changed_at: record.created_at,
+        }],
+        created_at: record.created_at,
+        updated_at: record.created_at,
+    }
+}
+
+// Helper to read a free-text consultation status; anything unrecognised is
+// treated as a request awaiting the provider's confirmation
+fn parse_legacy_consultation_status(status: &str) -> ConsultationStatus {
+    let status: String = status
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+    match status.as_str() {
+        "confirmed" | "scheduled" | "booked" => ConsultationStatus::Confirmed,
+        "inprogress" | "started" | "ongoing" => ConsultationStatus::InProgress,
+        "completed" | "complete" | "done" | "finished" => ConsultationStatus::Completed,
+        "cancelled" | "canceled" => ConsultationStatus::Cancelled,
+        "noshow" | "missed" => ConsultationStatus::NoShow,
+        _ => ConsultationStatus::Requested,
+    }
+}
+
+// Helper to convert a free-text reminder into the structured layout. A dosage
+// that cannot be parsed becomes one unit, and a schedule that cannot be parsed
+// becomes as-needed, so no reminders fire at guessed times; either way the
+// original text is kept on the reminder.
+fn convert_legacy_medication_reminder(record: LegacyMedicationReminder) -> MedicationReminder {
+    let dosage = parse_dosage(&record.dosage).filter(|dosage| validate_dosage(dosage).is_ok());
+    let schedule = parse_legacy_schedule(&record.schedule, record.created_at);
+    let medication_name = truncate_to_boundary(&record.medication_name, MAX_MEDICATION_NAME_LENGTH);
+    let legacy_entry = if medication_name.len() < record.medication_name.len() {
+        Some(format!(
+            "Medication: {}; dosage: {}; schedule: {}",
+            record.medication_name, record.dosage, record.schedule
+        ))
+    } else {
+        (dosage.is_none() || schedule.is_none())
+            .then(|| format!("Dosage: {}; schedule: {}", record.dosage, record.schedule))
+    };
+    MedicationReminder {
+        id: record.id,
+        user_id: record.user_id,
+        medication_name,
+        dosage: dosage.unwrap_or(Dosage {
+            quantity: 1.0,
+            unit: DoseUnit::Count,
+            form: DosageForm::Other,
+        }),
+        schedule: schedule.unwrap_or(MedicationSchedule {
+            frequency: ScheduleFrequency::AsNeeded,
+            start_date: record.created_at,
+            end_date: None,
+        }),
+        interaction_warnings: Vec::new(),
+        allergy_warnings: Vec::new(),
+        prescription_id: None,
+        legacy_entry,
+        created_at: record.created_at,
+    }
+}
+
+// Helper to cut text to at most `max_length` bytes without splitting a character
+fn truncate_to_boundary(text: &str, max_length: usize) -> String {
+    let mut end = text.len().min(max_length);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text[..end].to_string()
+}
+
+// Helper to read a free-text schedule such as "08:00 and 20:00", "every 8 hours"
+// or "as needed", starting on the given date
+fn parse_legacy_schedule(text: &str, start_date: u64) -> Option<MedicationSchedule> {
+    let text = text.to_lowercase();
+    let words: Vec<&str> = text
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != ':')
+        .filter(|word| !word.is_empty())
+        .collect();
+
+    let interval_hours = words.windows(3).find_map(|window| match window {
+        ["every", count, unit] if unit.starts_with("hour") => count.parse::<u32>().ok(),
+        _ => None,
+    });
+    let mut times: Vec<TimeOfDay> = words
+        .iter()
+        .filter_map(|word| {
+            let (hour, minute) = word.split_once(':')?;
+            let time_of_day = TimeOfDay {
+                hour: hour.parse().ok()?,
+                minute: minute.parse().ok()?,
+            };
+            (minute.len() == 2 && time_of_day.hour < 24 && time_of_day.minute < 60)
+                .then_some(time_of_day)
+        })
+        .collect();
+    times.sort_by_key(|time_of_day| (time_of_day.hour, time_of_day.minute));
+    times.dedup();
+
+    let frequency = if text.contains("as needed") || words.contains(&"prn") {
+        ScheduleFrequency::AsNeeded
+    } else if let Some(interval_hours) = interval_hours {
+        if !(1..=168).contains(&interval_hours) {
+            return None;
+        }
+        ScheduleFrequency::EveryNHours { interval_hours }
+    } else if !times.is_empty() {
+        ScheduleFrequency::FixedTimes { times }
+    } else {
+        return None;
+    };
+    Some(MedicationSchedule {
+        frequency,
+        start_date,
+        end_date: None,
+    })
+}
+
+// Helper to arm the recurring timer jobs
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(REMINDER_DISPATCH_INTERVAL, dispatch_due_reminders);
+    ic_cdk_timers::set_timer_interval(HYDRATION_CHECK_INTERVAL, check_hydration_pace);
+}
+
+// Timer job: notify about doses that fell due since the last run and mark
+// doses missed once their grace period has passed without a dose event
+fn dispatch_due_reminders() {
+    let now = time();
+    let last_dispatch = REMINDER_DISPATCH_CURSOR.with(|cursor| *cursor.borrow().get());
+    REMINDER_DISPATCH_CURSOR
+        .with(|cursor| cursor.borrow_mut().set(now))
+        .expect("Cannot update the reminder dispatch cursor");
+
+    // The first run only starts the cursor; nothing is backfilled
+    if last_dispatch == 0 {
+        return;
+    }
+
+    let reminders: Vec<MedicationReminder> = MEDICATION_REMINDERS_STORAGE
+        .with(|storage| storage.borrow().iter().map(|(_, record)| record).collect());
+
+    for reminder in reminders {
+        let Some(user) = USERS_STORAGE.with(|storage| storage.borrow().get(&reminder.user_id))
+        else {
+            continue;
+        };
+        if !reminder_is_active(&reminder, last_dispatch) {
+            continue;
+        }
+
+        for due_at in dose_times_between(
+            &reminder.schedule,
+            user.utc_offset_minutes.unwrap_or(0),
+            last_dispatch,
+            now,
+        ) {
+            notify_user_and_caregivers(
+                user.id,
+                NotificationType::DoseDue,
+                Some(reminder.id),
+                dose_due_message(&reminder, due_at),
+            );
+        }
+
+        for due_at in dose_times_between(
+            &reminder.schedule,
+            user.utc_offset_minutes.unwrap_or(0),
+            last_dispatch.saturating_sub(DOSE_GRACE_PERIOD_NANOS),
+            now.saturating_sub(DOSE_GRACE_PERIOD_NANOS),
+        ) {
+            if find_dose_event(reminder.id, due_at).is_some() {
+                continue;
+            }
+
+            let id = ID_COUNTER
+                .with(|counter| {
+                    let current_value = *counter.borrow().get();
+                    counter.borrow_mut().set(current_value + 1)
+                })
+                .expect("Cannot increment ID counter");
+
+            let dose_event = DoseEvent {
+                id,
+                medication_reminder_id: reminder.id,
+                user_id: user.id,
+                scheduled_for: due_at,
+                status: DoseStatus::Missed,
+                recorded_by: None,
+                recorded_at: now,
+            };
+            insert_dose_event(&dose_event);
+
+            notify_user_and_caregivers(
+                user.id,
+                NotificationType::DoseMissed,
+                Some(reminder.id),
+                dose_missed_message(&reminder, due_at),
+            );
+        }
+    }
+}
+
+// Helper to write the notification for a dose that has fallen due
+fn dose_due_message(reminder: &MedicationReminder, due_at: u64) -> String {
+    format!(
+        "{} ({}) is due at {}.",
+        reminder.medication_name,
+        format_dosage(&reminder.dosage),
+        format_timestamp(due_at)
+    )
+}
+
+// Helper to write the notification for a dose marked missed
+fn dose_missed_message(reminder: &MedicationReminder, due_at: u64) -> String {
+    format!(
+        "{} ({}) due at {} was not confirmed and has been marked missed.",
+        reminder.medication_name,
+        format_dosage(&reminder.dosage),
+        format_timestamp(due_at)
+    )
+}
+
+// Function for a healthcare provider to create or update their profile
+#[ic_cdk::update]
+fn set_provider_profile(payload: ProviderProfilePayload) -> Result<ProviderProfile, String> {
+    let provider = check_caller_is_user(payload.provider_id)?;
+    if provider.user_type != UserType::HealthcareProvider {
+        return Err("Only healthcare providers can have a provider profile.".to_string());
+    }
+
+    let licence_number = payload.licence_number.trim().to_string();
+    if licence_number.is_empty() || payload.specialties.is_empty() {
+        return Err("A licence number and at least one specialty must be provided.".to_string());
+    }
+    if payload.currency.is_empty() {
+        return Err("A currency must be provided for the consultation fee.".to_string());
+    }
+    if licence_number.len() > MAX_LICENCE_NUMBER_LENGTH
+        || payload.currency.len() > MAX_CURRENCY_LENGTH
+    {
+        return Err(format!(
+            "Licence numbers can be at most {} bytes and currencies {} bytes long.",
+            MAX_LICENCE_NUMBER_LENGTH, MAX_CURRENCY_LENGTH
+        ));
+    }
+    if payload.bio.len() > MAX_PROVIDER_BIO_LENGTH {
+        return Err(format!(
+            "The bio can be at most {} bytes long.",
+            MAX_PROVIDER_BIO_LENGTH
+        ));
+    }
+
+    // A licence number identifies a single provider
+    let licence_taken = PROVIDER_PROFILES_STORAGE.with(|storage| {
+        storage.borrow().iter().any(|(_, profile)| {
+            profile.provider_id != payload.provider_id
+                && profile.licence_number.eq_ignore_ascii_case(&licence_number)
+        })
+    });
+    if licence_taken {
+        return Err("This licence number belongs to another provider.".to_string());
+    }
+
+    let mut specialties: Vec<ProviderSpecialty> = Vec::new();
+    for specialty in payload.specialties {
+        if !specialties.contains(&specialty) {
+            specialties.push(specialty);
+        }
+    }
+    let languages: Vec<String> = payload
+        .languages
+        .iter()
+        .map(|language| language.trim().to_string())
+        .filter(|language| !language.is_empty())
+        .collect();
+    if languages.len() > MAX_PROVIDER_LANGUAGES
+        || languages
+            .iter()
+            .any(|language| language.len() > MAX_LANGUAGE_LENGTH)
+    {
+        return Err(format!(
+            "At most {} languages of up to {} bytes each can be listed.",
+            MAX_PROVIDER_LANGUAGES, MAX_LANGUAGE_LENGTH
+        ));
+    }
+
+    let now = time();
+    let created_at = PROVIDER_PROFILES_STORAGE
+        .with(|storage| storage.borrow().get(&payload.provider_id))
+        .map_or(now, |existing| existing.created_at);
+
+    let profile = ProviderProfile {
+        provider_id: payload.provider_id,
+        specialties,
+        languages,
+        licence_number,
+        bio: payload.bio,
+        consultation_fee: payload.consultation_fee,
+        currency: payload.currency,
+        rating_count: 0,
+        average_rating: None,
+        created_at,
+        updated_at: now,
+    };
+    let profile = with_provider_rating(profile);
+
+    PROVIDER_PROFILES_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(payload.provider_id, profile.clone())
+    });
+    Ok(profile)
+}
+
+// Function to retrieve a provider's profile
+#[ic_cdk::query]
+fn get_provider_profile(provider_id: u64) -> Result<ProviderProfile, String> {
+    PROVIDER_PROFILES_STORAGE
+        .with(|storage| storage.borrow().get(&provider_id))
+        .ok_or("No profile found for the provider.".to_string())
+}
+
+// Function for the patient or a linked caregiver to rate a completed consultation
+#[ic_cdk::update]
+fn submit_consultation_feedback(
+    consultation_id: u64,
+    payload: ConsultationFeedbackPayload,
+) -> Result<ConsultationFeedback, String> {
+    let consultation = VIRTUAL_CONSULTATIONS_STORAGE
+        .with(|storage| storage.borrow().get(&consultation_id))
+        .ok_or("Consultation ID does not exist.".to_string())?;
+    if consultation.status != ConsultationStatus::Completed {
+        return Err("Feedback can only be left for completed consultations.".to_string());
+    }
+    if payload.submitted_by != consultation.user_id
+        && !is_linked_caregiver(consultation.user_id, payload.submitted_by)
+    {
+        return Err("Only the patient or a linked caregiver can leave feedback.".to_string());
+    }
+    check_caller_is_user(payload.submitted_by)?;
+    if !(1..=5).contains(&payload.rating) {
+        return Err("Rating must be between 1 and 5.".to_string());
+    }
+    if payload.comment.len() > MAX_FEEDBACK_COMMENT_LENGTH {
+        return Err(format!(
+            "Comments can be at most {} bytes long.",
+            MAX_FEEDBACK_COMMENT_LENGTH
+        ));
+    }
+
+    let already_rated = CONSULTATION_FEEDBACK_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .any(|(_, feedback)| feedback.consultation_id == consultation_id)
+    });
+    if already_rated {
+        return Err("Feedback has already been left for this consultation.".to_string());
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let feedback = ConsultationFeedback {
+        id,
+        consultation_id,
+        provider_id: consultation.provider_id,
+        user_id: consultation.user_id,
+        submitted_by: payload.submitted_by,
+        rating: payload.rating,
+        comment: payload.comment,
+        hidden_at: None,
+        moderation_reason: String::new(),
+        created_at: time(),
+    };
+
+    CONSULTATION_FEEDBACK_STORAGE.with(|storage| storage.borrow_mut().insert(id, feedback.clone()));
+
+    // Keep the provider's aggregated rating up to date
+    PROVIDER_PROFILES_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if let Some(profile) = storage.get(&consultation.provider_id) {
+            storage.insert(consultation.provider_id, with_provider_rating(profile));
+        }
+    });
+    Ok(feedback)
+}
+
+// Function to retrieve the feedback left for a provider; comments hidden by
+// moderation are left out
+#[ic_cdk::query]
+fn get_feedback_by_provider_id(provider_id: u64) -> Result<Vec<ConsultationFeedback>, String> {
+    CONSULTATION_FEEDBACK_STORAGE.with(|storage| {
+        let records: Vec<ConsultationFeedback> = storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| record.provider_id == provider_id)
+            .map(|(_, mut record)| {
+                if record.hidden_at.is_some() {
+                    record.comment = String::new();
+                }
+                record
+            })
+            .collect();
+        if records.is_empty() {
+            Err("No feedback found for the provider.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function for an admin to hide or restore the comment of a piece of feedback
+#[ic_cdk::update]
+fn moderate_consultation_feedback(
+    feedback_id: u64,
+    payload: FeedbackModerationPayload,
+) -> Result<ConsultationFeedback, String> {
+    if !is_admin() {
+        return Err("Only an admin can moderate feedback.".to_string());
+    }
+    if payload.hidden && payload.reason.is_empty() {
+        return Err("A reason is required to hide a comment.".to_string());
+    }
+    validate_status_reason(&payload.reason)?;
+
+    CONSULTATION_FEEDBACK_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let mut feedback = storage
+            .get(&feedback_id)
+            .ok_or("Feedback ID does not exist.".to_string())?;
+        feedback.hidden_at = if payload.hidden { Some(time()) } else { None };
+        feedback.moderation_reason = payload.reason;
+        storage.insert(feedback_id, feedback.clone());
+        Ok(feedback)
+    })
+}
+
+// Helper to fill in a provider profile's aggregated rating from all feedback
+// left for the provider; hiding a comment does not remove its rating
+fn with_provider_rating(mut profile: ProviderProfile) -> ProviderProfile {
+    let ratings: Vec<u32> = CONSULTATION_FEEDBACK_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, feedback)| feedback.provider_id == profile.provider_id)
+            .map(|(_, feedback)| feedback.rating as u32)
+            .collect()
+    });
+    profile.rating_count = ratings.len() as u32;
+    profile.average_rating = if ratings.is_empty() {
+        None
+    } else {
+        Some(ratings.iter().sum::<u32>() as f64 / ratings.len() as f64)
+    };
+    profile
+}
+
+// Function to search provider profiles by specialty and spoken language
+#[ic_cdk::query]
+fn search_providers(query: ProviderSearchQuery) -> Result<Vec<ProviderSearchResult>, String> {
+    let language = query
+        .language
+        .map(|language| language.trim().to_lowercase())
+        .filter(|language| !language.is_empty());
+
+    let mut results: Vec<ProviderSearchResult> = PROVIDER_PROFILES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, profile)| match query.specialty {
+                Some(specialty) => profile.specialties.contains(&specialty),
+                None => true,
+            })
+            .filter(|(_, profile)| match &language {
+                Some(language) => profile
+                    .languages
+                    .iter()
+                    .any(|spoken| spoken.to_lowercase() == *language),
+                None => true,
+            })
+            .filter_map(|(_, profile)| {
+                let provider =
+                    USERS_STORAGE.with(|users| users.borrow().get(&profile.provider_id))?;
+                Some(ProviderSearchResult {
+                    name: provider.name,
+                    contact: provider.contact,
+                    profile,
+                })
+            })
+            .collect()
+    });
+
+    if results.is_empty() {
+        return Err("No providers match the search.".to_string());
+    }
+    results.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(results)
+}
+
+// Function to create a new virtual consultation
+#[ic_cdk::update]
+fn create_virtual_consultation(
+    payload: VirtualConsultationPayload,
+) -> Result<VirtualConsultation, String> {
+    // Ensure user ID exists and the patient is an elderly user
+    let user = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&payload.user_id))
+        .ok_or("User ID does not exist.".to_string())?;
+    if user.user_type != UserType::Elderly {
+        return Err("Consultations can only be booked for elderly users.".to_string());
+    }
+
+    // Patients book for themselves; a caregiver may book for a linked elderly user
+    if payload.booked_by != payload.user_id {
+        let booker = USERS_STORAGE
+            .with(|storage| storage.borrow().get(&payload.booked_by))
+            .ok_or("Booking user ID does not exist.".to_string())?;
+        if booker.user_type != UserType::Caregiver
+            || !is_linked_caregiver(payload.user_id, payload.booked_by)
+        {
+            return Err(
+                "Only the patient or a linked caregiver can book a consultation.".to_string(),
+            );
+        }
+    }
+    check_caller_is_user(payload.booked_by)?;
+
+    let slot = AVAILABILITY_SLOTS_STORAGE
+        .with(|storage| storage.borrow().get(&payload.slot_id))
+        .ok_or("Availability slot ID does not exist.".to_string())?;
+
+    // Ensure the provider is a healthcare provider other than the patient
+    let provider = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&slot.provider_id))
+        .ok_or("Provider ID does not exist.".to_string())?;
+    if provider.user_type != UserType::HealthcareProvider {
+        return Err("The provider must be a healthcare provider.".to_string());
+    }
+    if provider.id == payload.user_id {
+        return Err("A provider cannot consult with themselves.".to_string());
+    }
+    if slot.consultation_id.is_some() {
+        return Err("This availability slot is already booked.".to_string());
+    }
+
+    check_consultation_period(
+        slot.provider_id,
+        payload.user_id,
+        slot.start_at,
+        slot.end_at,
+        &[],
+    )?;
+
+    Ok(book_consultation(
+        payload.user_id,
+        payload.booked_by,
+        slot,
+        None,
+    ))
+}
+
+// Helper to book a consultation into a slot; every consultation starts out as
+// a request awaiting the provider's confirmation
+fn book_consultation(
+    user_id: u64,
+    booked_by: u64,
+    mut slot: AvailabilitySlot,
+    series_id: Option<u64>,
+) -> VirtualConsultation {
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let now = time();
+    let virtual_consultation = VirtualConsultation {
+        id,
+        user_id,
+        provider_id: slot.provider_id,
+        booked_by,
+        slot_id: slot.id,
+        series_id,
+        scheduled_at: slot.start_at,
+        ends_at: slot.end_at,
+        status: ConsultationStatus::Requested,
+        status_history: vec![ConsultationStatusChange {
+            status: ConsultationStatus::Requested,
+            reason: String::new(),
+            changed_by: booked_by,
+            changed_at: now,
+        }],
+        created_at: now,
+        updated_at: now,
+    };
+
+    VIRTUAL_CONSULTATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(id, virtual_consultation.clone())
+    });
+
+    slot.consultation_id = Some(id);
+    AVAILABILITY_SLOTS_STORAGE.with(|storage| storage.borrow_mut().insert(slot.id, slot));
+    virtual_consultation
+}
+
+// Helper to check that a patient and provider are both free between start_at
+// and end_at. Returns the provider's free slot for exactly that period, if one
+// is published; consultations listed in `moving` are being rescheduled and
+// neither they nor their slots count as conflicts.
+fn check_consultation_period(
+    provider_id: u64,
+    user_id: u64,
+    start_at: u64,
+    end_at: u64,
+    moving: &[u64],
+) -> Result<Option<AvailabilitySlot>, String> {
+    if start_at <= time() {
+        return Err("Consultations can only be booked in the future.".to_string());
+    }
+
+    // Neither the provider nor the patient may be double booked
+    if let Some(conflict) =
+        find_overlapping_consultation(&[provider_id, user_id], start_at, end_at, moving)
+    {
+        return Err(format!(
+            "This booking overlaps consultation {} at {}.",
+            conflict.id,
+            format_timestamp(conflict.scheduled_at)
+        ));
+    }
+
+    let mut matching_slot = None;
+    let overlapping_slots: Vec<AvailabilitySlot> = AVAILABILITY_SLOTS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, slot)| {
+                slot.provider_id == provider_id && slot.start_at < end_at && start_at < slot.end_at
+            })
+            .map(|(_, slot)| slot)
+            .collect()
+    });
+    for slot in overlapping_slots {
+        let is_free = match slot.consultation_id {
+            Some(consultation_id) => moving.contains(&consultation_id),
+            None => true,
+        };
+        if is_free && slot.start_at == start_at && slot.end_at == end_at {
+            matching_slot = Some(slot);
+        } else {
+            return Err(format!(
+                "This booking overlaps the provider's availability slot {} at {}.",
+                slot.id,
+                format_timestamp(slot.start_at)
+            ));
+        }
+    }
+    Ok(matching_slot)
+}
+
+// Function to retrieve all virtual consultations
+#[ic_cdk::query]
+fn get_all_virtual_consultations() -> Result<Vec<VirtualConsultation>, String> {
+    if !is_admin() {
+        return Err("Only an admin can list every user's consultations.".to_string());
+    }
+    VIRTUAL_CONSULTATIONS_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<VirtualConsultation> = stable_btree_map
+            .iter()
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No virtual consultations found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function for the provider to confirm a requested consultation
+#[ic_cdk::update]
+fn confirm_consultation(
+    consultation_id: u64,
+    payload: ConsultationTransitionPayload,
+) -> Result<VirtualConsultation, String> {
+    transition_consultation(consultation_id, payload, ConsultationStatus::Confirmed)
+}
+
+// Function for the provider to start a confirmed consultation
+#[ic_cdk::update]
+fn start_consultation(
+    consultation_id: u64,
+    payload: ConsultationTransitionPayload,
+) -> Result<VirtualConsultation, String> {
+    transition_consultation(consultation_id, payload, ConsultationStatus::InProgress)
+}
+
+// Function for the provider to complete a consultation in progress
+#[ic_cdk::update]
+fn complete_consultation(
+    consultation_id: u64,
+    payload: ConsultationTransitionPayload,
+) -> Result<VirtualConsultation, String> {
+    transition_consultation(consultation_id, payload, ConsultationStatus::Completed)
+}
+
+// Function for the patient or the provider to cancel a consultation that has not started
+#[ic_cdk::update]
+fn cancel_consultation(
+    consultation_id: u64,
+    payload: ConsultationTransitionPayload,
+) -> Result<VirtualConsultation, String> {
+    transition_consultation(consultation_id, payload, ConsultationStatus::Cancelled)
+}
+
+// Function for the provider to record that the patient did not attend
+#[ic_cdk::update]
+fn mark_consultation_no_show(
+    consultation_id: u64,
+    payload: ConsultationTransitionPayload,
+) -> Result<VirtualConsultation, String> {
+    transition_consultation(consultation_id, payload, ConsultationStatus::NoShow)
+}
+
+// Helper to check whether a consultation may move from one status to another
+fn is_valid_consultation_transition(from: ConsultationStatus, to: ConsultationStatus) -> bool {
+    use ConsultationStatus::*;
+    matches!(
+        (from, to),
+        (Requested, Confirmed)
+            | (Requested, Cancelled)
+            | (Confirmed, InProgress)
+            | (Confirmed, Cancelled)
+            | (Confirmed, NoShow)
+            | (InProgress, Completed)
+    )
+}
+
+// Helper to apply a status transition to a consultation, recording who made it and when
+fn transition_consultation(
+    consultation_id: u64,
+    payload: ConsultationTransitionPayload,
+    status: ConsultationStatus,
+) -> Result<VirtualConsultation, String> {
+    let mut consultation = VIRTUAL_CONSULTATIONS_STORAGE
+        .with(|storage| storage.borrow().get(&consultation_id))
+        .ok_or("Consultation ID does not exist.".to_string())?;
+
+    if !is_valid_consultation_transition(consultation.status, status) {
+        return Err(format!(
+            "A {:?} consultation cannot be marked {:?}.",
+            consultation.status, status
+        ));
+    }
+
+    check_caller_is_user(payload.actor_id)?;
+
+    // Only cancelling is open to the patient (or a linked caregiver); every
+    // other move is the provider's
+    let is_provider = payload.actor_id == consultation.provider_id;
+    let is_patient = payload.actor_id == consultation.user_id
+        || is_linked_caregiver(consultation.user_id, payload.actor_id);
+    let allowed = match status {
+        ConsultationStatus::Cancelled => is_provider || is_patient,
+        _ => is_provider,
+    };
+    if !allowed {
+        return Err(format!(
+            "User {} is not allowed to mark this consultation {:?}.",
+            payload.actor_id, status
+        ));
+    }
+    if status == ConsultationStatus::Cancelled && payload.reason.is_empty() {
+        return Err("A reason is required to cancel a consultation.".to_string());
+    }
+    validate_status_reason(&payload.reason)?;
+
+    let now = time();
+    consultation.status = status;
+    consultation.status_history.push(ConsultationStatusChange {
+        status,
+        reason: payload.reason,
+        changed_by: payload.actor_id,
+        changed_at: now,
+    });
+    consultation.updated_at = now;
+
+    VIRTUAL_CONSULTATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(consultation_id, consultation.clone())
+    });
+
+    // A cancelled booking frees its slot for someone else
+    if status == ConsultationStatus::Cancelled {
+        release_availability_slot(consultation.slot_id);
+    }
+    Ok(consultation)
+}
+
+// Helper to mark an availability slot as free again
+fn release_availability_slot(slot_id: u64) {
+    AVAILABILITY_SLOTS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if let Some(mut slot) = storage.get(&slot_id) {
+            slot.consultation_id = None;
+            storage.insert(slot.id, slot);
+        }
+    });
+}
+
+// Function for a healthcare provider to set up a recurring consultation series,
+// booking every instance ahead of time
+#[ic_cdk::update]
+fn create_consultation_series(
+    payload: ConsultationSeriesPayload,
+) -> Result<ConsultationSeries, String> {
+    let provider = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&payload.provider_id))
+        .ok_or("Provider ID does not exist.".to_string())?;
+    if provider.user_type != UserType::HealthcareProvider {
+        return Err("Only healthcare providers can set up a consultation series.".to_string());
+    }
+
+    let user = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&payload.user_id))
+        .ok_or("User ID does not exist.".to_string())?;
+    if user.user_type != UserType::Elderly {
+        return Err("Consultations can only be booked for elderly users.".to_string());
+    }
+
+    if payload.recurrence.occurrences == 0
+        || payload.recurrence.occurrences > MAX_SERIES_OCCURRENCES
+    {
+        return Err(format!(
+            "A series must have between 1 and {} occurrences.",
+            MAX_SERIES_OCCURRENCES
+        ));
+    }
+    if payload.duration_minutes == 0 || payload.duration_minutes > MAX_CONSULTATION_MINUTES {
+        return Err(format!(
+            "Consultations must last between 1 and {} minutes.",
+            MAX_CONSULTATION_MINUTES
+        ));
+    }
+
+    // Check every instance before booking any of them
+    let duration = payload.duration_minutes as u64 * NANOS_PER_MINUTE;
+    let mut periods = Vec::new();
+    for start_at in series_occurrences(payload.start_at, &payload.recurrence)? {
+        let slot = check_consultation_period(
+            payload.provider_id,
+            payload.user_id,
+            start_at,
+            consultation_end(start_at, duration)?,
+            &[],
+        )?;
+        periods.push((start_at, slot));
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let consultation_ids = periods
+        .into_iter()
+        .map(|(start_at, slot)| {
+            let slot = slot.unwrap_or_else(|| {
+                insert_availability_slot(payload.provider_id, start_at, start_at + duration)
+            });
+            book_consultation(payload.user_id, payload.provider_id, slot, Some(id)).id
+        })
+        .collect();
+
+    let now = time();
+    let series = ConsultationSeries {
+        id,
+        user_id: payload.user_id,
+        provider_id: payload.provider_id,
+        recurrence: payload.recurrence,
+        start_at: payload.start_at,
+        duration_minutes: payload.duration_minutes,
+        consultation_ids,
+        cancelled_at: None,
+        created_at: now,
+        updated_at: now,
+    };
+
+    CONSULTATION_SERIES_STORAGE.with(|storage| storage.borrow_mut().insert(id, series.clone()));
+    Ok(series)
+}
+
+// Function for the provider to move a single upcoming consultation to a new time
+#[ic_cdk::update]
+fn reschedule_consultation(
+    consultation_id: u64,
+    payload: ConsultationReschedulePayload,
+) -> Result<VirtualConsultation, String> {
+    let consultation = VIRTUAL_CONSULTATIONS_STORAGE
+        .with(|storage| storage.borrow().get(&consultation_id))
+        .ok_or("Consultation ID does not exist.".to_string())?;
+    if payload.actor_id != consultation.provider_id {
+        return Err("Only the provider can reschedule a consultation.".to_string());
+    }
+    if !matches!(
+        consultation.status,
+        ConsultationStatus::Requested | ConsultationStatus::Confirmed
+    ) {
+        return Err(format!(
+            "A {:?} consultation cannot be rescheduled.",
+            consultation.status
+        ));
+    }
+
+    check_can_reschedule(&consultation)?;
+
+    let end_at = consultation_end(
+        payload.start_at,
+        consultation.ends_at - consultation.scheduled_at,
+    )?;
+    let slot = check_consultation_period(
+        consultation.provider_id,
+        consultation.user_id,
+        payload.start_at,
+        end_at,
+        &[consultation_id],
+    )?;
+
+    release_availability_slot(consultation.slot_id);
+    Ok(move_consultation(
+        consultation,
+        payload.start_at,
+        slot,
+        payload.actor_id,
+    ))
+}
+
+// Function for the provider to move all remaining consultations of a series,
+// the next one to the given time and the rest following the recurrence rule
+#[ic_cdk::update]
+fn reschedule_consultation_series(
+    series_id: u64,
+    payload: ConsultationReschedulePayload,
+) -> Result<ConsultationSeries, String> {
+    let mut series = CONSULTATION_SERIES_STORAGE
+        .with(|storage| storage.borrow().get(&series_id))
+        .ok_or("Consultation series ID does not exist.".to_string())?;
+    if payload.actor_id != series.provider_id {
+        return Err("Only the provider can reschedule a consultation series.".to_string());
+    }
+
+    let remaining = remaining_series_consultations(&series);
+    if remaining.is_empty() {
+        return Err("The series has no upcoming consultations to reschedule.".to_string());
+    }
+    for consultation in &remaining {
+        check_can_reschedule(consultation)?;
+    }
+
+    let recurrence = ConsultationRecurrence {
+        occurrences: remaining.len() as u32,
+        ..series.recurrence.clone()
+    };
+    let moving: Vec<u64> = remaining.iter().map(|record| record.id).collect();
+    let duration = series.duration_minutes as u64 * NANOS_PER_MINUTE;
+
+    // Check every new time before moving any of the consultations
+    let mut periods = Vec::new();
+    for start_at in series_occurrences(payload.start_at, &recurrence)? {
+        let slot = check_consultation_period(
+            series.provider_id,
+            series.user_id,
+            start_at,
+            consultation_end(start_at, duration)?,
+            &moving,
+        )?;
+        periods.push((start_at, slot));
+    }
+
+    for consultation in &remaining {
+        release_availability_slot(consultation.slot_id);
+    }
+    for (consultation, (start_at, slot)) in remaining.into_iter().zip(periods) {
+        move_consultation(consultation, start_at, slot, payload.actor_id);
+    }
+
+    series.updated_at = time();
+    CONSULTATION_SERIES_STORAGE
+        .with(|storage| storage.borrow_mut().insert(series_id, series.clone()));
+    Ok(series)
+}
+
+// Function to cancel all remaining consultations of a series
+#[ic_cdk::update]
+fn cancel_consultation_series(
+    series_id: u64,
+    payload: ConsultationTransitionPayload,
+) -> Result<ConsultationSeries, String> {
+    let mut series = CONSULTATION_SERIES_STORAGE
+        .with(|storage| storage.borrow().get(&series_id))
+        .ok_or("Consultation series ID does not exist.".to_string())?;
+    if series.cancelled_at.is_some() {
+        return Err("The consultation series is already cancelled.".to_string());
+    }
+
+    let is_participant = payload.actor_id == series.provider_id
+        || payload.actor_id == series.user_id
+        || is_linked_caregiver(series.user_id, payload.actor_id);
+    if !is_participant {
+        return Err(
+            "Only the patient, a linked caregiver or the provider can cancel a series.".to_string(),
+        );
+    }
+    if payload.reason.is_empty() {
+        return Err("A reason is required to cancel a consultation.".to_string());
+    }
+    validate_status_reason(&payload.reason)?;
+
+    for consultation in remaining_series_consultations(&series) {
+        transition_consultation(
+            consultation.id,
+            ConsultationTransitionPayload {
+                actor_id: payload.actor_id,
+                reason: payload.reason.clone(),
+            },
+            ConsultationStatus::Cancelled,
+        )?;
+    }
+
+    let now = time();
+    series.cancelled_at = Some(now);
+    series.updated_at = now;
+    CONSULTATION_SERIES_STORAGE
+        .with(|storage| storage.borrow_mut().insert(series_id, series.clone()));
+    Ok(series)
+}
+
+// Function to retrieve the consultation series of a user
+#[ic_cdk::query]
+fn get_consultation_series_by_user_id(user_id: u64) -> Result<Vec<ConsultationSeries>, String> {
+    CONSULTATION_SERIES_STORAGE.with(|storage| {
+        let records: Vec<ConsultationSeries> = storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id)
+            .map(|(_, record)| record)
+            .collect();
+        if records.is_empty() {
+            Err("No consultation series found for the user.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Helper to list the start times of a series, in UTC; monthly series keep the
+// day of the month, falling back to the last day in shorter months
+fn series_occurrences(
+    start_at: u64,
+    recurrence: &ConsultationRecurrence,
+) -> Result<Vec<u64>, String> {
+    if recurrence.interval == 0 || recurrence.interval > MAX_RECURRENCE_INTERVAL {
+        return Err(format!(
+            "Recurrence interval must be between 1 and {}.",
+            MAX_RECURRENCE_INTERVAL
+        ));
+    }
+    let out_of_range = || "The series runs past the supported date range.".to_string();
+    let start = i64::try_from(start_at).map_err(|_| out_of_range())?;
+
+    (0..recurrence.occurrences)
+        .map(|index| {
+            let step = index
+                .checked_mul(recurrence.interval)
+                .ok_or_else(out_of_range)?;
+            match recurrence.frequency {
+                RecurrenceFrequency::Weekly => (step as u64)
+                    .checked_mul(7 * NANOS_PER_DAY)
+                    .and_then(|offset| start_at.checked_add(offset)),
+                RecurrenceFrequency::Monthly => DateTime::from_timestamp_nanos(start)
+                    .checked_add_months(Months::new(step))
+                    .and_then(|date| date.timestamp_nanos_opt())
+                    .map(|nanos| nanos as u64),
+            }
+            .ok_or_else(out_of_range)
+        })
+        .collect()
+}
+
+// Helper to work out when a consultation starting at the given time ends
+fn consultation_end(start_at: u64, duration: u64) -> Result<u64, String> {
+    start_at
+        .checked_add(duration)
+        .ok_or("The consultation ends past the supported date range.".to_string())
+}
+
+// Helper to check a consultation can be rescheduled again and still record
+// the rest of its lifecycle within MAX_CONSULTATION_STATUS_CHANGES
+fn check_can_reschedule(consultation: &VirtualConsultation) -> Result<(), String> {
+    if consultation.status_history.len() + 1 + CONSULTATION_LIFECYCLE_CHANGES
+        > MAX_CONSULTATION_STATUS_CHANGES
+    {
+        return Err(format!(
+            "Consultation {} has been rescheduled too many times; cancel it and book a new one.",
+            consultation.id
+        ));
+    }
+    Ok(())
+}
+
+// Helper to list the upcoming consultations of a series that can still be changed
+fn remaining_series_consultations(series: &ConsultationSeries) -> Vec<VirtualConsultation> {
+    let now = time();
+    let mut records: Vec<VirtualConsultation> = VIRTUAL_CONSULTATIONS_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        series
+            .consultation_ids
+            .iter()
+            .filter_map(|id| storage.get(id))
+            .filter(|record| {
+                record.scheduled_at > now
+                    && matches!(
+                        record.status,
+                        ConsultationStatus::Requested | ConsultationStatus::Confirmed
+                    )
+            })
+            .collect()
+    });
+    records.sort_by_key(|record| record.scheduled_at);
+    records
+}
+
+// Helper to move a consultation to a new start time, keeping its length, and
+// record the change in its history
+fn move_consultation(
+    mut consultation: VirtualConsultation,
+    start_at: u64,
+    slot: Option<AvailabilitySlot>,
+    actor_id: u64,
+) -> VirtualConsultation {
+    // The caller has checked the end time with consultation_end
+    let end_at = start_at + (consultation.ends_at - consultation.scheduled_at);
+    let mut slot = slot
+        .unwrap_or_else(|| insert_availability_slot(consultation.provider_id, start_at, end_at));
+
+    let now = time();
+    consultation.slot_id = slot.id;
+    consultation.scheduled_at = start_at;
+    consultation.ends_at = end_at;
+    consultation.status_history.push(ConsultationStatusChange {
+        status: consultation.status,
+        reason: format!("Rescheduled to {}", format_timestamp(start_at)),
+        changed_by: actor_id,
+        changed_at: now,
+    });
+    consultation.updated_at = now;
+
+    VIRTUAL_CONSULTATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(consultation.id, consultation.clone())
+    });
+
+    slot.consultation_id = Some(consultation.id);
+    AVAILABILITY_SLOTS_STORAGE.with(|storage| storage.borrow_mut().insert(slot.id, slot));
+    consultation
+}
+
+// Function for the consulting provider to write the notes for a consultation
+#[ic_cdk::update]
+fn create_consultation_note(
+    consultation_id: u64,
+    payload: ConsultationNotePayload,
+) -> Result<ConsultationNote, String> {
+    let consultation = VIRTUAL_CONSULTATIONS_STORAGE
+        .with(|storage| storage.borrow().get(&consultation_id))
+        .ok_or("Consultation ID does not exist.".to_string())?;
+    if payload.provider_id != consultation.provider_id {
+        return Err("Only the consulting provider can write notes.".to_string());
+    }
+    check_caller_is_user(payload.provider_id)?;
+    if !matches!(
+        consultation.status,
+        ConsultationStatus::InProgress | ConsultationStatus::Completed
+    ) {
+        return Err("Notes can only be written once the consultation has started.".to_string());
+    }
+    if find_consultation_note(consultation_id).is_some() {
+        return Err("This consultation already has notes.".to_string());
+    }
+    validate_consultation_note(&payload)?;
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let now = time();
+    let note = ConsultationNote {
+        id,
+        consultation_id,
+        user_id: consultation.user_id,
+        provider_id: consultation.provider_id,
+        subjective: payload.subjective,
+        objective: payload.objective,
+        assessment: payload.assessment,
+        plan: payload.plan,
+        follow_up_actions: payload.follow_up_actions,
+        signed_off_at: None,
+        created_at: now,
+        updated_at: now,
+    };
+
+    CONSULTATION_NOTES_STORAGE.with(|storage| storage.borrow_mut().insert(id, note.clone()));
+    Ok(note)
+}
+
+// Function for the author to revise consultation notes that have not been signed off
+#[ic_cdk::update]
+fn update_consultation_note(
+    note_id: u64,
+    payload: ConsultationNotePayload,
+) -> Result<ConsultationNote, String> {
+    let mut note = CONSULTATION_NOTES_STORAGE
+        .with(|storage| storage.borrow().get(&note_id))
+        .ok_or("Consultation note ID does not exist.".to_string())?;
+    if payload.provider_id != note.provider_id {
+        return Err("Only the author can edit consultation notes.".to_string());
+    }
+    check_caller_is_user(payload.provider_id)?;
+    if note.signed_off_at.is_some() {
+        return Err("Signed-off consultation notes cannot be edited.".to_string());
+    }
+    validate_consultation_note(&payload)?;
+
+    note.subjective = payload.subjective;
+    note.objective = payload.objective;
+    note.assessment = payload.assessment;
+    note.plan = payload.plan;
+    note.follow_up_actions = payload.follow_up_actions;
+    note.updated_at = time();
+
+    CONSULTATION_NOTES_STORAGE.with(|storage| storage.borrow_mut().insert(note_id, note.clone()));
+    Ok(note)
+}
+
+// Function for the author to sign off consultation notes, locking them against edits
+#[ic_cdk::update]
+fn sign_off_consultation_note(note_id: u64, provider_id: u64) -> Result<ConsultationNote, String> {
+    check_caller_is_user(provider_id)?;
+    CONSULTATION_NOTES_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let mut note = storage
+            .get(&note_id)
+            .ok_or("Consultation note ID does not exist.".to_string())?;
+        if provider_id != note.provider_id {
+            return Err("Only the author can sign off consultation notes.".to_string());
+        }
+        if note.signed_off_at.is_some() {
+            return Err("Consultation notes are already signed off.".to_string());
+        }
+        let now = time();
+        note.signed_off_at = Some(now);
+        note.updated_at = now;
+        storage.insert(note_id, note.clone());
+        Ok(note)
+    })
+}
+
+// Function to retrieve the notes of a consultation, for users allowed to see them
+#[ic_cdk::query]
+fn get_consultation_note_by_consultation_id(
+    consultation_id: u64,
+    viewer_id: u64,
+) -> Result<ConsultationNote, String> {
+    check_caller_is_user(viewer_id)?;
+    let note = find_consultation_note(consultation_id)
+        .ok_or("No notes found for the consultation.".to_string())?;
+    if !can_view_consultation_note(&note, viewer_id) {
+        return Err("You do not have access to these consultation notes.".to_string());
+    }
+    Ok(note)
+}
+
+// Helper to find the notes written for a consultation
+fn find_consultation_note(consultation_id: u64) -> Option<ConsultationNote> {
+    CONSULTATION_NOTES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .find(|(_, note)| note.consultation_id == consultation_id)
+            .map(|(_, note)| note)
+    })
+}
+
+// Helper to validate consultation notes
+fn validate_consultation_note(payload: &ConsultationNotePayload) -> Result<(), String> {
+    if payload.assessment.is_empty() || payload.plan.is_empty() {
+        return Err("An assessment and a plan must be provided.".to_string());
+    }
+    if [
+        &payload.subjective,
+        &payload.objective,
+        &payload.assessment,
+        &payload.plan,
+    ]
+    .iter()
+    .any(|section| section.len() > MAX_NOTE_SECTION_LENGTH)
+    {
+        return Err(format!(
+            "Each section of the notes must be at most {} bytes.",
+            MAX_NOTE_SECTION_LENGTH
+        ));
+    }
+    if payload.follow_up_actions.len() > MAX_FOLLOW_UP_ACTIONS {
+        return Err(format!(
+            "At most {} follow-up actions can be recorded.",
+            MAX_FOLLOW_UP_ACTIONS
+        ));
+    }
+    if payload.follow_up_actions.iter().any(|action| {
+        action.description.is_empty() || action.description.len() > MAX_FOLLOW_UP_DESCRIPTION_LENGTH
+    }) {
+        return Err(format!(
+            "Follow-up actions must have a description of at most {} bytes.",
+            MAX_FOLLOW_UP_DESCRIPTION_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+// Helper to check whether a user may read consultation notes: the patient,
+// their linked caregivers, the consulting provider and providers the notes
+// were shared with through an accepted referral
+fn can_view_consultation_note(note: &ConsultationNote, viewer_id: u64) -> bool {
+    viewer_id == note.user_id
+        || viewer_id == note.provider_id
+        || is_linked_caregiver(note.user_id, viewer_id)
+        || has_referral_access(
+            note.user_id,
+            viewer_id,
+            &ReferralAttachment {
+                record_type: ReferralRecordType::ConsultationNote,
+                record_id: note.id,
+            },
+        )
+}
+
+// Function for a healthcare provider to publish an availability slot
+#[ic_cdk::update]
+fn create_availability_slot(payload: AvailabilitySlotPayload) -> Result<AvailabilitySlot, String> {
+    let provider = check_caller_is_user(payload.provider_id)?;
+    if provider.user_type != UserType::HealthcareProvider {
+        return Err("Only healthcare providers can publish availability.".to_string());
+    }
+
+    if payload.start_at <= time() {
+        return Err("Availability slots must start in the future.".to_string());
+    }
+    if payload.end_at <= payload.start_at {
+        return Err("Availability slots must end after they start.".to_string());
+    }
+
+    let overlaps = AVAILABILITY_SLOTS_STORAGE.with(|storage| {
+        storage.borrow().iter().any(|(_, slot)| {
+            slot.provider_id == payload.provider_id
+                && slot.start_at < payload.end_at
+                && payload.start_at < slot.end_at
+        })
+    });
+    if overlaps {
+        return Err("This slot overlaps another of the provider's slots.".to_string());
+    }
+
+    Ok(insert_availability_slot(
+        payload.provider_id,
+        payload.start_at,
+        payload.end_at,
+    ))
+}
+
+// Helper to store a new, unbooked availability slot
+fn insert_availability_slot(provider_id: u64, start_at: u64, end_at: u64) -> AvailabilitySlot {
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let slot = AvailabilitySlot {
+        id,
+        provider_id,
+        start_at,
+        end_at,
+        consultation_id: None,
+        created_at: time(),
+    };
+
+    AVAILABILITY_SLOTS_STORAGE.with(|storage| storage.borrow_mut().insert(id, slot.clone()));
+    slot
+}
+
+// Function for a healthcare provider to withdraw an unbooked availability slot
+#[ic_cdk::update]
+fn remove_availability_slot(slot_id: u64, provider_id: u64) -> Result<AvailabilitySlot, String> {
+    check_caller_is_user(provider_id)?;
+    AVAILABILITY_SLOTS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let slot = storage
+            .get(&slot_id)
+            .ok_or("Availability slot ID does not exist.".to_string())?;
+        if slot.provider_id != provider_id {
+            return Err("Only the provider who published a slot can remove it.".to_string());
+        }
+        if slot.consultation_id.is_some() {
+            return Err(
+                "A booked slot cannot be removed; cancel the consultation first.".to_string(),
+            );
+        }
+        storage.remove(&slot_id);
+        Ok(slot)
+    })
+}
+
+// Function to retrieve a provider's free upcoming availability slots
+#[ic_cdk::query]
+fn get_available_slots_by_provider_id(provider_id: u64) -> Result<Vec<AvailabilitySlot>, String> {
+    let now = time();
+    let mut records: Vec<AvailabilitySlot> = AVAILABILITY_SLOTS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, slot)| {
+                slot.provider_id == provider_id
+                    && slot.consultation_id.is_none()
+                    && slot.start_at > now
+            })
+            .map(|(_, slot)| slot)
+            .collect()
+    });
+    if records.is_empty() {
+        return Err("No available slots found for the provider.".to_string());
+    }
+    records.sort_by_key(|slot| slot.start_at);
+    Ok(records)
+}
+
+// Helper to find an open consultation of any of the given users (as patient or
+// provider), other than the ignored ones, that overlaps the period [start_at, end_at)
+fn find_overlapping_consultation(
+    user_ids: &[u64],
+    start_at: u64,
+    end_at: u64,
+    ignored: &[u64],
+) -> Option<VirtualConsultation> {
+    VIRTUAL_CONSULTATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .find(|(_, record)| {
+                matches!(
+                    record.status,
+                    ConsultationStatus::Requested
+                        | ConsultationStatus::Confirmed
+                        | ConsultationStatus::InProgress
+                ) && !ignored.contains(&record.id)
+                    && (user_ids.contains(&record.user_id)
+                        || user_ids.contains(&record.provider_id))
+                    && record.scheduled_at < end_at
+                    && start_at < record.ends_at
+            })
+            .map(|(_, record)| record)
+    })
+}
+
+// Function for a provider who has treated a patient to refer them to another provider
+#[ic_cdk::update]
+fn create_referral(payload: ReferralPayload) -> Result<Referral, String> {
+    if payload.reason.is_empty() {
+        return Err("A reason for the referral must be provided.".to_string());
+    }
+    validate_status_reason(&payload.reason)?;
+    check_caller_is_user(payload.referring_provider_id)?;
+    if payload.referring_provider_id == payload.receiving_provider_id {
+        return Err("A provider cannot refer a patient to themselves.".to_string());
+    }
+
+    for provider_id in [payload.referring_provider_id, payload.receiving_provider_id] {
+        let provider = USERS_STORAGE
+            .with(|storage| storage.borrow().get(&provider_id))
+            .ok_or("Provider ID does not exist.".to_string())?;
+        if provider.user_type != UserType::HealthcareProvider {
+            return Err("Referrals can only be made between healthcare providers.".to_string());
+        }
+    }
+
+    let user_exists = USERS_STORAGE.with(|storage| storage.borrow().contains_key(&payload.user_id));
+    if !user_exists {
+        return Err("User ID does not exist.".to_string());
+    }
+    if !has_treated_patient(payload.referring_provider_id, payload.user_id) {
+        return Err("Only a provider who has treated the patient can refer them.".to_string());
+    }
+
+    let mut attachments: Vec<ReferralAttachment> = Vec::new();
+    for attachment in payload.attachments {
+        if record_owner(&attachment) != Some(payload.user_id) {
+            return Err(format!(
+                "{:?} {} does not exist or does not belong to the patient.",
+                attachment.record_type, attachment.record_id
+            ));
+        }
+        if !attachments.contains(&attachment) {
+            attachments.push(attachment);
+        }
+    }
+    if attachments.len() > MAX_REFERRAL_ATTACHMENTS {
+        return Err(format!(
+            "At most {} records can be attached to a referral.",
+            MAX_REFERRAL_ATTACHMENTS
+        ));
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let now = time();
+    let referral = Referral {
+        id,
+        user_id: payload.user_id,
+        referring_provider_id: payload.referring_provider_id,
+        receiving_provider_id: payload.receiving_provider_id,
+        reason: payload.reason,
+        attachments,
+        status: ReferralStatus::Sent,
+        status_history: vec![ReferralStatusChange {
+            status: ReferralStatus::Sent,
+            note: String::new(),
+            changed_by: payload.referring_provider_id,
+            changed_at: now,
+        }],
+        created_at: now,
+        updated_at: now,
+    };
+
+    REFERRALS_STORAGE.with(|storage| storage.borrow_mut().insert(id, referral.clone()));
+    Ok(referral)
+}
+
+// Function for the receiving provider to accept a referral, gaining access to its attached records
+#[ic_cdk::update]
+fn accept_referral(
+    referral_id: u64,
+    payload: ReferralTransitionPayload,
+) -> Result<Referral, String> {
+    transition_referral(referral_id, payload, ReferralStatus::Accepted)
+}
+
+// Function for the receiving provider to decline a referral
+#[ic_cdk::update]
+fn decline_referral(
+    referral_id: u64,
+    payload: ReferralTransitionPayload,
+) -> Result<Referral, String> {
+    transition_referral(referral_id, payload, ReferralStatus::Declined)
+}
+
+// Function for the receiving provider to complete a referral, ending their access
+#[ic_cdk::update]
+fn complete_referral(
+    referral_id: u64,
+    payload: ReferralTransitionPayload,
+) -> Result<Referral, String> {
+    transition_referral(referral_id, payload, ReferralStatus::Completed)
+}
+
+// Function to retrieve the referrals a provider has sent or received
+#[ic_cdk::query]
+fn get_referrals_by_provider_id(provider_id: u64) -> Result<Vec<Referral>, String> {
+    REFERRALS_STORAGE.with(|storage| {
+        let records: Vec<Referral> = storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| {
+                record.referring_provider_id == provider_id
+                    || record.receiving_provider_id == provider_id
+            })
+            .map(|(_, record)| record)
+            .collect();
+        if records.is_empty() {
+            Err("No referrals found for the provider.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to retrieve the referrals of a patient
+#[ic_cdk::query]
+fn get_referrals_by_user_id(user_id: u64) -> Result<Vec<Referral>, String> {
+    REFERRALS_STORAGE.with(|storage| {
+        let records: Vec<Referral> = storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id)
+            .map(|(_, record)| record)
+            .collect();
+        if records.is_empty() {
+            Err("No referrals found for the user.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function for the receiving provider to read the records attached to an accepted referral
+#[ic_cdk::query]
+fn get_referral_records(referral_id: u64, provider_id: u64) -> Result<ReferralRecords, String> {
+    check_caller_is_user(provider_id)?;
+    let referral = REFERRALS_STORAGE
+        .with(|storage| storage.borrow().get(&referral_id))
+        .ok_or("Referral ID does not exist.".to_string())?;
+    if referral.receiving_provider_id != provider_id || referral.status != ReferralStatus::Accepted
+    {
+        return Err("You do not have access to the records of this referral.".to_string());
+    }
+
+    let mut records = ReferralRecords {
+        referral_id,
+        ..Default::default()
+    };
+    for attachment in &referral.attachments {
+        let id = &attachment.record_id;
+        match attachment.record_type {
+            ReferralRecordType::HealthRecord => records
+                .health_records
+                .extend(HEALTH_RECORDS_STORAGE.with(|storage| storage.borrow().get(id))),
+            ReferralRecordType::MentalHealthRecord => records
+                .mental_health_records
+                .extend(MENTAL_HEALTH_RECORDS_STORAGE.with(|storage| storage.borrow().get(id))),
+            ReferralRecordType::MedicationReminder => records
+                .medication_reminders
+                .extend(MEDICATION_REMINDERS_STORAGE.with(|storage| storage.borrow().get(id))),
+            ReferralRecordType::Prescription => records
+                .prescriptions
+                .extend(PRESCRIPTIONS_STORAGE.with(|storage| storage.borrow().get(id))),
+            ReferralRecordType::ConsultationNote => records
+                .consultation_notes
+                .extend(CONSULTATION_NOTES_STORAGE.with(|storage| storage.borrow().get(id))),
+        }
+    }
+    Ok(records)
+}
+
+// Helper to apply a status transition to a referral; only the receiving
+// provider can move it on from Sent
+fn transition_referral(
+    referral_id: u64,
+    payload: ReferralTransitionPayload,
+    status: ReferralStatus,
+) -> Result<Referral, String> {
+    let mut referral = REFERRALS_STORAGE
+        .with(|storage| storage.borrow().get(&referral_id))
+        .ok_or("Referral ID does not exist.".to_string())?;
+    if payload.provider_id != referral.receiving_provider_id {
+        return Err("Only the receiving provider can update a referral.".to_string());
+    }
+    check_caller_is_user(payload.provider_id)?;
+
+    let valid = matches!(
+        (referral.status, status),
+        (ReferralStatus::Sent, ReferralStatus::Accepted)
+            | (ReferralStatus::Sent, ReferralStatus::Declined)
+            | (ReferralStatus::Accepted, ReferralStatus::Completed)
+    );
+    if !valid {
+        return Err(format!(
+            "A {:?} referral cannot be marked {:?}.",
+            referral.status, status
+        ));
+    }
+    if status == ReferralStatus::Declined && payload.note.is_empty() {
+        return Err("A reason is required to decline a referral.".to_string());
+    }
+    validate_status_reason(&payload.note)?;
+
+    let now = time();
+    referral.status = status;
+    referral.status_history.push(ReferralStatusChange {
+        status,
+        note: payload.note,
+        changed_by: payload.provider_id,
+        changed_at: now,
+    });
+    referral.updated_at = now;
+
+    REFERRALS_STORAGE.with(|storage| storage.borrow_mut().insert(referral_id, referral.clone()));
+    Ok(referral)
+}
+
+// Helper to check whether a provider has held a consultation with, prescribed
+// for or accepted a referral of a patient
+fn has_treated_patient(provider_id: u64, user_id: u64) -> bool {
+    let consulted = VIRTUAL_CONSULTATIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().any(|(_, record)| {
+            record.provider_id == provider_id
+                && record.user_id == user_id
+                && record.status == ConsultationStatus::Completed
+        })
+    });
+    let prescribed = PRESCRIPTIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .any(|(_, record)| record.provider_id == provider_id && record.patient_id == user_id)
+    });
+    let referred = REFERRALS_STORAGE.with(|storage| {
+        storage.borrow().iter().any(|(_, record)| {
+            record.receiving_provider_id == provider_id
+                && record.user_id == user_id
+                && record.status == ReferralStatus::Accepted
+        })
+    });
+    consulted || prescribed || referred
+}
+
+// Helper to find the patient a referral attachment belongs to, if the record exists
+fn record_owner(attachment: &ReferralAttachment) -> Option<u64> {
+    let id = &attachment.record_id;
+    match attachment.record_type {
+        ReferralRecordType::HealthRecord => {
+            HEALTH_RECORDS_STORAGE.with(|storage| storage.borrow().get(id).map(|r| r.user_id))
+        }
+        ReferralRecordType::MentalHealthRecord => MENTAL_HEALTH_RECORDS_STORAGE
+            .with(|storage| storage.borrow().get(id).map(|r| r.user_id)),
+        ReferralRecordType::MedicationReminder => {
+            MEDICATION_REMINDERS_STORAGE.with(|storage| storage.borrow().get(id).map(|r| r.user_id))
+        }
+        ReferralRecordType::Prescription => {
+            PRESCRIPTIONS_STORAGE.with(|storage| storage.borrow().get(id).map(|r| r.patient_id))
+        }
+        ReferralRecordType::ConsultationNote => {
+            CONSULTATION_NOTES_STORAGE.with(|storage| storage.borrow().get(id).map(|r| r.user_id))
+        }
+    }
+}
+
+// Helper to check whether a provider, acting as the caller, can see a patient
+// record through an accepted referral
+fn has_referral_access(user_id: u64, provider_id: u64, attachment: &ReferralAttachment) -> bool {
+    if check_caller_is_user(provider_id).is_err() {
+        return false;
+    }
+    REFERRALS_STORAGE.with(|storage| {
+        storage.borrow().iter().any(|(_, referral)| {
+            referral.user_id == user_id
+                && referral.receiving_provider_id == provider_id
+                && referral.status == ReferralStatus::Accepted
+                && referral.attachments.contains(attachment)
+        })
+    })
+}
+
+// Function to create a new diet record
+#[ic_cdk::update]
+fn create_diet_record(payload: DietRecordPayload) -> Result<DietRecord, String> {
+    // Ensure all fields are provided
+    if payload.items.is_empty() {
+        return Err("All fields must be provided.".to_string());
+    }
+    if payload.items.len() > MAX_MEAL_ITEMS {
+        return Err(format!(
+            "A meal can include at most {} items.",
+            MAX_MEAL_ITEMS
+        ));
+    }
+
+    // Ensure user ID exists
+    let user_exists = USERS_STORAGE.with(|storage| storage.borrow().contains_key(&payload.user_id));
+    if !user_exists {
+        return Err("User ID does not exist.".to_string());
+    }
+
+    let mut items = Vec::new();
+    let mut foods = Vec::new();
+    for item in &payload.items {
+        validate_servings(item.servings)?;
+        let food_item = FOOD_ITEMS_STORAGE
+            .with(|storage| storage.borrow().get(&item.food_item_id))
+            .ok_or(format!(
+                "Food item ID {} does not exist.",
+                item.food_item_id
+            ))?;
+        items.push(DietRecordItem {
+            food_item_id: food_item.id,
+            food_name: food_item.name.clone(),
+            servings: item.servings,
+            nutrients: scale_nutrients(&food_item.nutrients_per_serving, item.servings),
+        });
+        foods.push((food_item, item.servings));
+    }
+    let nutrients = items.iter().fold(Nutrients::default(), |total, item| {
+        add_nutrients(&total, &item.nutrients)
+    });
+
+    let food_names: Vec<&str> = items.iter().map(|item| item.food_name.as_str()).collect();
+    let allergy_warnings = check_allergies(payload.user_id, &[AllergyCategory::Food], &food_names)?;
+    let dietary_conflicts = cap_dietary_conflicts(check_dietary_restrictions(
+        payload.user_id,
+        &format!("{:?}", payload.meal_type),
+        &foods,
+    ));
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let diet_record = DietRecord {
+        id,
+        user_id: payload.user_id,
+        meal_type: payload.meal_type,
+        items,
+        nutrients,
+        allergy_warnings,
+        dietary_conflicts: Some(dietary_conflicts.clone()),
+        recorded_at: time(),
+    };
+
+    DIET_RECORDS_STORAGE.with(|storage| storage.borrow_mut().insert(id, diet_record.clone()));
+    flag_for_diet_review(
+        payload.user_id,
+        DietEntryType::DietRecord,
+        id,
+        &dietary_conflicts,
+    );
+    Ok(diet_record)
+}
+
+// Function to retrieve diet records by user ID
+#[ic_cdk::query]
+fn get_diet_records_by_user_id(user_id: u64) -> Result<Vec<DietRecord>, String> {
+    DIET_RECORDS_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<DietRecord> = stable_btree_map
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id)
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No diet records found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to retrieve all diet records
+#[ic_cdk::query]
+fn get_all_diet_records() -> Result<Vec<DietRecord>, String> {
+    if !is_admin() {
+        return Err("Only an admin can list every user's records.".to_string());
+    }
+    DIET_RECORDS_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<DietRecord> = stable_btree_map
+            .iter()
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No diet records found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to add an item to the food catalogue (admin only)
+#[ic_cdk::update]
+fn add_food_item(payload: FoodItemPayload) -> Result<FoodItem, String> {
+    if !is_admin() {
+        return Err("Only an admin can manage the food catalogue.".to_string());
+    }
+    let name = validate_food_item(&payload, None)?;
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let now = time();
+    let food_item = FoodItem {
+        id,
+        name,
+        serving_description: payload.serving_description,
+        nutrients_per_serving: payload.nutrients_per_serving,
+        is_drink: Some(payload.is_drink),
+        iddsi_level: Some(payload.iddsi_level),
+        created_at: now,
+        updated_at: now,
+    };
+
+    FOOD_ITEMS_STORAGE.with(|storage| storage.borrow_mut().insert(id, food_item.clone()));
+    Ok(food_item)
+}
+
+// Function to update an item in the food catalogue (admin only)
+#[ic_cdk::update]
+fn update_food_item(food_item_id: u64, payload: FoodItemPayload) -> Result<FoodItem, String> {
+    if !is_admin() {
+        return Err("Only an admin can manage the food catalogue.".to_string());
+    }
+    let name = validate_food_item(&payload, Some(food_item_id))?;
+
+    FOOD_ITEMS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let mut food_item = storage
+            .get(&food_item_id)
+            .ok_or("Food item ID does not exist.".to_string())?;
+        food_item.name = name;
+        food_item.serving_description = payload.serving_description;
+        food_item.nutrients_per_serving = payload.nutrients_per_serving;
+        food_item.is_drink = Some(payload.is_drink);
+        food_item.iddsi_level = Some(payload.iddsi_level);
+        food_item.updated_at = time();
+        storage.insert(food_item_id, food_item.clone());
+        Ok(food_item)
+    })
+}
+
+// Function to remove an item from the food catalogue (admin only); existing
+// diet records keep their copy of its nutrients
+#[ic_cdk::update]
+fn remove_food_item(food_item_id: u64) -> Result<FoodItem, String> {
+    if !is_admin() {
+        return Err("Only an admin can manage the food catalogue.".to_string());
+    }
+
+    FOOD_ITEMS_STORAGE
+        .with(|storage| storage.borrow_mut().remove(&food_item_id))
+        .ok_or("Food item ID does not exist.".to_string())
+}
+
+// Function to search the food catalogue by name; an empty query lists every item
+#[ic_cdk::query]
+fn search_food_items(query: String) -> Result<Vec<FoodItem>, String> {
+    let query = query.trim().to_lowercase();
+    let mut records: Vec<FoodItem> = FOOD_ITEMS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, item)| item.name.to_lowercase().contains(&query))
+            .map(|(_, item)| item)
+            .collect()
+    });
+    if records.is_empty() {
+        return Err("No food items found.".to_string());
+    }
+    records.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(records)
+}
+
+// Helper to validate a food item payload and return its trimmed name; names
+// must be unique (ignoring case) across the catalogue
+fn validate_food_item(
+    payload: &FoodItemPayload,
+    food_item_id: Option<u64>,
+) -> Result<String, String> {
+    let name = payload.name.trim().to_string();
+    if name.is_empty() || payload.serving_description.is_empty() {
+        return Err("All fields must be provided.".to_string());
+    }
+    if name.len() > MAX_FOOD_NAME_LENGTH
+        || payload.serving_description.len() > MAX_SERVING_DESCRIPTION_LENGTH
+    {
+        return Err(format!(
+            "Names can be at most {} bytes and serving descriptions {} bytes long.",
+            MAX_FOOD_NAME_LENGTH, MAX_SERVING_DESCRIPTION_LENGTH
+        ));
+    }
+
+    let nutrients = &payload.nutrients_per_serving;
+    let amounts = [
+        nutrients.calories,
+        nutrients.protein_grams,
+        nutrients.carbohydrate_grams,
+        nutrients.fat_grams,
+        nutrients.sodium_milligrams,
+        nutrients.sugar_grams,
+    ];
+    if amounts
+        .iter()
+        .any(|amount| !amount.is_finite() || *amount < 0.0 || *amount > MAX_NUTRIENT_AMOUNT)
+    {
+        return Err(format!(
+            "Nutrient amounts must be between 0 and {}.",
+            MAX_NUTRIENT_AMOUNT
+        ));
+    }
+    check_iddsi_level(payload.is_drink, payload.iddsi_level)?;
+
+    let duplicate = FOOD_ITEMS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .any(|(id, item)| Some(id) != food_item_id && item.name.eq_ignore_ascii_case(&name))
+    });
+    if duplicate {
+        return Err("A food item with this name already exists.".to_string());
+    }
+    Ok(name)
+}
+
+// Helper to check an IDDSI level applies to the kind of item: drinks use the
+// levels 0 to 4 and foods the levels 3 to 7
+fn check_iddsi_level(is_drink: bool, level: IddsiLevel) -> Result<(), String> {
+    let (kind, levels) = if is_drink {
+        ("Drinks", 0..=4)
+    } else {
+        ("Foods", 3..=7)
+    };
+    if !levels.contains(&iddsi_number(level)) {
+        return Err(format!(
+            "{} must have an IDDSI level from {} to {}.",
+            kind,
+            levels.start(),
+            levels.end()
+        ));
+    }
+    Ok(())
+}
+
+// Helper to validate the number of servings of an item in a meal
+fn validate_servings(servings: f64) -> Result<(), String> {
+    if !servings.is_finite() || servings <= 0.0 || servings > MAX_SERVINGS {
+        return Err(format!(
+            "Servings must be greater than zero and at most {}.",
+            MAX_SERVINGS
+        ));
+    }
+    Ok(())
+}
+
+// Function for a healthcare provider to set a patient's daily nutrition targets,
+// replacing any previous targets
+#[ic_cdk::update]
+fn set_nutrition_targets(payload: NutritionTargetsPayload) -> Result<NutritionTargets, String> {
+    let provider = check_caller_is_user(payload.provider_id)?;
+    if provider.user_type != UserType::HealthcareProvider {
+        return Err("Only healthcare providers can set nutrition targets.".to_string());
+    }
+
+    let user_exists = USERS_STORAGE.with(|storage| storage.borrow().contains_key(&payload.user_id));
+    if !user_exists {
+        return Err("User ID does not exist.".to_string());
+    }
+
+    let mut nutrients: Vec<Nutrient> = Vec::new();
+    for target in &payload.targets {
+        if nutrients.contains(&target.nutrient) {
+            return Err(format!("{:?} has more than one target.", target.nutrient));
+        }
+        nutrients.push(target.nutrient);
+
+        let bounds = [target.minimum, target.maximum];
+        if bounds.iter().all(Option::is_none) {
+            return Err(format!(
+                "The {:?} target needs a minimum or a maximum.",
+                target.nutrient
+            ));
+        }
+        if bounds
+            .iter()
+            .flatten()
+            .any(|bound| !bound.is_finite() || *bound < 0.0)
+        {
+            return Err("Nutrition targets must be zero or more.".to_string());
+        }
+        if let (Some(minimum), Some(maximum)) = (target.minimum, target.maximum) {
+            if minimum > maximum {
+                return Err(format!(
+                    "The {:?} target's minimum is above its maximum.",
+                    target.nutrient
+                ));
+            }
+        }
+    }
+
+    let targets = NutritionTargets {
+        user_id: payload.user_id,
+        targets: payload.targets,
+        set_by: payload.provider_id,
+        updated_at: time(),
+    };
+
+    NUTRITION_TARGETS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(payload.user_id, targets.clone())
+    });
+    Ok(targets)
+}
+
+// Function to retrieve a patient's nutrition targets
+#[ic_cdk::query]
+fn get_nutrition_targets(user_id: u64) -> Result<NutritionTargets, String> {
+    NUTRITION_TARGETS_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .ok_or("No nutrition targets found for the user.".to_string())
+}
+
+// Function to total a user's diet records for the local day or week (starting
+// Monday) containing `date`, and compare the daily average with their targets
+#[ic_cdk::query]
+fn get_nutrition_summary(
+    user_id: u64,
+    period: SummaryPeriod,
+    date: u64,
+) -> Result<NutritionSummary, String> {
+    let user = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .ok_or("User ID does not exist.".to_string())?;
+
+    let period_start = local_period_start(date, user.utc_offset_minutes.unwrap_or(0), period);
+    let period_days = match period {
+        SummaryPeriod::Daily => 1,
+        SummaryPeriod::Weekly => 7,
+    };
+    let period_end = period_start + period_days * NANOS_PER_DAY;
+
+    // A period still in progress is averaged over the days begun so far
+    let counted_until = period_end.min(time().max(period_start + 1));
+    let days_counted = (counted_until - period_start).div_ceil(NANOS_PER_DAY) as u32;
+
+    let (meals_logged, totals) = DIET_RECORDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| {
+                record.user_id == user_id
+                    && record.recorded_at >= period_start
+                    && record.recorded_at < period_end
+            })
+            .fold(
+                (0u32, Nutrients::default()),
+                |(meals, total), (_, record)| (meals + 1, add_nutrients(&total, &record.nutrients)),
+            )
+    });
+    let daily_average = scale_nutrients(&totals, 1.0 / days_counted as f64);
+
+    let targets = NUTRITION_TARGETS_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .map_or(Vec::new(), |targets| targets.targets)
+        .into_iter()
+        .map(|target| {
+            let amount = nutrient_amount(&daily_average, target.nutrient);
+            let status = match (target.minimum, target.maximum) {
+                (Some(minimum), _) if amount < minimum => TargetStatus::Under,
+                (_, Some(maximum)) if amount > maximum => TargetStatus::Over,
+                _ => TargetStatus::Met,
+            };
+            NutritionTargetResult {
+                nutrient: target.nutrient,
+                minimum: target.minimum,
+                maximum: target.maximum,
+                daily_average: amount,
+                status,
+            }
+        })
+        .collect();
+
+    Ok(NutritionSummary {
+        user_id,
+        period,
+        period_start,
+        period_end,
+        days_counted,
+        meals_logged,
+        totals,
+        daily_average,
+        targets,
+    })
+}
+
+// Helper to find when the local day, or the week starting Monday, containing a
+// timestamp begins
+fn local_period_start(date: u64, utc_offset_minutes: i32, period: SummaryPeriod) -> u64 {
+    let offset = utc_offset_minutes as i64 * NANOS_PER_MINUTE as i64;
+    let local_date = date as i64 + offset;
+    let local_day_start = local_date - local_date.rem_euclid(NANOS_PER_DAY as i64);
+    let local_period_start = match period {
+        SummaryPeriod::Daily => local_day_start,
+        SummaryPeriod::Weekly => {
+            let days_since_monday = ALL_WEEKDAYS
+                .iter()
+                .position(|day| *day == weekday_of(local_date))
+                .unwrap_or(0) as i64;
+            local_day_start - days_since_monday * NANOS_PER_DAY as i64
+        }
+    };
+    (local_period_start - offset).max(0) as u64
+}
+
+// Helper to read the amount of one nutrient
+fn nutrient_amount(nutrients: &Nutrients, nutrient: Nutrient) -> f64 {
+    match nutrient {
+        Nutrient::Calories => nutrients.calories,
+        Nutrient::Protein => nutrients.protein_grams,
+        Nutrient::Carbohydrate => nutrients.carbohydrate_grams,
+        Nutrient::Fat => nutrients.fat_grams,
+        Nutrient::Sodium => nutrients.sodium_milligrams,
+        Nutrient::Sugar => nutrients.sugar_grams,
+    }
+}
+
+// Function for a linked caregiver or a healthcare provider to plan a week of
+// meals for an elderly user
+#[ic_cdk::update]
+fn create_meal_plan(payload: MealPlanPayload) -> Result<MealPlan, String> {
+    let user = check_diet_manager(payload.user_id, payload.created_by)?;
+    let week_start = local_period_start(
+        payload.week_of,
+        user.utc_offset_minutes.unwrap_or(0),
+        SummaryPeriod::Weekly,
+    );
+
+    let week_planned = MEAL_PLANS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .any(|(_, plan)| plan.user_id == payload.user_id && plan.week_start == week_start)
+    });
+    if week_planned {
+        return Err("The user already has a meal plan for this week.".to_string());
+    }
+
+    let (meals, dietary_conflicts) = build_planned_meals(payload.user_id, payload.meals)?;
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let now = time();
+    let meal_plan = MealPlan {
+        id,
+        user_id: payload.user_id,
+        created_by: payload.created_by,
+        week_start,
+        meals,
+        dietary_conflicts: Some(dietary_conflicts.clone()),
+        created_at: now,
+        updated_at: now,
+    };
+
+    MEAL_PLANS_STORAGE.with(|storage| storage.borrow_mut().insert(id, meal_plan.clone()));
+    flag_for_diet_review(
+        payload.user_id,
+        DietEntryType::MealPlan,
+        id,
+        &dietary_conflicts,
+    );
+    Ok(meal_plan)
+}
+
+// Function to replace the planned meals of a meal plan
+#[ic_cdk::update]
+fn update_meal_plan(meal_plan_id: u64, payload: MealPlanPayload) -> Result<MealPlan, String> {
+    let mut meal_plan = MEAL_PLANS_STORAGE
+        .with(|storage| storage.borrow().get(&meal_plan_id))
+        .ok_or("Meal plan ID does not exist.".to_string())?;
+    if payload.user_id != meal_plan.user_id {
+        return Err("A meal plan cannot be moved to another user.".to_string());
+    }
+    check_diet_manager(meal_plan.user_id, payload.created_by)?;
+
+    let (meals, dietary_conflicts) = build_planned_meals(meal_plan.user_id, payload.meals)?;
+    meal_plan.meals = meals;
+    meal_plan.dietary_conflicts = Some(dietary_conflicts.clone());
+    meal_plan.updated_at = time();
+
+    MEAL_PLANS_STORAGE.with(|storage| storage.borrow_mut().insert(meal_plan_id, meal_plan.clone()));
+    close_diet_review_flags(
+        DietEntryType::MealPlan,
+        meal_plan_id,
+        "Superseded by an update to the meal plan.",
+    );
+    flag_for_diet_review(
+        meal_plan.user_id,
+        DietEntryType::MealPlan,
+        meal_plan_id,
+        &dietary_conflicts,
+    );
+    Ok(meal_plan)
+}
+
+// Function to retrieve the meal plans of a user
+#[ic_cdk::query]
+fn get_meal_plans_by_user_id(user_id: u64) -> Result<Vec<MealPlan>, String> {
+    let mut records: Vec<MealPlan> = MEAL_PLANS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id)
+            .map(|(_, record)| record)
+            .collect()
+    });
+    if records.is_empty() {
+        return Err("No meal plans found for the user.".to_string());
+    }
+    records.sort_by_key(|record| record.week_start);
+    Ok(records)
+}
+
+// Function to compare the diet records logged during a meal plan's week with
+// the plan. A planned meal is followed when the same catalogue items were
+// logged for that day and meal type, substituted when different items were
+// logged, and missed when nothing was logged by the end of the day.
+#[ic_cdk::query]
+fn get_meal_plan_compliance(meal_plan_id: u64) -> Result<MealPlanCompliance, String> {
+    let meal_plan = MEAL_PLANS_STORAGE
+        .with(|storage| storage.borrow().get(&meal_plan_id))
+        .ok_or("Meal plan ID does not exist.".to_string())?;
+    let now = time();
+
+    let diet_records: Vec<DietRecord> = DIET_RECORDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| {
+                record.user_id == meal_plan.user_id
+                    && record.recorded_at >= meal_plan.week_start
+                    && record.recorded_at < meal_plan.week_start + 7 * NANOS_PER_DAY
+            })
+            .map(|(_, record)| record)
+            .collect()
+    });
+
+    let mut compliance = MealPlanCompliance {
+        meal_plan_id,
+        user_id: meal_plan.user_id,
+        week_start: meal_plan.week_start,
+        meals_planned: meal_plan.meals.len() as u32,
+        meals_followed: 0,
+        meals_substituted: 0,
+        meals_missed: 0,
+        meals_pending: 0,
+        compliance_percent: 0,
+        missed_meals: Vec::new(),
+        substitutions: Vec::new(),
+    };
+
+    for planned_meal in &meal_plan.meals {
+        let day_index = ALL_WEEKDAYS
+            .iter()
+            .position(|day| *day == planned_meal.day)
+            .unwrap_or(0) as u64;
+        let day_start = meal_plan.week_start + day_index * NANOS_PER_DAY;
+        let day_end = day_start + NANOS_PER_DAY;
+
+        let eaten: Vec<&DietRecordItem> = diet_records
+            .iter()
+            .filter(|record| {
+                record.meal_type == planned_meal.meal_type
+                    && record.recorded_at >= day_start
+                    && record.recorded_at < day_end
+            })
+            .flat_map(|record| record.items.iter())
+            .collect();
+
+        if eaten.is_empty() {
+            if day_end <= now {
+                compliance.meals_missed += 1;
+                compliance.missed_meals.push(MealSlot {
+                    day: planned_meal.day,
+                    meal_type: planned_meal.meal_type,
+                });
+            } else {
+                compliance.meals_pending += 1;
+            }
+            continue;
+        }
+
+        let mut skipped_items: Vec<String> = planned_meal
+            .items
+            .iter()
+            .filter(|planned| {
+                !eaten
+                    .iter()
+                    .any(|item| item.food_item_id == planned.food_item_id)
+            })
+            .map(|planned| planned.food_name.clone())
+            .collect();
+        let mut substituted_items: Vec<String> = eaten
+            .iter()
+            .filter(|item| {
+                !planned_meal
+                    .items
+                    .iter()
+                    .any(|planned| planned.food_item_id == item.food_item_id)
+            })
+            .map(|item| item.food_name.clone())
+            .collect();
+        skipped_items.sort();
+        skipped_items.dedup();
+        substituted_items.sort();
+        substituted_items.dedup();
+
+        if skipped_items.is_empty() && substituted_items.is_empty() {
+            compliance.meals_followed += 1;
+        } else {
+            compliance.meals_substituted += 1;
+            compliance.substitutions.push(MealSubstitution {
+                day: planned_meal.day,
+                meal_type: planned_meal.meal_type,
+                skipped_items,
+                substituted_items,
+            });
+        }
+    }
+
+    compliance.compliance_percent = percent(
+        compliance.meals_followed,
+        compliance.meals_planned - compliance.meals_pending,
+    );
+    Ok(compliance)
+}
+
+// Helper to check that a meal plan or dietary restriction is for an elderly
+// user and written by a linked caregiver or a healthcare provider acting as the
+// caller; returns the elderly user
+fn check_diet_manager(user_id: u64, author_id: u64) -> Result<User, String> {
+    let user = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .ok_or("User ID does not exist.".to_string())?;
+    if user.user_type != UserType::Elderly {
+        return Err("Diets can only be managed for elderly users.".to_string());
+    }
+
+    let author = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&author_id))
+        .ok_or("Author ID does not exist.".to_string())?;
+    let allowed = match author.user_type {
+        UserType::HealthcareProvider => true,
+        UserType::Caregiver => is_linked_caregiver(user_id, author_id),
+        UserType::Elderly => false,
+    };
+    if !allowed {
+        return Err(
+            "Only a linked caregiver or a healthcare provider can manage this user's diet."
+                .to_string(),
+        );
+    }
+    check_caller_is_user(author_id)?;
+    Ok(user)
+}
+
+// Helper to resolve planned meals against the food catalogue and check them
+// against the user's dietary restrictions; each day and meal type can be
+// planned once
+fn build_planned_meals(
+    user_id: u64,
+    meals: Vec<PlannedMealPayload>,
+) -> Result<(Vec<PlannedMeal>, Vec<DietaryConflict>), String> {
+    if meals.is_empty() {
+        return Err("A meal plan must include at least one meal.".to_string());
+    }
+
+    let mut planned_meals: Vec<PlannedMeal> = Vec::new();
+    let mut dietary_conflicts = Vec::new();
+    for meal in meals {
+        if planned_meals
+            .iter()
+            .any(|planned| planned.day == meal.day && planned.meal_type == meal.meal_type)
+        {
+            return Err(format!(
+                "{:?} {:?} is planned more than once.",
+                meal.day, meal.meal_type
+            ));
+        }
+        if meal.items.is_empty() || meal.items.len() > MAX_PLANNED_MEAL_ITEMS {
+            return Err(format!(
+                "{:?} {:?} must include between 1 and {} items.",
+                meal.day, meal.meal_type, MAX_PLANNED_MEAL_ITEMS
+            ));
+        }
+
+        let mut items = Vec::new();
+        let mut foods = Vec::new();
+        for item in meal.items {
+            validate_servings(item.servings)?;
+            let food_item = FOOD_ITEMS_STORAGE
+                .with(|storage| storage.borrow().get(&item.food_item_id))
+                .ok_or(format!(
+                    "Food item ID {} does not exist.",
+                    item.food_item_id
+                ))?;
+            items.push(PlannedMealItem {
+                food_item_id: food_item.id,
+                food_name: food_item.name.clone(),
+                servings: item.servings,
+            });
+            foods.push((food_item, item.servings));
+        }
+
+        dietary_conflicts.extend(check_dietary_restrictions(
+            user_id,
+            &format!("{:?} {:?}", meal.day, meal.meal_type),
+            &foods,
+        ));
+        planned_meals.push(PlannedMeal {
+            day: meal.day,
+            meal_type: meal.meal_type,
+            items,
+        });
+    }
+    Ok((planned_meals, cap_dietary_conflicts(dietary_conflicts)))
+}
+
+// Function for a linked caregiver or a healthcare provider to set a user's
+// dietary restrictions, replacing any previous ones
+#[ic_cdk::update]
+fn set_dietary_restrictions(
+    payload: DietaryRestrictionsPayload,
+) -> Result<DietaryRestrictions, String> {
+    check_diet_manager(payload.user_id, payload.set_by)?;
+
+    if let Some(level) = payload.max_food_texture {
+        if iddsi_number(level) < 3 {
+            return Err("Food textures range from IDDSI level 3 to 7.".to_string());
+        }
+    }
+    if let Some(level) = payload.min_drink_thickness {
+        if iddsi_number(level) > 4 {
+            return Err("Drink thickness ranges from IDDSI level 0 to 4.".to_string());
+        }
+    }
+
+    let mut diets: Vec<DietType> = Vec::new();
+    for diet in payload.diets {
+        if !diets.contains(&diet) {
+            diets.push(diet);
+        }
+    }
+
+    let restrictions = DietaryRestrictions {
+        user_id: payload.user_id,
+        diets,
+        max_food_texture: payload.max_food_texture,
+        min_drink_thickness: payload.min_drink_thickness,
+        set_by: payload.set_by,
+        updated_at: time(),
+    };
+
+    DIETARY_RESTRICTIONS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(payload.user_id, restrictions.clone())
+    });
+    Ok(restrictions)
+}
+
+// Function to retrieve a user's dietary restrictions
+#[ic_cdk::query]
+fn get_dietary_restrictions(user_id: u64) -> Result<DietaryRestrictions, String> {
+    DIETARY_RESTRICTIONS_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .ok_or("No dietary restrictions found for the user.".to_string())
+}
+
+// Function to retrieve a user's diet entries flagged for review, unreviewed first
+#[ic_cdk::query]
+fn get_diet_review_flags_by_user_id(user_id: u64) -> Result<Vec<DietReviewFlag>, String> {
+    let mut records: Vec<DietReviewFlag> = DIET_REVIEW_FLAGS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id)
+            .map(|(_, record)| record)
+            .collect()
+    });
+    if records.is_empty() {
+        return Err("No flagged diet entries found for the user.".to_string());
+    }
+    records.sort_by_key(|record| (record.reviewed_at.is_some(), record.created_at));
+    Ok(records)
+}
+
+// Function for a linked caregiver or a healthcare provider to review a flagged diet entry
+#[ic_cdk::update]
+fn review_diet_flag(flag_id: u64, payload: DietReviewPayload) -> Result<DietReviewFlag, String> {
+    let mut flag = DIET_REVIEW_FLAGS_STORAGE
+        .with(|storage| storage.borrow().get(&flag_id))
+        .ok_or("Diet review flag ID does not exist.".to_string())?;
+    check_diet_manager(flag.user_id, payload.reviewer_id)?;
+    if flag.reviewed_at.is_some() {
+        return Err("This diet entry has already been reviewed.".to_string());
+    }
+    validate_status_reason(&payload.note)?;
+
+    flag.reviewed_by = Some(payload.reviewer_id);
+    flag.review_note = payload.note;
+    flag.reviewed_at = Some(time());
+
+    DIET_REVIEW_FLAGS_STORAGE.with(|storage| storage.borrow_mut().insert(flag_id, flag.clone()));
+    Ok(flag)
+}
+
+// Helper to check the foods of one meal against a user's dietary restrictions;
+// `meal` names the meal in the conflict details
+fn check_dietary_restrictions(
+    user_id: u64,
+    meal: &str,
+    foods: &[(FoodItem, f64)],
+) -> Vec<DietaryConflict> {
+    let Some(restrictions) =
+        DIETARY_RESTRICTIONS_STORAGE.with(|storage| storage.borrow().get(&user_id))
+    else {
+        return Vec::new();
+    };
+    let mut conflicts = Vec::new();
+
+    for (food_item, _) in foods {
+        // Items added before the catalogue recorded IDDSI levels are not checked
+        let Some(iddsi_level) = food_item.iddsi_level else {
+            continue;
+        };
+        let level = iddsi_number(iddsi_level);
+        match (
+            food_item.is_drink.unwrap_or(false),
+            restrictions.max_food_texture,
+            restrictions.min_drink_thickness,
+        ) {
+            (false, Some(max_texture), _) if level > iddsi_number(max_texture) => {
+                conflicts.push(DietaryConflict {
+                    restriction: "Food texture".to_string(),
+                    detail: format!(
+                        "{}: {} is IDDSI level {}, above the allowed level {}.",
+                        meal,
+                        food_item.name,
+                        level,
+                        iddsi_number(max_texture)
+                    ),
+                })
+            }
+            (true, _, Some(min_thickness)) if level < iddsi_number(min_thickness) => conflicts
+                .push(DietaryConflict {
+                    restriction: "Drink thickness".to_string(),
+                    detail: format!(
+                        "{}: {} is IDDSI level {}, thinner than the required level {}.",
+                        meal,
+                        food_item.name,
+                        level,
+                        iddsi_number(min_thickness)
+                    ),
+                }),
+            _ => {}
+        }
+    }
+
+    let totals = foods
+        .iter()
+        .fold(Nutrients::default(), |total, (food_item, servings)| {
+            add_nutrients(
+                &total,
+                &scale_nutrients(&food_item.nutrients_per_serving, *servings),
+            )
+        });
+    let limits: Vec<(DietType, &str, f64, f64, &str)> = restrictions
+        .diets
+        .iter()
+        .flat_map(|diet| match diet {
+            DietType::Diabetic => vec![(
+                *diet,
+                "sugar",
+                totals.sugar_grams,
+                DIABETIC_MAX_SUGAR_GRAMS_PER_MEAL,
+                "g",
+            )],
+            DietType::LowSodium => vec![(
+                *diet,
+                "sodium",
+                totals.sodium_milligrams,
+                LOW_SODIUM_MAX_SODIUM_MILLIGRAMS_PER_MEAL,
+                "mg",
+            )],
+            DietType::Renal => vec![
+                (
+                    *diet,
+                    "sodium",
+                    totals.sodium_milligrams,
+                    RENAL_MAX_SODIUM_MILLIGRAMS_PER_MEAL,
+                    "mg",
+                ),
+                (
+                    *diet,
+                    "protein",
+                    totals.protein_grams,
+                    RENAL_MAX_PROTEIN_GRAMS_PER_MEAL,
+                    "g",
+                ),
+            ],
+        })
+        .collect();
+    for (diet, nutrient, amount, limit, unit) in limits {
+        if amount > limit {
+            conflicts.push(DietaryConflict {
+                restriction: format!("{:?} diet", diet),
+                detail: format!(
+                    "{}: {:.0} {} of {}, above the {:.0} {} allowed per meal.",
+                    meal, amount, unit, nutrient, limit, unit
+                ),
+            });
+        }
+    }
+    conflicts
+}
+
+// Helper to flag a conflicting diet entry for review and let the user's caregivers know
+fn flag_for_diet_review(
+    user_id: u64,
+    entry_type: DietEntryType,
+    entry_id: u64,
+    conflicts: &[DietaryConflict],
+) {
+    if conflicts.is_empty() {
+        return;
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let flag = DietReviewFlag {
+        id,
+        user_id,
+        entry_type,
+        entry_id,
+        conflicts: conflicts.to_vec(),
+        reviewed_by: None,
+        review_note: String::new(),
+        reviewed_at: None,
+        created_at: time(),
+    };
+    DIET_REVIEW_FLAGS_STORAGE.with(|storage| storage.borrow_mut().insert(id, flag));
+
+    let mut restrictions: Vec<&str> = Vec::new();
+    for conflict in conflicts {
+        if !restrictions.contains(&conflict.restriction.as_str()) {
+            restrictions.push(&conflict.restriction);
+        }
+    }
+    notify_user_and_caregivers(
+        user_id,
+        NotificationType::DietReview,
+        Some(id),
+        format!(
+            "{:?} {} has {} dietary conflict(s) ({}) and needs review.",
+            entry_type,
+            entry_id,
+            conflicts.len(),
+            restrictions.join(", ")
+        ),
+    );
+}
+
+// Helper to close the unreviewed flags of a diet entry once they no longer apply
+fn close_diet_review_flags(entry_type: DietEntryType, entry_id: u64, note: &str) {
+    let open_flags: Vec<DietReviewFlag> = DIET_REVIEW_FLAGS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, flag)| {
+                flag.entry_type == entry_type
+                    && flag.entry_id == entry_id
+                    && flag.reviewed_at.is_none()
+            })
+            .map(|(_, flag)| flag)
+            .collect()
+    });
+    let now = time();
+    for mut flag in open_flags {
+        flag.review_note = note.to_string();
+        flag.reviewed_at = Some(now);
+        DIET_REVIEW_FLAGS_STORAGE.with(|storage| storage.borrow_mut().insert(flag.id, flag));
+    }
+}
+
+// Helper to keep at most MAX_DIETARY_CONFLICTS conflicts, replacing the last
+// one kept with a count of those left out
+fn cap_dietary_conflicts(mut conflicts: Vec<DietaryConflict>) -> Vec<DietaryConflict> {
+    if conflicts.len() > MAX_DIETARY_CONFLICTS {
+        let left_out = conflicts.len() - (MAX_DIETARY_CONFLICTS - 1);
+        conflicts.truncate(MAX_DIETARY_CONFLICTS - 1);
+        conflicts.push(DietaryConflict {
+            restriction: "Other conflicts".to_string(),
+            detail: format!("{} more conflicts are not listed.", left_out),
+        });
+    }
+    conflicts
+}
+
+// Helper to give the number of an IDDSI level
+fn iddsi_number(level: IddsiLevel) -> u8 {
+    match level {
+        IddsiLevel::Thin => 0,
+        IddsiLevel::SlightlyThick => 1,
+        IddsiLevel::MildlyThick => 2,
+        IddsiLevel::ModeratelyThick => 3,
+        IddsiLevel::ExtremelyThick => 4,
+        IddsiLevel::MincedAndMoist => 5,
+        IddsiLevel::SoftAndBiteSized => 6,
+        IddsiLevel::Regular => 7,
+    }
+}
+
+// Function to record a user's weight, by the user, a linked caregiver or a
+// healthcare provider, raising an alert on a rapid gain
+#[ic_cdk::update]
+fn record_weight(payload: WeightEntryPayload) -> Result<WeightEntry, String> {
+    check_measurement_recorder(payload.user_id, payload.recorded_by, "record weight")?;
+    validate_weight(payload.weight_kg)?;
+
+    let measured_at = payload.measured_at.unwrap_or(time());
+    if measured_at > time() {
+        return Err("Weight cannot be recorded in the future.".to_string());
+    }
+
+    Ok(insert_weight_entry(
+        payload.user_id,
+        payload.recorded_by,
+        payload.weight_kg,
+        measured_at,
+    ))
+}
+
+// Function to retrieve a user's weight entries with BMI, oldest first
+#[ic_cdk::query]
+fn get_weight_history_by_user_id(user_id: u64) -> Result<Vec<WeightEntry>, String> {
+    let mut entries: Vec<WeightEntry> = WEIGHT_ENTRIES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, entry)| entry.user_id == user_id)
+            .map(|(_, entry)| entry)
+            .collect()
+    });
+    if entries.is_empty() {
+        return Err("No weight entries found for the user.".to_string());
+    }
+    entries.sort_by_key(|entry| entry.measured_at);
+    Ok(entries)
+}
+
+// Helper to store a weight entry, keep the profile's latest weight current and
+// apply the rapid weight gain rule
+fn insert_weight_entry(
+    user_id: u64,
+    recorded_by: u64,
+    weight_kg: f64,
+    measured_at: u64,
+) -> WeightEntry {
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let mut user = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .expect("User ID does not exist.");
+
+    // Weighings within the window on either side of this one, so a back-dated
+    // low reading is compared with the weighings that followed it
+    let window = WEIGHT_GAIN_WINDOW_DAYS * NANOS_PER_DAY;
+    let window_start = measured_at.saturating_sub(window);
+    let window_end = measured_at.saturating_add(window);
+    let window_entries: Vec<WeightEntry> = WEIGHT_ENTRIES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, entry)| {
+                entry.user_id == user_id
+                    && entry.measured_at >= window_start
+                    && entry.measured_at <= window_end
+            })
+            .map(|(_, entry)| entry)
+            .collect()
+    });
+
+    let alert_id = find_rapid_weight_gain(&window_entries, weight_kg, measured_at).map(|gain| {
+        let days = (gain.to_at - gain.from_at) as f64 / NANOS_PER_DAY as f64;
+        let alert = raise_alert(
+            user_id,
+            AlertType::RapidWeightGain,
+            format!(
+                "Weight rose by {:.1} kg in {:.1} days ({:.1} kg to {:.1} kg), above the {} kg in {} days threshold. Check for signs of fluid retention.",
+                gain.to_kg - gain.from_kg,
+                days,
+                gain.from_kg,
+                gain.to_kg,
+                WEIGHT_GAIN_ALERT_KG,
+                WEIGHT_GAIN_WINDOW_DAYS
+            ),
+        );
+        alert.id
+    });
+
+    let entry = WeightEntry {
+        id,
+        user_id,
+        weight_kg,
+        bmi: user.height_cm.map(|height_cm| bmi(weight_kg, height_cm)),
+        alert_id,
+        recorded_by,
+        measured_at,
+        created_at: time(),
+    };
+
+    WEIGHT_ENTRIES_STORAGE.with(|storage| storage.borrow_mut().insert(id, entry.clone()));
+
+    // The profile keeps the most recent weighing, which a back-dated entry may not be
+    let is_latest = WEIGHT_ENTRIES_STORAGE.with(|storage| {
+        !storage
+            .borrow()
+            .iter()
+            .any(|(_, other)| other.user_id == user_id && other.measured_at > measured_at)
+    });
+    if is_latest {
+        user.weight_kg = Some(weight_kg);
+        USERS_STORAGE.with(|storage| storage.borrow_mut().insert(user_id, user));
+    }
+
+    entry
+}
+
+// Helper with the rapid weight gain rule for a new weighing: finds the largest
+// gain above WEIGHT_GAIN_ALERT_KG from an earlier weighing to this one, or from
+// this one to a later weighing, within WEIGHT_GAIN_WINDOW_DAYS. Only one alert
+// is raised per episode, so nothing is found if a weighing in the window on
+// either side has already raised one.
+fn find_rapid_weight_gain(
+    entries: &[WeightEntry],
+    weight_kg: f64,
+    measured_at: u64,
+) -> Option<WeightGain> {
+    let window = WEIGHT_GAIN_WINDOW_DAYS * NANOS_PER_DAY;
+    let in_window: Vec<&WeightEntry> = entries
+        .iter()
+        .filter(|entry| entry.measured_at.abs_diff(measured_at) <= window)
+        .collect();
+    if in_window.iter().any(|entry| entry.alert_id.is_some()) {
+        return None;
+    }
+
+    in_window
+        .iter()
+        .map(|entry| {
+            if entry.measured_at <= measured_at {
+                WeightGain {
+                    from_kg: entry.weight_kg,
+                    from_at: entry.measured_at,
+                    to_kg: weight_kg,
+                    to_at: measured_at,
+                }
+            } else {
+                WeightGain {
+                    from_kg: weight_kg,
+                    from_at: measured_at,
+                    to_kg: entry.weight_kg,
+                    to_at: entry.measured_at,
+                }
+            }
+        })
+        .filter(|gain| gain.to_kg - gain.from_kg > WEIGHT_GAIN_ALERT_KG)
+        .max_by(|a, b| (a.to_kg - a.from_kg).total_cmp(&(b.to_kg - b.from_kg)))
+}
+
+// Helper to compute body mass index, rounded to one decimal place
+fn bmi(weight_kg: f64, height_cm: f64) -> f64 {
+    let height_m = height_cm / 100.0;
+    (weight_kg / (height_m * height_m) * 10.0).round() / 10.0
+}
+
+// Helper to check that a measurement is recorded by the user themselves, a
+// linked caregiver or a healthcare provider acting as the caller
+fn check_measurement_recorder(user_id: u64, recorded_by: u64, action: &str) -> Result<(), String> {
+    let user_exists = USERS_STORAGE.with(|storage| storage.borrow().contains_key(&user_id));
+    if !user_exists {
+        return Err("User ID does not exist.".to_string());
+    }
+    if recorded_by != user_id {
+        let recorder = USERS_STORAGE
+            .with(|storage| storage.borrow().get(&recorded_by))
+            .ok_or("Recorder ID does not exist.".to_string())?;
+        let allowed = match recorder.user_type {
+            UserType::HealthcareProvider => true,
+            UserType::Caregiver => is_linked_caregiver(user_id, recorded_by),
+            UserType::Elderly => false,
+        };
+        if !allowed {
+            return Err(format!(
+                "Only the user, a linked caregiver or a healthcare provider can {}.",
+                action
+            ));
+        }
+    }
+    check_caller_is_user(recorded_by)?;
+    Ok(())
+}
+
+// Function to log a drink taken by a user, by the user, a linked caregiver or a
+// healthcare provider
+#[ic_cdk::update]
+fn log_fluid_intake(payload: FluidIntakePayload) -> Result<FluidIntake, String> {
+    check_measurement_recorder(payload.user_id, payload.recorded_by, "log fluid intake")?;
+
+    if payload.volume_ml == 0 || payload.volume_ml > MAX_FLUID_INTAKE_ML {
+        return Err(format!(
+            "Volume must be between 1 and {} ml.",
+            MAX_FLUID_INTAKE_ML
+        ));
+    }
+    let now = time();
+    let taken_at = payload.taken_at.unwrap_or(now);
+    if taken_at > now {
+        return Err("Fluid intake cannot be logged in the future.".to_string());
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let intake = FluidIntake {
+        id,
+        user_id: payload.user_id,
+        beverage_type: payload.beverage_type,
+        volume_ml: payload.volume_ml,
+        recorded_by: payload.recorded_by,
+        taken_at,
+        created_at: now,
+    };
+
+    FLUID_INTAKE_STORAGE.with(|storage| storage.borrow_mut().insert(id, intake.clone()));
+    Ok(intake)
+}
+
+// Function for a linked caregiver or a healthcare provider to set a user's
+// daily fluid goal and the pace it is checked against
+#[ic_cdk::update]
+fn set_hydration_goal(payload: HydrationGoalPayload) -> Result<HydrationGoal, String> {
+    check_diet_manager(payload.user_id, payload.set_by)?;
+
+    if payload.daily_goal_ml == 0 || payload.daily_goal_ml > MAX_DAILY_FLUID_GOAL_ML {
+        return Err(format!(
+            "Daily fluid goal must be between 1 and {} ml.",
+            MAX_DAILY_FLUID_GOAL_ML
+        ));
+    }
+    if payload.check_time.hour >= 24 || payload.check_time.minute >= 60 {
+        return Err("Times of day must be between 00:00 and 23:59.".to_string());
+    }
+    if !(1..=100).contains(&payload.expected_percent_by_check_time) {
+        return Err("Expected percentage must be between 1 and 100.".to_string());
+    }
+
+    // Changing the goal keeps today's check from running twice, and a new goal
+    // is first checked tomorrow rather than against intake logged before it
+    let last_checked_day =
+        match HYDRATION_GOALS_STORAGE.with(|storage| storage.borrow().get(&payload.user_id)) {
+            Some(goal) => goal.last_checked_day,
+            None => {
+                let user = USERS_STORAGE
+                    .with(|storage| storage.borrow().get(&payload.user_id))
+                    .ok_or("User ID does not exist.".to_string())?;
+                local_period_start(
+                    time(),
+                    user.utc_offset_minutes.unwrap_or(0),
+                    SummaryPeriod::Daily,
+                )
+            }
+        };
+
+    let goal = HydrationGoal {
+        user_id: payload.user_id,
+        daily_goal_ml: payload.daily_goal_ml,
+        check_time: payload.check_time,
+        expected_percent_by_check_time: payload.expected_percent_by_check_time,
+        last_checked_day,
+        set_by: payload.set_by,
+        updated_at: time(),
+    };
+
+    HYDRATION_GOALS_STORAGE
+        .with(|storage| storage.borrow_mut().insert(payload.user_id, goal.clone()));
+    Ok(goal)
+}
+
+// Function to retrieve a user's hydration goal
+#[ic_cdk::query]
+fn get_hydration_goal(user_id: u64) -> Result<HydrationGoal, String> {
+    HYDRATION_GOALS_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .ok_or("No hydration goal found for the user.".to_string())
+}
+
+// Function to total a user's fluid intake for the local day containing `date`
+#[ic_cdk::query]
+fn get_daily_hydration(user_id: u64, date: u64) -> Result<DailyHydration, String> {
+    let user = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .ok_or("User ID does not exist.".to_string())?;
+    let day_start = local_period_start(
+        date,
+        user.utc_offset_minutes.unwrap_or(0),
+        SummaryPeriod::Daily,
+    );
+
+    let mut intakes = fluid_intakes_between(user_id, day_start, day_start + NANOS_PER_DAY);
+    intakes.sort_by_key(|intake| intake.taken_at);
+    let total_ml = intakes
+        .iter()
+        .fold(0u32, |total, intake| total.saturating_add(intake.volume_ml));
+
+    let mut by_beverage: Vec<BeverageTotal> = Vec::new();
+    for intake in &intakes {
+        match by_beverage
+            .iter_mut()
+            .find(|total| total.beverage_type == intake.beverage_type)
+        {
+            Some(total) => total.volume_ml = total.volume_ml.saturating_add(intake.volume_ml),
+            None => by_beverage.push(BeverageTotal {
+                beverage_type: intake.beverage_type,
+                volume_ml: intake.volume_ml,
+            }),
+        }
+    }
+
+    let goal_ml = HYDRATION_GOALS_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .map(|goal| goal.daily_goal_ml);
+
+    Ok(DailyHydration {
+        user_id,
+        day_start,
+        total_ml,
+        goal_ml,
+        percent_of_goal: goal_ml.map(|goal_ml| percent(total_ml, goal_ml)),
+        by_beverage,
+        intakes,
+    })
+}
+
+// Timer job: once a day, after each user's check time, alert the user and
+// their caregivers if fluid intake so far is behind the expected pace
+fn check_hydration_pace() {
+    let now = time();
+    let goals: Vec<HydrationGoal> = HYDRATION_GOALS_STORAGE
+        .with(|storage| storage.borrow().iter().map(|(_, goal)| goal).collect());
+
+    for mut goal in goals {
+        let Some(user) = USERS_STORAGE.with(|storage| storage.borrow().get(&goal.user_id)) else {
+            continue;
+        };
+        let day_start = local_period_start(
+            now,
+            user.utc_offset_minutes.unwrap_or(0),
+            SummaryPeriod::Daily,
+        );
+        if !is_hydration_check_due(&goal, day_start, now) {
+            continue;
+        }
+
+        let total_ml: u64 =
+            fluid_intakes_between(goal.user_id, day_start, day_start + NANOS_PER_DAY)
+                .iter()
+                .map(|intake| intake.volume_ml as u64)
+                .sum();
+        if let Some(expected_ml) = hydration_shortfall(&goal, total_ml) {
+            raise_alert(
+                goal.user_id,
+                AlertType::LowFluidIntake,
+                format!(
+                    "Only {} ml of fluid taken today, below the {} ml expected by {:02}:{:02} ({}% of the {} ml daily goal).",
+                    total_ml,
+                    expected_ml,
+                    goal.check_time.hour,
+                    goal.check_time.minute,
+                    goal.expected_percent_by_check_time,
+                    goal.daily_goal_ml
+                ),
+            );
+        }
+
+        goal.last_checked_day = day_start;
+        HYDRATION_GOALS_STORAGE.with(|storage| storage.borrow_mut().insert(goal.user_id, goal));
+    }
+}
+
+// Helper to check whether a goal's check for the local day starting at
+// `day_start` is due: the check time has passed and the day is not yet checked
+fn is_hydration_check_due(goal: &HydrationGoal, day_start: u64, now: u64) -> bool {
+    let check_at = day_start
+        + goal.check_time.hour as u64 * NANOS_PER_HOUR
+        + goal.check_time.minute as u64 * NANOS_PER_MINUTE;
+    now >= check_at && goal.last_checked_day < day_start
+}
+
+// Helper with the pace rule: gives the intake expected by the check time when
+// less than that has been taken
+fn hydration_shortfall(goal: &HydrationGoal, total_ml: u64) -> Option<u64> {
+    let expected_ml = goal.daily_goal_ml as u64 * goal.expected_percent_by_check_time as u64 / 100;
+    (total_ml < expected_ml).then_some(expected_ml)
+}
+
+// Helper to list a user's fluid intakes taken within [from, to)
+fn fluid_intakes_between(user_id: u64, from: u64, to: u64) -> Vec<FluidIntake> {
+    FLUID_INTAKE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, intake)| {
+                intake.user_id == user_id && intake.taken_at >= from && intake.taken_at < to
+            })
+            .map(|(_, intake)| intake)
+            .collect()
+    })
+}
+
+// Helper to multiply nutrient amounts by a number of servings
+fn scale_nutrients(nutrients: &Nutrients, servings: f64) -> Nutrients {
+    Nutrients {
+        calories: nutrients.calories * servings,
+        protein_grams: nutrients.protein_grams * servings,
+        carbohydrate_grams: nutrients.carbohydrate_grams * servings,
+        fat_grams: nutrients.fat_grams * servings,
+        sodium_milligrams: nutrients.sodium_milligrams * servings,
+        sugar_grams: nutrients.sugar_grams * servings,
+    }
+}
+
+// Helper to add two sets of nutrient amounts together
+fn add_nutrients(a: &Nutrients, b: &Nutrients) -> Nutrients {
+    Nutrients {
+        calories: a.calories + b.calories,
+        protein_grams: a.protein_grams + b.protein_grams,
+        carbohydrate_grams: a.carbohydrate_grams + b.carbohydrate_grams,
+        fat_grams: a.fat_grams + b.fat_grams,
+        sodium_milligrams: a.sodium_milligrams + b.sodium_milligrams,
+        sugar_grams: a.sugar_grams + b.sugar_grams,
+    }
+}
+
+// Function to create a new exercise recommendation
+#[ic_cdk::update]
+fn create_exercise_recommendation(
+    payload: ExerciseRecommendationPayload,
+) -> Result<ExerciseRecommendation, String> {
+    // Ensure all fields are provided
+    if payload.duration == 0 {
+        return Err("All fields must be provided.".to_string());
+    }
+
+    // Ensure user ID exists
+    let user_exists = USERS_STORAGE.with(|storage| storage.borrow().contains_key(&payload.user_id));
+    if !user_exists {
+        return Err("User ID does not exist.".to_string());
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let recommendation = ExerciseRecommendation {
+        id,
+        user_id: payload.user_id,
+        exercise_type: payload.exercise_type,
+        duration: payload.duration,
+        intensity: payload.intensity,
+        recommended_at: time(),
+    };
+
+    EXERCISE_RECOMMENDATIONS_STORAGE
+        .with(|storage| storage.borrow_mut().insert(id, recommendation.clone()));
+    Ok(recommendation)
+}
+
+// Function to retrieve exercise recommendations by user ID
+#[ic_cdk::query]
+fn get_exercise_recommendations_by_user_id(
+    user_id: u64,
+) -> Result<Vec<ExerciseRecommendation>, String> {
+    EXERCISE_RECOMMENDATIONS_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<ExerciseRecommendation> = stable_btree_map
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id)
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No exercise recommendations found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to retrieve all exercise recommendations
+#[ic_cdk::query]
+fn get_all_exercise_recommendations() -> Result<Vec<ExerciseRecommendation>, String> {
+    EXERCISE_RECOMMENDATIONS_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<ExerciseRecommendation> = stable_btree_map
+            .iter()
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No exercise recommendations found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to create a new mental health record
+#[ic_cdk::update]
+fn create_mental_health_record(
+    payload: MentalHealthRecordPayload,
+) -> Result<MentalHealthRecord, String> {
+    // Ensure all fields are provided
+    if payload.notes.is_empty() {
+        return Err("All fields must be provided.".to_string());
+    }
+
+    // Ensure user ID exists
+    let user_exists = USERS_STORAGE.with(|storage| storage.borrow().contains_key(&payload.user_id));
+    if !user_exists {
+        return Err("User ID does not exist.".to_string());
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let mental_health_record = MentalHealthRecord {
+        id,
+        user_id: payload.user_id,
+        mood: payload.mood,
+        stress_level: payload.stress_level,
+        notes: payload.notes,
+        recorded_at: time(),
+    };
+
+    MENTAL_HEALTH_RECORDS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(id, mental_health_record.clone())
+    });
+    Ok(mental_health_record)
+}
+
+// Function to retrieve mental health records by user ID
+#[ic_cdk::query]
+fn get_mental_health_records_by_user_id(user_id: u64) -> Result<Vec<MentalHealthRecord>, String> {
+    MENTAL_HEALTH_RECORDS_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<MentalHealthRecord> = stable_btree_map
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id)
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No mental health records found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to retrieve all mental health records
+#[ic_cdk::query]
+fn get_all_mental_health_records() -> Result<Vec<MentalHealthRecord>, String> {
+    MENTAL_HEALTH_RECORDS_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<MentalHealthRecord> = stable_btree_map
+            .iter()
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No mental health records found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to create a new fitness challenge
+#[ic_cdk::update]
+fn create_fitness_challenge(payload: FitnessChallengePayload) -> Result<FitnessChallenge, String> {
+    if payload.name.is_empty() || payload.description.is_empty() {
+        return Err("Name and description cannot be empty".to_string());
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let challenge = FitnessChallenge {
+        id,
+        name: payload.name,
+        description: payload.description,
+        start_date: payload.start_date,
+        end_date: payload.end_date,
+        created_at: time(),
+    };
+
+    FITNESS_CHALLENGES_STORAGE.with(|storage| storage.borrow_mut().insert(id, challenge.clone()));
+    Ok(challenge)
+}
+
+// Function to create a new fitness challenge participant
+#[ic_cdk::update]
+fn create_fitness_challenge_participant(
+    payload: FitnessChallengeParticipantPayload,
+) -> Result<FitnessChallengeParticipant, String> {
+    let challenge_exists = FITNESS_CHALLENGES_STORAGE
+        .with(|storage| storage.borrow().contains_key(&payload.challenge_id));
+    if !challenge_exists {
+        return Err("Challenge ID does not exist.".to_string());
+    }
+
+    let user_exists = USERS_STORAGE.with(|storage| storage.borrow().contains_key(&payload.user_id));
+    if !user_exists {
+        return Err("User ID does not exist.".to_string());
+    }
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter");
+
+    let participant = FitnessChallengeParticipant {
+        id,
+        challenge_id: payload.challenge_id,
+        user_id: payload.user_id,
+        progress: payload.progress,
+        updated_at: time(),
+    };
+
+    FITNESS_CHALLENGE_PARTICIPANTS_STORAGE
+        .with(|storage| storage.borrow_mut().insert(id, participant.clone()));
+    Ok(participant)
+}
+
+// Function to retrieve all fitness challenges
+#[ic_cdk::query]
+fn get_all_fitness_challenges() -> Result<Vec<FitnessChallenge>, String> {
+    FITNESS_CHALLENGES_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<FitnessChallenge> = stable_btree_map
+            .iter()
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No fitness challenges found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to retrieve all fitness challenge participants
+#[ic_cdk::query]
+fn get_all_fitness_challenge_participants() -> Result<Vec<FitnessChallengeParticipant>, String> {
+    FITNESS_CHALLENGE_PARTICIPANTS_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<FitnessChallengeParticipant> = stable_btree_map
+            .iter()
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No fitness challenge participants found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to retrieve alerts by user ID
+#[ic_cdk::query]
+fn get_alerts_by_user_id(user_id: u64) -> Result<Vec<Alert>, String> {
+    ALERTS_STORAGE.with(|storage| {
+        let stable_btree_map = &*storage.borrow();
+        let records: Vec<Alert> = stable_btree_map
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id)
+            .map(|(_, record)| record.clone())
+            .collect();
+        if records.is_empty() {
+            Err("No alerts found.".to_string())
+        } else {
+            Ok(records)
+        }
+    })
+}
+
+// Function to acknowledge an alert
+#[ic_cdk::update]
+fn acknowledge_alert(alert_id: u64) -> Result<Alert, String> {
+    ALERTS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let mut alert = storage
+            .get(&alert_id)
+            .ok_or("Alert ID does not exist.".to_string())?;
+        if alert.acknowledged_at.is_some() {
+            return Err("Alert has already been acknowledged.".to_string());
+        }
+        alert.acknowledged_at = Some(time());
+        storage.insert(alert_id, alert.clone());
+        Ok(alert)
+    })
+}
+
+// Helper to store a new alert for a user
+fn raise_alert(user_id: u64, alert_type: AlertType, message: String) -> Alert {
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
         })
         .expect("Cannot increment ID counter");
 
-    let mental_health_record = MentalHealthRecord {
-        id,
-        user_id: payload.user_id,
-        mood: payload.mood,
-        stress_level: payload.stress_level,
-        notes: payload.notes,
-        recorded_at: time(),
-    };
+    let alert = Alert {
+        id,
+        user_id,
+        alert_type,
+        message,
+        acknowledged_at: None,
+        created_at: time(),
+    };
+
+    ALERTS_STORAGE.with(|storage| storage.borrow_mut().insert(id, alert.clone()));
+
+    // Every alert also lands in the inbox of the user and their caregivers
+    notify_user_and_caregivers(
+        user_id,
+        NotificationType::Alert,
+        Some(alert.id),
+        alert.message.clone(),
+    );
+
+    alert
+}
+
+// Number of days the patient summary treats as "recent"
+const SUMMARY_WINDOW_DAYS: u32 = 7;
+
+// Number of meal slots a patient is expected to log per day
+const MEALS_PER_DAY: u32 = 3;
+
+// Function to build a single pre-consultation summary for a patient
+#[ic_cdk::query]
+fn get_patient_summary(user_id: u64, viewer_id: u64) -> Result<PatientSummary, String> {
+    let user = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .ok_or("User ID does not exist.".to_string())?;
+    check_caller_is_user(viewer_id)?;
+    if !can_view_patient_summary(user_id, viewer_id) {
+        return Err("You do not have access to this patient's summary.".to_string());
+    }
+
+    let now = time();
+    let window_start = now.saturating_sub(SUMMARY_WINDOW_DAYS as u64 * NANOS_PER_DAY);
+
+    let latest_health_record = HEALTH_RECORDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id)
+            .map(|(_, record)| record)
+            .max_by_key(|record| record.recorded_at)
+    });
+
+    let active_medications: Vec<MedicationReminder> =
+        MEDICATION_REMINDERS_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .filter(|(_, record)| record.user_id == user_id && reminder_is_active(record, now))
+                .map(|(_, record)| record)
+                .collect()
+        });
+
+    let mut upcoming_consultations: Vec<VirtualConsultation> =
+        VIRTUAL_CONSULTATIONS_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .filter(|(_, record)| {
+                    record.user_id == user_id
+                        && record.scheduled_at >= now
+                        && matches!(
+                            record.status,
+                            ConsultationStatus::Requested | ConsultationStatus::Confirmed
+                        )
+                })
+                .map(|(_, record)| record)
+                .collect()
+        });
+    upcoming_consultations.sort_by_key(|record| record.scheduled_at);
+
+    let mut recent_mental_health_records: Vec<MentalHealthRecord> = MENTAL_HEALTH_RECORDS_STORAGE
+        .with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .filter(|(_, record)| {
+                    record.user_id == user_id && record.recorded_at >= window_start
+                })
+                .map(|(_, record)| record)
+                .collect()
+        });
+    recent_mental_health_records.sort_by_key(|record| std::cmp::Reverse(record.recorded_at));
+
+    let (meals_logged, total_calories) = DIET_RECORDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id && record.recorded_at >= window_start)
+            .fold((0u32, 0.0), |(meals, calories), (_, record)| {
+                (meals + 1, calories + record.nutrients.calories)
+            })
+    });
+    let meals_expected = MEALS_PER_DAY * SUMMARY_WINDOW_DAYS;
+    let diet_adherence = DietAdherence {
+        meals_logged,
+        meals_expected,
+        adherence_percent: (meals_logged * 100 / meals_expected).min(100),
+        average_daily_calories: (total_calories / SUMMARY_WINDOW_DAYS as f64).round() as u32,
+    };
+
+    let (recommendations, recommended_minutes) = EXERCISE_RECOMMENDATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| {
+                record.user_id == user_id && record.recommended_at >= window_start
+            })
+            .fold((0u32, 0u32), |(count, minutes), (_, record)| {
+                (count + 1, minutes.saturating_add(record.duration))
+            })
+    });
+    let active_challenges = FITNESS_CHALLENGE_PARTICIPANTS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, participant)| participant.user_id == user_id)
+            .filter(|(_, participant)| {
+                FITNESS_CHALLENGES_STORAGE.with(|challenges| {
+                    challenges
+                        .borrow()
+                        .get(&participant.challenge_id)
+                        .is_some_and(|challenge| {
+                            challenge.start_date <= now && now <= challenge.end_date
+                        })
+                })
+            })
+            .count() as u32
+    });
+    let exercise_adherence = ExerciseAdherence {
+        recommendations,
+        recommended_minutes,
+        active_challenges,
+    };
+
+    let open_alerts: Vec<Alert> = ALERTS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, alert)| alert.user_id == user_id && alert.acknowledged_at.is_none())
+            .map(|(_, alert)| alert)
+            .collect()
+    });
+
+    Ok(PatientSummary {
+        user,
+        latest_health_record,
+        active_medications,
+        upcoming_consultations,
+        recent_mental_health_records,
+        diet_adherence,
+        exercise_adherence,
+        open_alerts,
+        generated_at: now,
+    })
+}
+
+// Helper to check whether a user may read a patient's summary: the patient,
+// their linked caregivers and providers who have treated them or have a
+// consultation booked with them
+fn can_view_patient_summary(user_id: u64, viewer_id: u64) -> bool {
+    viewer_id == user_id
+        || is_linked_caregiver(user_id, viewer_id)
+        || has_treated_patient(viewer_id, user_id)
+        || VIRTUAL_CONSULTATIONS_STORAGE.with(|storage| {
+            storage.borrow().iter().any(|(_, record)| {
+                record.provider_id == viewer_id
+                    && record.user_id == user_id
+                    && matches!(
+                        record.status,
+                        ConsultationStatus::Requested
+                            | ConsultationStatus::Confirmed
+                            | ConsultationStatus::InProgress
+                    )
+            })
+        })
+}
+
+// Function to export all of a user's records as CSV documents
+#[ic_cdk::query]
+fn export_user_records_csv(user_id: u64) -> Result<UserRecordsCsv, String> {
+    check_caller_is_user(user_id)?;
+
+    let health_records = HEALTH_RECORDS_STORAGE.with(|storage| {
+        let rows = storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id)
+            .map(|(_, record)| {
+                vec![
+                    record.id.to_string(),
+                    record.user_id.to_string(),
+                    record.heart_rate.to_string(),
+                    record.blood_pressure,
+                    record.activity_level,
+                    format!("{:?}", record.status),
+                    format_timestamp(record.recorded_at),
+                ]
+            })
+            .collect();
+        to_csv(
+            &[
+                "id",
+                "user_id",
+                "heart_rate",
+                "blood_pressure",
+                "activity_level",
+                "status",
+                "recorded_at",
+            ],
+            rows,
+        )
+    });
+
+    let diet_records = DIET_RECORDS_STORAGE.with(|storage| {
+        let rows = storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id)
+            .map(|(_, record)| {
+                vec![
+                    record.id.to_string(),
+                    record.user_id.to_string(),
+                    format!("{:?}", record.meal_type),
+                    record
+                        .items
+                        .iter()
+                        .map(|item| format!("{} x{}", item.food_name, item.servings))
+                        .collect::<Vec<_>>()
+                        .join("; "),
+                    format!("{:.0}", record.nutrients.calories),
+                    format!("{:.1}", record.nutrients.protein_grams),
+                    format!("{:.1}", record.nutrients.carbohydrate_grams),
+                    format!("{:.1}", record.nutrients.fat_grams),
+                    format!("{:.0}", record.nutrients.sodium_milligrams),
+                    format!("{:.1}", record.nutrients.sugar_grams),
+                    format_timestamp(record.recorded_at),
+                ]
+            })
+            .collect();
+        to_csv(
+            &[
+                "id",
+                "user_id",
+                "meal_type",
+                "food_items",
+                "calories",
+                "protein_grams",
+                "carbohydrate_grams",
+                "fat_grams",
+                "sodium_milligrams",
+                "sugar_grams",
+                "recorded_at",
+            ],
+            rows,
+        )
+    });
+
+    let exercise_recommendations = EXERCISE_RECOMMENDATIONS_STORAGE.with(|storage| {
+        let rows = storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id)
+            .map(|(_, record)| {
+                vec![
+                    record.id.to_string(),
+                    record.user_id.to_string(),
+                    format!("{:?}", record.exercise_type),
+                    record.duration.to_string(),
+                    format!("{:?}", record.intensity),
+                    format_timestamp(record.recommended_at),
+                ]
+            })
+            .collect();
+        to_csv(
+            &[
+                "id",
+                "user_id",
+                "exercise_type",
+                "duration_minutes",
+                "intensity",
+                "recommended_at",
+            ],
+            rows,
+        )
+    });
+
+    let mental_health_records = MENTAL_HEALTH_RECORDS_STORAGE.with(|storage| {
+        let rows = storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id)
+            .map(|(_, record)| {
+                vec![
+                    record.id.to_string(),
+                    record.user_id.to_string(),
+                    format!("{:?}", record.mood),
+                    format!("{:?}", record.stress_level),
+                    record.notes,
+                    format_timestamp(record.recorded_at),
+                ]
+            })
+            .collect();
+        to_csv(
+            &[
+                "id",
+                "user_id",
+                "mood",
+                "stress_level",
+                "notes",
+                "recorded_at",
+            ],
+            rows,
+        )
+    });
+
+    let medication_reminders = MEDICATION_REMINDERS_STORAGE.with(|storage| {
+        let rows = storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id)
+            .map(|(_, record)| {
+                vec![
+                    record.id.to_string(),
+                    record.user_id.to_string(),
+                    record.medication_name,
+                    format_dosage(&record.dosage),
+                    describe_schedule(&record.schedule),
+                    format_timestamp(record.created_at),
+                ]
+            })
+            .collect();
+        to_csv(
+            &[
+                "id",
+                "user_id",
+                "medication_name",
+                "dosage",
+                "schedule",
+                "created_at",
+            ],
+            rows,
+        )
+    });
+
+    Ok(UserRecordsCsv {
+        user_id,
+        health_records,
+        diet_records,
+        exercise_recommendations,
+        mental_health_records,
+        medication_reminders,
+    })
+}
+
+// Helper to convert a nanosecond timestamp into an ISO-8601 (UTC) string
+fn format_timestamp(nanos: u64) -> String {
+    DateTime::from_timestamp_nanos(nanos as i64).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+// Helper to build a CSV document from a header and rows
+fn to_csv(header: &[&str], rows: Vec<Vec<String>>) -> String {
+    let mut csv = header.join(",");
+    csv.push_str("\r\n");
+    for row in rows {
+        let fields: Vec<String> = row.iter().map(|field| escape_csv_field(field)).collect();
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+// Helper to quote a CSV field when it contains separators, quotes or line breaks
+fn escape_csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// Function to export a user's upcoming consultations and medication schedules
+// as an iCalendar (RFC 5545) document. Event UIDs are derived from record IDs,
+// so importing a newer export updates the events instead of duplicating them.
+// Only the user or a linked caregiver can export the calendar.
+#[ic_cdk::query]
+fn export_user_calendar_ics(user_id: u64, viewer_id: u64) -> Result<String, String> {
+    let user = USERS_STORAGE
+        .with(|storage| storage.borrow().get(&user_id))
+        .ok_or("User ID does not exist.".to_string())?;
+    if viewer_id != user_id && !is_linked_caregiver(user_id, viewer_id) {
+        return Err("Only the user or a linked caregiver can export this calendar.".to_string());
+    }
+    check_caller_is_user(viewer_id)?;
+    let now = time();
+    let tzid = ics_tzid(user.utc_offset_minutes.unwrap_or(0));
+    let tz_offset = ics_utc_offset(user.utc_offset_minutes.unwrap_or(0));
+
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//Elderly Care Platform//Calendar Export//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        "METHOD:PUBLISH".to_string(),
+        format!(
+            "X-WR-CALNAME:{}",
+            ics_text(&format!("Care calendar for {}", user.name))
+        ),
+        // Users have a fixed UTC offset, so their time zone has no daylight saving rules
+        "BEGIN:VTIMEZONE".to_string(),
+        format!("TZID:{}", tzid),
+        "BEGIN:STANDARD".to_string(),
+        "DTSTART:19700101T000000".to_string(),
+        format!("TZOFFSETFROM:{}", tz_offset),
+        format!("TZOFFSETTO:{}", tz_offset),
+        format!("TZNAME:{}", tzid),
+        "END:STANDARD".to_string(),
+        "END:VTIMEZONE".to_string(),
+    ];
+
+    // Upcoming consultations; cancelled ones are kept so re-importing removes them
+    let consultations: Vec<VirtualConsultation> = VIRTUAL_CONSULTATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| {
+                record.user_id == user_id
+                    && record.ends_at > now
+                    && record.status != ConsultationStatus::NoShow
+                    && record.status != ConsultationStatus::Completed
+            })
+            .map(|(_, record)| record)
+            .collect()
+    });
+    for consultation in consultations {
+        let provider_name = USERS_STORAGE
+            .with(|storage| storage.borrow().get(&consultation.provider_id))
+            .map_or("your provider".to_string(), |provider| provider.name);
+        let status = match consultation.status {
+            ConsultationStatus::Requested => "TENTATIVE",
+            ConsultationStatus::Cancelled => "CANCELLED",
+            _ => "CONFIRMED",
+        };
+        lines.extend([
+            "BEGIN:VEVENT".to_string(),
+            format!("UID:consultation-{}@{}", consultation.id, ICS_UID_DOMAIN),
+            format!("SEQUENCE:{}", consultation.status_history.len() - 1),
+            format!("DTSTAMP:{}", ics_utc_time(consultation.updated_at)),
+            format!("DTSTART:{}", ics_utc_time(consultation.scheduled_at)),
+            format!("DTEND:{}", ics_utc_time(consultation.ends_at)),
+            format!(
+                "SUMMARY:{}",
+                ics_text(&format!("Virtual consultation with {}", provider_name))
+            ),
+            format!("STATUS:{}", status),
+            "END:VEVENT".to_string(),
+        ]);
+    }
+
+    // One recurring event per dose time of each active medication schedule
+    let reminders: Vec<MedicationReminder> = MEDICATION_REMINDERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| record.user_id == user_id && reminder_is_active(record, now))
+            .map(|(_, record)| record)
+            .collect()
+    });
+    for reminder in reminders {
+        let schedule = &reminder.schedule;
+        let until = schedule.end_date.map_or(String::new(), |end_date| {
+            format!(";UNTIL={}", ics_utc_time(end_date.saturating_sub(1)))
+        });
+
+        // (UID, DTSTART property, RRULE) for each event of the schedule
+        let mut events: Vec<(String, String, String)> = Vec::new();
+        match &schedule.frequency {
+            ScheduleFrequency::AsNeeded => {}
+            ScheduleFrequency::EveryNHours { interval_hours } => events.push((
+                format!("medication-{}@{}", reminder.id, ICS_UID_DOMAIN),
+                format!("DTSTART:{}", ics_utc_time(schedule.start_date)),
+                format!("FREQ=HOURLY;INTERVAL={}{}", interval_hours, until),
+            )),
+            ScheduleFrequency::FixedTimes { times } | ScheduleFrequency::Weekdays { times, .. } => {
+                let rule = match &schedule.frequency {
+                    ScheduleFrequency::Weekdays { days, .. } => format!(
+                        "FREQ=WEEKLY;BYDAY={}{}",
+                        days.iter().map(ics_weekday).collect::<Vec<_>>().join(","),
+                        until
+                    ),
+                    _ => format!("FREQ=DAILY{}", until),
+                };
+                for time_of_day in times {
+                    // The first dose at this time of day anchors the recurrence
+                    let single_time = MedicationSchedule {
+                        frequency: match &schedule.frequency {
+                            ScheduleFrequency::Weekdays { days, .. } => {
+                                ScheduleFrequency::Weekdays {
+                                    days: days.clone(),
+                                    times: vec![*time_of_day],
+                                }
+                            }
+                            _ => ScheduleFrequency::FixedTimes {
+                                times: vec![*time_of_day],
+                            },
+                        },
+                        start_date: schedule.start_date,
+                        end_date: None,
+                    };
+                    let Some(first_dose) = dose_times_between(
+                        &single_time,
+                        user.utc_offset_minutes.unwrap_or(0),
+                        schedule.start_date,
+                        schedule.start_date + 8 * NANOS_PER_DAY,
+                    )
+                    .first()
+                    .copied() else {
+                        continue;
+                    };
+                    events.push((
+                        format!(
+                            "medication-{}-{:02}{:02}@{}",
+                            reminder.id, time_of_day.hour, time_of_day.minute, ICS_UID_DOMAIN
+                        ),
+                        format!(
+                            "DTSTART;TZID={}:{}",
+                            tzid,
+                            ics_local_time(first_dose, user.utc_offset_minutes.unwrap_or(0))
+                        ),
+                        rule.clone(),
+                    ));
+                }
+            }
+        }
+
+        for (uid, dtstart, rule) in events {
+            lines.extend([
+                "BEGIN:VEVENT".to_string(),
+                format!("UID:{}", uid),
+                format!("DTSTAMP:{}", ics_utc_time(reminder.created_at)),
+                dtstart,
+                format!("DURATION:PT{}M", ICS_DOSE_EVENT_MINUTES),
+                format!("RRULE:{}", rule),
+                format!(
+                    "SUMMARY:{}",
+                    ics_text(&format!(
+                        "Take {} ({})",
+                        reminder.medication_name,
+                        format_dosage(&reminder.dosage)
+                    ))
+                ),
+                format!("DESCRIPTION:{}", ics_text(&describe_schedule(schedule))),
+                "END:VEVENT".to_string(),
+            ]);
+        }
+    }
+
+    lines.push("END:VCALENDAR".to_string());
+    Ok(lines.iter().map(|line| ics_fold(line) + "\r\n").collect())
+}
+
+// Helper to format a timestamp as an iCalendar UTC date-time
+fn ics_utc_time(nanos: u64) -> String {
+    DateTime::from_timestamp_nanos(nanos as i64)
+        .format("%Y%m%dT%H%M%SZ")
+        .to_string()
+}
+
+// Helper to format a timestamp as an iCalendar local date-time at a UTC offset
+fn ics_local_time(nanos: u64, utc_offset_minutes: i32) -> String {
+    let local = nanos as i64 + utc_offset_minutes as i64 * NANOS_PER_MINUTE as i64;
+    DateTime::from_timestamp_nanos(local)
+        .format("%Y%m%dT%H%M%S")
+        .to_string()
+}
+
+// Helper to name the fixed-offset time zone of a user, e.g. "UTC+03:00"
+fn ics_tzid(utc_offset_minutes: i32) -> String {
+    let sign = if utc_offset_minutes < 0 { '-' } else { '+' };
+    let minutes = utc_offset_minutes.unsigned_abs();
+    format!("UTC{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+}
+
+// Helper to format a UTC offset as an iCalendar UTC offset, e.g. "+0300"
+fn ics_utc_offset(utc_offset_minutes: i32) -> String {
+    let sign = if utc_offset_minutes < 0 { '-' } else { '+' };
+    let minutes = utc_offset_minutes.unsigned_abs();
+    format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60)
+}
+
+// Helper to map a weekday to its iCalendar BYDAY code
+fn ics_weekday(day: &Weekday) -> &'static str {
+    match day {
+        Weekday::Monday => "MO",
+        Weekday::Tuesday => "TU",
+        Weekday::Wednesday => "WE",
+        Weekday::Thursday => "TH",
+        Weekday::Friday => "FR",
+        Weekday::Saturday => "SA",
+        Weekday::Sunday => "SU",
+    }
+}
+
+// Helper to escape an iCalendar TEXT value
+fn ics_text(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace(['\r', '\n'], "\\n")
+}
+
+// Helper to fold a content line longer than 75 octets onto continuation lines
+fn ics_fold(line: &str) -> String {
+    let mut folded = String::new();
+    let mut line_length = 0;
+    for character in line.chars() {
+        if line_length + character.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            line_length = 1;
+        }
+        folded.push(character);
+        line_length += character.len_utf8();
+    }
+    folded
+}
+
+// Error types
+#[derive(candid::CandidType, Deserialize, Serialize)]
+enum Error {
+    NotFound { msg: String },
+    UnAuthorized { msg: String },
+}
+
+// need this to generate candid
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell as ClockCell;
+
+    thread_local! {
+        static NOW: ClockCell<u64> = const { ClockCell::new(0) };
+    }
+
+    // Stands in for the system time in native tests
+    pub(super) fn time() -> u64 {
+        NOW.with(|now| now.get())
+    }
+
+    fn set_time(now: u64) {
+        NOW.with(|clock| clock.set(now));
+    }
+
+    // 2024-01-01T00:00:00Z, a Monday
+    const MONDAY: u64 = 1_704_067_200 * 1_000_000_000;
+
+    fn fixed_times(times: &[(u8, u8)]) -> MedicationSchedule {
+        MedicationSchedule {
+            frequency: ScheduleFrequency::FixedTimes {
+                times: times
+                    .iter()
+                    .map(|&(hour, minute)| TimeOfDay { hour, minute })
+                    .collect(),
+            },
+            start_date: MONDAY,
+            end_date: None,
+        }
+    }
+
+    #[test]
+    fn dose_times_cover_half_open_windows() {
+        let schedule = fixed_times(&[(8, 0), (20, 0)]);
+        let eight = MONDAY + 8 * NANOS_PER_HOUR;
+        let twenty = MONDAY + 20 * NANOS_PER_HOUR;
+        assert_eq!(
+            dose_times_between(&schedule, 0, MONDAY, MONDAY + NANOS_PER_DAY),
+            vec![eight, twenty]
+        );
+        // The end of the window is exclusive, the start inclusive
+        assert!(dose_times_between(&schedule, 0, MONDAY, eight).is_empty());
+        assert_eq!(
+            dose_times_between(&schedule, 0, eight, eight + 1),
+            vec![eight]
+        );
+        // Nothing before the schedule starts
+        assert!(dose_times_between(&schedule, 0, 0, MONDAY).is_empty());
+    }
+
+    #[test]
+    fn dose_times_follow_the_local_day() {
+        // 08:00 at UTC+02:00 is 06:00 UTC; at UTC-05:00 it is 13:00 UTC
+        let schedule = fixed_times(&[(8, 0)]);
+        let window = (MONDAY, MONDAY + NANOS_PER_DAY);
+        assert_eq!(
+            dose_times_between(&schedule, 120, window.0, window.1),
+            vec![MONDAY + 6 * NANOS_PER_HOUR]
+        );
+        assert_eq!(
+            dose_times_between(&schedule, -300, window.0, window.1),
+            vec![MONDAY + 13 * NANOS_PER_HOUR]
+        );
+    }
+
+    #[test]
+    fn dose_times_respect_weekdays_intervals_and_end_dates() {
+        let weekdays = MedicationSchedule {
+            frequency: ScheduleFrequency::Weekdays {
+                days: vec![Weekday::Tuesday],
+                times: vec![TimeOfDay { hour: 9, minute: 0 }],
+            },
+            start_date: MONDAY,
+            end_date: None,
+        };
+        assert_eq!(
+            dose_times_between(&weekdays, 0, MONDAY, MONDAY + 7 * NANOS_PER_DAY),
+            vec![MONDAY + NANOS_PER_DAY + 9 * NANOS_PER_HOUR]
+        );
+
+        let every_eight_hours = MedicationSchedule {
+            frequency: ScheduleFrequency::EveryNHours { interval_hours: 8 },
+            start_date: MONDAY + NANOS_PER_HOUR,
+            end_date: Some(MONDAY + 17 * NANOS_PER_HOUR),
+        };
+        assert_eq!(
+            dose_times_between(&every_eight_hours, 0, MONDAY, MONDAY + NANOS_PER_DAY),
+            vec![MONDAY + NANOS_PER_HOUR, MONDAY + 9 * NANOS_PER_HOUR]
+        );
+    }
+
+    #[test]
+    fn only_scheduled_dose_times_can_be_recorded() {
+        let schedule = fixed_times(&[(8, 0)]);
+        let eight = MONDAY + 8 * NANOS_PER_HOUR;
+        assert!(is_scheduled_dose_time(&schedule, 0, eight));
+        assert!(!is_scheduled_dose_time(&schedule, 0, eight + 1));
+        assert!(!is_scheduled_dose_time(&schedule, 0, eight - NANOS_PER_DAY));
+
+        let as_needed = MedicationSchedule {
+            frequency: ScheduleFrequency::AsNeeded,
+            start_date: MONDAY,
+            end_date: Some(MONDAY + NANOS_PER_DAY),
+        };
+        assert!(is_scheduled_dose_time(&as_needed, 0, MONDAY));
+        assert!(!is_scheduled_dose_time(&as_needed, 0, MONDAY - 1));
+        assert!(!is_scheduled_dose_time(
+            &as_needed,
+            0,
+            MONDAY + NANOS_PER_DAY
+        ));
+    }
+
+    #[test]
+    fn adherence_windows_are_capped() {
+        let limit = MAX_ADHERENCE_WINDOW_DAYS * NANOS_PER_DAY;
+        assert!(validate_adherence_window(MONDAY, MONDAY + limit).is_ok());
+        assert!(validate_adherence_window(MONDAY, MONDAY + limit + 1).is_err());
+        assert!(validate_adherence_window(MONDAY, MONDAY).is_err());
+        assert!(validate_adherence_window(0, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn legacy_schedule_reads_times_of_day() {
+        let schedule = parse_legacy_schedule("20:00 and 08:00, 8:00", 5).unwrap();
+        assert_eq!(
+            schedule.frequency,
+            ScheduleFrequency::FixedTimes {
+                times: vec![
+                    TimeOfDay { hour: 8, minute: 0 },
+                    TimeOfDay {
+                        hour: 20,
+                        minute: 0
+                    },
+                ],
+            }
+        );
+        assert_eq!(schedule.start_date, 5);
+    }
+
+    #[test]
+    fn legacy_schedule_reads_intervals_and_as_needed() {
+        assert_eq!(
+            parse_legacy_schedule("Every 8 hours", 0).unwrap().frequency,
+            ScheduleFrequency::EveryNHours { interval_hours: 8 }
+        );
+        assert_eq!(
+            parse_legacy_schedule("Take as needed for pain", 0)
+                .unwrap()
+                .frequency,
+            ScheduleFrequency::AsNeeded
+        );
+        assert!(parse_legacy_schedule("every 0 hours", 0).is_none());
+        assert!(parse_legacy_schedule("every 200 hours", 0).is_none());
+    }
+
+    #[test]
+    fn legacy_schedule_rejects_unreadable_text() {
+        assert!(parse_legacy_schedule("twice a day", 0).is_none());
+        assert!(parse_legacy_schedule("24:00", 0).is_none());
+        assert!(parse_legacy_schedule("8:5", 0).is_none());
+        assert!(parse_legacy_schedule("", 0).is_none());
+    }
+
+    #[test]
+    fn legacy_reminder_keeps_its_original_text() {
+        let reminder = convert_legacy_medication_reminder(LegacyMedicationReminder {
+            id: 7,
+            user_id: 1,
+            medication_name: "Metformin".to_string(),
+            dosage: "500mg".to_string(),
+            schedule: "twice a day".to_string(),
+            created_at: 42,
+        });
+        assert_eq!(reminder.id, 7);
+        assert_eq!(
+            reminder.dosage,
+            Dosage {
+                quantity: 500.0,
+                unit: DoseUnit::Mg,
+                form: DosageForm::Other,
+            }
+        );
+        assert_eq!(reminder.schedule.frequency, ScheduleFrequency::AsNeeded);
+        assert_eq!(reminder.schedule.start_date, 42);
+        assert_eq!(
+            reminder.legacy_entry.as_deref(),
+            Some("Dosage: 500mg; schedule: twice a day")
+        );
+    }
+
+    #[test]
+    fn dose_events_are_found_by_reminder_and_scheduled_time() {
+        let event = DoseEvent {
+            id: 900,
+            medication_reminder_id: 90,
+            user_id: 9,
+            scheduled_for: MONDAY,
+            status: DoseStatus::Taken,
+            recorded_by: Some(9),
+            recorded_at: MONDAY,
+        };
+        insert_dose_event(&event);
+        assert_eq!(find_dose_event(90, MONDAY).map(|event| event.id), Some(900));
+        assert!(find_dose_event(90, MONDAY + 1).is_none());
+        assert!(find_dose_event(91, MONDAY).is_none());
+    }
+
+    fn drug_interaction_payload(drug_a: &str, drug_b: &str) -> DrugInteractionPayload {
+        DrugInteractionPayload {
+            drug_a: drug_a.to_string(),
+            drug_b: drug_b.to_string(),
+            severity: InteractionSeverity::Major,
+            description: "Raises the risk of bleeding.".to_string(),
+        }
+    }
+
+    fn store_drug_interaction(id: u64, drug_a: &str, drug_b: &str, severity: InteractionSeverity) {
+        let interaction = DrugInteraction {
+            id,
+            drug_a: drug_a.to_string(),
+            drug_b: drug_b.to_string(),
+            severity,
+            description: String::new(),
+            created_at: 0,
+            updated_at: 0,
+        };
+        DRUG_INTERACTIONS_STORAGE.with(|storage| storage.borrow_mut().insert(id, interaction));
+    }
+
+    fn store_reminder(id: u64, user_id: u64, medication_name: &str) {
+        let reminder = MedicationReminder {
+            id,
+            user_id,
+            medication_name: medication_name.to_string(),
+            schedule: fixed_times(&[(8, 0)]),
+            ..Default::default()
+        };
+        MEDICATION_REMINDERS_STORAGE.with(|storage| storage.borrow_mut().insert(id, reminder));
+    }
+
+    #[test]
+    fn drug_interactions_are_bounded_and_unique_per_pair() {
+        store_drug_interaction(300, "warfarin", "aspirin", InteractionSeverity::Major);
+
+        let mut payload = drug_interaction_payload(" Aspirin ", "WARFARIN");
+        assert!(validate_drug_interaction(&payload, None).is_err());
+        // The interaction being updated does not count as a duplicate
+        assert_eq!(
+            validate_drug_interaction(&payload, Some(300)),
+            Ok(("aspirin".to_string(), "warfarin".to_string()))
+        );
 
-    MENTAL_HEALTH_RECORDS_STORAGE.with(|storage| {
-        storage
-            .borrow_mut()
-            .insert(id, mental_health_record.clone())
-    });
-    Ok(mental_health_record)
-}
+        payload.drug_b = "ibuprofen".to_string();
+        payload.description = "x".repeat(MAX_INTERACTION_DESCRIPTION_LENGTH);
+        assert!(validate_drug_interaction(&payload, None).is_ok());
+        payload.description.push('x');
+        assert!(validate_drug_interaction(&payload, None).is_err());
 
-// Function to retrieve mental health records by user ID
-#[ic_cdk::query]
-fn get_mental_health_records_by_user_id(user_id: u64) -> Result<Vec<MentalHealthRecord>, String> {
-    MENTAL_HEALTH_RECORDS_STORAGE.with(|storage| {
-        let stable_btree_map = &*storage.borrow();
-        let records: Vec<MentalHealthRecord> = stable_btree_map
+        let payload = drug_interaction_payload("aspirin", &"x".repeat(MAX_DRUG_NAME_LENGTH + 1));
+        assert!(validate_drug_interaction(&payload, None).is_err());
+        assert!(
+            validate_drug_interaction(&drug_interaction_payload("aspirin", "Aspirin"), None)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn drug_interaction_warnings_are_ordered_by_severity() {
+        store_reminder(310, 31, "Warfarin");
+        store_reminder(311, 31, "Simvastatin");
+        store_reminder(312, 32, "Simvastatin");
+        store_drug_interaction(
+            320,
+            "warfarin",
+            "clarithromycin",
+            InteractionSeverity::Major,
+        );
+        store_drug_interaction(
+            321,
+            "clarithromycin",
+            "simvastatin",
+            InteractionSeverity::Contraindicated,
+        );
+
+        let warnings = check_drug_interactions(31, " Clarithromycin", MONDAY);
+        let found: Vec<(u64, u64, InteractionSeverity)> = warnings
             .iter()
-            .filter(|(_, record)| record.user_id == user_id)
-            .map(|(_, record)| record.clone())
+            .map(|warning| {
+                (
+                    warning.drug_interaction_id,
+                    warning.interacting_medication_reminder_id,
+                    warning.severity,
+                )
+            })
             .collect();
-        if records.is_empty() {
-            Err("No mental health records found.".to_string())
-        } else {
-            Ok(records)
+        assert_eq!(
+            found,
+            vec![
+                (321, 311, InteractionSeverity::Contraindicated),
+                (320, 310, InteractionSeverity::Major),
+            ]
+        );
+        // Partial names do not match
+        assert!(check_drug_interactions(31, "clarithro", MONDAY).is_empty());
+    }
+
+    #[test]
+    fn prescription_end_dates_do_not_overflow() {
+        assert_eq!(
+            prescription_end_date(MONDAY, None, Some(7)),
+            Ok(Some(MONDAY + 7 * NANOS_PER_DAY))
+        );
+        assert_eq!(prescription_end_date(MONDAY, Some(5), None), Ok(Some(5)));
+        assert_eq!(prescription_end_date(MONDAY, None, None), Ok(None));
+        assert!(prescription_end_date(MONDAY, Some(5), Some(7)).is_err());
+        assert!(prescription_end_date(u64::MAX - NANOS_PER_DAY, None, Some(2)).is_err());
+        assert!(prescription_end_date(0, None, Some(200_000)).is_ok());
+        assert!(prescription_end_date(0, None, Some(u32::MAX)).is_err());
+    }
+
+    #[test]
+    fn status_reasons_are_bounded() {
+        assert!(validate_status_reason(&"x".repeat(MAX_STATUS_REASON_LENGTH)).is_ok());
+        // The limit is in bytes, so multi-byte text reaches it sooner
+        assert!(validate_status_reason(&"é".repeat(MAX_STATUS_REASON_LENGTH / 2 + 1)).is_err());
+    }
+
+    #[test]
+    fn allergens_match_whole_words_only() {
+        assert!(allergen_matches("Peanut", "peanut butter"));
+        assert!(allergen_matches("penicillin", " Penicillin V potassium "));
+        assert!(allergen_matches("tree nut", "Mixed tree-nut bar"));
+        assert!(!allergen_matches("pen", "Pentoxifylline"));
+        assert!(!allergen_matches("nut", "Nutmeg"));
+        assert!(!allergen_matches("tree nut", "nut tree"));
+        assert!(!allergen_matches("", "anything"));
+        assert!(!allergen_matches("peanut butter", "peanut"));
+    }
+
+    #[test]
+    fn allergy_warnings_are_capped_most_severe_first() {
+        for (index, severity) in [AllergySeverity::Mild, AllergySeverity::Moderate]
+            .iter()
+            .cycle()
+            .take(MAX_ALLERGY_WARNINGS + 4)
+            .enumerate()
+        {
+            let allergy = Allergy {
+                id: 400 + index as u64,
+                user_id: 40,
+                substance: "milk".to_string(),
+                category: AllergyCategory::Food,
+                reaction: "Hives".to_string(),
+                severity: *severity,
+                ..Default::default()
+            };
+            ALLERGIES_STORAGE.with(|storage| storage.borrow_mut().insert(allergy.id, allergy));
         }
-    })
-}
 
-// Function to retrieve all mental health records
-#[ic_cdk::query]
-fn get_all_mental_health_records() -> Result<Vec<MentalHealthRecord>, String> {
-    MENTAL_HEALTH_RECORDS_STORAGE.with(|storage| {
-        let stable_btree_map = &*storage.borrow();
-        let records: Vec<MentalHealthRecord> = stable_btree_map
+        let warnings = check_allergies(40, &[AllergyCategory::Food], &["Whole milk"]).unwrap();
+        assert_eq!(warnings.len(), MAX_ALLERGY_WARNINGS);
+        assert!(warnings[..MAX_ALLERGY_WARNINGS / 2 + 2]
             .iter()
-            .map(|(_, record)| record.clone())
+            .all(|warning| warning.severity == AllergySeverity::Moderate));
+        assert!(
+            check_allergies(40, &[AllergyCategory::Medication], &["Whole milk"])
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            check_allergies(40, &[AllergyCategory::Food], &["Milkshake"])
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn severe_allergies_reject_the_entry() {
+        let allergy = Allergy {
+            id: 450,
+            user_id: 45,
+            substance: "Penicillin".to_string(),
+            category: AllergyCategory::Medication,
+            reaction: "Anaphylaxis".to_string(),
+            severity: AllergySeverity::Severe,
+            ..Default::default()
+        };
+        ALLERGIES_STORAGE.with(|storage| storage.borrow_mut().insert(allergy.id, allergy));
+        assert!(check_allergies(45, &[AllergyCategory::Medication], &["penicillin"]).is_err());
+        assert!(check_allergies(45, &[AllergyCategory::Medication], &["Pentoxifylline"]).is_ok());
+    }
+
+    #[test]
+    fn legacy_diet_record_keeps_its_text_and_calories() {
+        let record = convert_legacy_diet_record(LegacyDietRecord {
+            id: 8,
+            user_id: 1,
+            meal_type: MealType::Lunch,
+            food_items: "Soup, bread".to_string(),
+            calories: 450,
+            recorded_at: 42,
+        });
+        assert_eq!(record.items.len(), 1);
+        assert_eq!(record.items[0].food_name, "Soup, bread");
+        assert_eq!(record.nutrients.calories, 450.0);
+        assert_eq!(record.recorded_at, 42);
+    }
+
+    fn dosage(quantity: f64, unit: DoseUnit, form: DosageForm) -> Dosage {
+        Dosage {
+            quantity,
+            unit,
+            form,
+        }
+    }
+
+    #[test]
+    fn dosages_are_parsed_from_free_text() {
+        assert_eq!(
+            parse_dosage("500mg"),
+            Some(dosage(500.0, DoseUnit::Mg, DosageForm::Other))
+        );
+        assert_eq!(
+            parse_dosage(" 1,000 IU "),
+            Some(dosage(1000.0, DoseUnit::Iu, DosageForm::Other))
+        );
+        assert_eq!(
+            parse_dosage("0.5 g tablet"),
+            Some(dosage(0.5, DoseUnit::G, DosageForm::Tablet))
+        );
+        assert_eq!(
+            parse_dosage("10ml"),
+            Some(dosage(10.0, DoseUnit::Ml, DosageForm::Liquid))
+        );
+        assert_eq!(
+            parse_dosage("2 Puffs"),
+            Some(dosage(2.0, DoseUnit::Count, DosageForm::Puff))
+        );
+        assert_eq!(parse_dosage("one tablet"), None);
+        assert_eq!(parse_dosage("500"), None);
+        assert_eq!(parse_dosage("0 mg"), None);
+        assert_eq!(parse_dosage("500 mg twice daily"), None);
+        assert_eq!(parse_dosage("2 tablets capsule"), None);
+        assert_eq!(parse_dosage("1e400 mg"), None);
+    }
+
+    #[test]
+    fn legacy_reminder_without_free_text_left_over() {
+        let reminder = convert_legacy_medication_reminder(LegacyMedicationReminder {
+            dosage: "2 tablets".to_string(),
+            schedule: "08:00".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(
+            reminder.dosage,
+            dosage(2.0, DoseUnit::Count, DosageForm::Tablet)
+        );
+        assert_eq!(reminder.legacy_entry, None);
+    }
+
+    #[test]
+    fn dosages_and_dose_limits_must_be_finite() {
+        assert!(validate_dosage(&dosage(1.0, DoseUnit::Mg, DosageForm::Tablet)).is_ok());
+        for quantity in [
+            0.0,
+            -1.0,
+            MIN_DOSAGE_QUANTITY / 2.0,
+            MAX_DOSAGE_QUANTITY * 2.0,
+            f64::NAN,
+            f64::INFINITY,
+        ] {
+            assert!(validate_dosage(&dosage(quantity, DoseUnit::Mg, DosageForm::Tablet)).is_err());
+        }
+    }
+
+    #[test]
+    fn daily_doses_are_checked_in_the_limit_unit() {
+        let limit = DrugDoseLimit {
+            id: 500,
+            drug_name: "paracetamol".to_string(),
+            max_daily_quantity: 4.0,
+            unit: DoseUnit::G,
+            updated_at: 0,
+        };
+        DRUG_DOSE_LIMITS_STORAGE.with(|storage| storage.borrow_mut().insert(limit.id, limit));
+        let tablet = |quantity, unit| dosage(quantity, unit, DosageForm::Tablet);
+        let hours = |count: u64| MONDAY + count * NANOS_PER_HOUR;
+
+        // 4 x 1000 mg within a day is exactly the limit
+        let four_doses: Vec<(u64, Dosage)> = (0..4)
+            .map(|dose| (hours(dose * 6), tablet(1000.0, DoseUnit::Mg)))
             .collect();
-        if records.is_empty() {
-            Err("No mental health records found.".to_string())
-        } else {
-            Ok(records)
+        assert!(check_max_daily_dose("Paracetamol", four_doses.clone(), "This schedule").is_ok());
+
+        // A fifth dose within 24 hours of the first is over it
+        let mut five_doses = four_doses.clone();
+        five_doses.push((hours(23), tablet(0.5, DoseUnit::G)));
+        assert!(check_max_daily_dose("Paracetamol", five_doses, "This schedule").is_err());
+
+        // ...but not once the first dose has left the window
+        let mut next_day = four_doses.clone();
+        next_day.push((hours(24), tablet(1000.0, DoseUnit::Mg)));
+        assert!(check_max_daily_dose("Paracetamol", next_day, "This schedule").is_ok());
+
+        // Doses that cannot be converted to grams are rejected, not skipped
+        let mut counted = four_doses;
+        counted.push((hours(1), tablet(1.0, DoseUnit::Count)));
+        assert!(check_max_daily_dose("Paracetamol", counted, "This schedule").is_err());
+
+        // Drugs without a limit are not checked
+        let unlimited = vec![(hours(0), tablet(1.0, DoseUnit::Count))];
+        assert!(check_max_daily_dose("Ibuprofen", unlimited, "This schedule").is_ok());
+    }
+
+    #[test]
+    fn legacy_consultations_get_a_status_and_length() {
+        for (text, status) in [
+            ("Scheduled", ConsultationStatus::Confirmed),
+            ("in progress", ConsultationStatus::InProgress),
+            ("COMPLETED", ConsultationStatus::Completed),
+            ("canceled", ConsultationStatus::Cancelled),
+            ("no-show", ConsultationStatus::NoShow),
+            ("pending", ConsultationStatus::Requested),
+            ("", ConsultationStatus::Requested),
+        ] {
+            assert_eq!(parse_legacy_consultation_status(text), status, "{}", text);
         }
-    })
-}
 
-// Function to create a new fitness challenge
-#[ic_cdk::update]
-fn create_fitness_challenge(payload: FitnessChallengePayload) -> Result<FitnessChallenge, String> {
-    if payload.name.is_empty() || payload.description.is_empty() {
-        return Err("Name and description cannot be empty".to_string());
+        let consultation = convert_legacy_virtual_consultation(LegacyVirtualConsultation {
+            id: 9,
+            user_id: 1,
+            provider_id: 2,
+            scheduled_at: MONDAY,
+            status: "Completed".to_string(),
+            created_at: 42,
+        });
+        assert_eq!(consultation.status, ConsultationStatus::Completed);
+        assert_eq!(
+            consultation.ends_at,
+            MONDAY + LEGACY_CONSULTATION_MINUTES * NANOS_PER_MINUTE
+        );
+        assert_eq!(consultation.status_history.len(), 1);
+        assert_eq!(consultation.booked_by, 1);
     }
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+    #[test]
+    fn largest_valid_consultation_note_fits_its_storage_bound() {
+        let section = "x".repeat(MAX_NOTE_SECTION_LENGTH);
+        let payload = ConsultationNotePayload {
+            provider_id: 2,
+            subjective: section.clone(),
+            objective: section.clone(),
+            assessment: section.clone(),
+            plan: section,
+            follow_up_actions: vec![
+                FollowUpAction {
+                    description: "x".repeat(MAX_FOLLOW_UP_DESCRIPTION_LENGTH),
+                    due_at: Some(u64::MAX),
+                };
+                MAX_FOLLOW_UP_ACTIONS
+            ],
+        };
+        assert!(validate_consultation_note(&payload).is_ok());
 
-    let challenge = FitnessChallenge {
-        id,
-        name: payload.name,
-        description: payload.description,
-        start_date: payload.start_date,
-        end_date: payload.end_date,
-        created_at: time(),
-    };
+        let note = ConsultationNote {
+            id: u64::MAX,
+            consultation_id: u64::MAX,
+            user_id: u64::MAX,
+            provider_id: u64::MAX,
+            subjective: payload.subjective,
+            objective: payload.objective,
+            assessment: payload.assessment,
+            plan: payload.plan,
+            follow_up_actions: payload.follow_up_actions,
+            signed_off_at: Some(u64::MAX),
+            created_at: u64::MAX,
+            updated_at: u64::MAX,
+        };
+        assert!(note.to_bytes().len() <= ConsultationNote::MAX_SIZE as usize);
+    }
 
-    FITNESS_CHALLENGES_STORAGE.with(|storage| storage.borrow_mut().insert(id, challenge.clone()));
-    Ok(challenge)
-}
+    fn recurrence(
+        frequency: RecurrenceFrequency,
+        interval: u32,
+        occurrences: u32,
+    ) -> ConsultationRecurrence {
+        ConsultationRecurrence {
+            frequency,
+            interval,
+            occurrences,
+        }
+    }
 
-// Function to create a new fitness challenge participant
-#[ic_cdk::update]
-fn create_fitness_challenge_participant(
-    payload: FitnessChallengeParticipantPayload,
-) -> Result<FitnessChallengeParticipant, String> {
-    let challenge_exists = FITNESS_CHALLENGES_STORAGE
-        .with(|storage| storage.borrow().contains_key(&payload.challenge_id));
-    if !challenge_exists {
-        return Err("Challenge ID does not exist.".to_string());
+    #[test]
+    fn series_occurrences_follow_the_recurrence() {
+        let weekly = series_occurrences(MONDAY, &recurrence(RecurrenceFrequency::Weekly, 2, 3));
+        assert_eq!(
+            weekly.unwrap(),
+            vec![
+                MONDAY,
+                MONDAY + 14 * NANOS_PER_DAY,
+                MONDAY + 28 * NANOS_PER_DAY
+            ]
+        );
+
+        // 31 January falls back to the end of February, then keeps the 31st
+        let january_31 = MONDAY + 30 * NANOS_PER_DAY;
+        let monthly =
+            series_occurrences(january_31, &recurrence(RecurrenceFrequency::Monthly, 1, 3))
+                .unwrap();
+        assert_eq!(monthly[1], january_31 + 29 * NANOS_PER_DAY);
+        assert_eq!(monthly[2], january_31 + 60 * NANOS_PER_DAY);
     }
 
-    let user_exists = USERS_STORAGE.with(|storage| storage.borrow().contains_key(&payload.user_id));
-    if !user_exists {
-        return Err("User ID does not exist.".to_string());
+    #[test]
+    fn series_occurrences_reject_bad_intervals_and_overflow() {
+        for interval in [0, MAX_RECURRENCE_INTERVAL + 1] {
+            let weekly = recurrence(RecurrenceFrequency::Weekly, interval, 2);
+            assert!(series_occurrences(MONDAY, &weekly).is_err());
+        }
+        let weekly = recurrence(RecurrenceFrequency::Weekly, MAX_RECURRENCE_INTERVAL, 2);
+        assert!(series_occurrences(u64::MAX - NANOS_PER_DAY, &weekly).is_err());
+        let monthly = recurrence(RecurrenceFrequency::Monthly, 1, 2);
+        assert!(series_occurrences(u64::MAX, &monthly).is_err());
+        assert!(series_occurrences(i64::MAX as u64 - NANOS_PER_DAY, &monthly).is_err());
     }
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment ID counter");
+    #[test]
+    fn largest_consultation_history_fits_its_storage_bound() {
+        let long_change = ConsultationStatusChange {
+            status: ConsultationStatus::Cancelled,
+            reason: "x".repeat(MAX_STATUS_REASON_LENGTH + 32),
+            changed_by: u64::MAX,
+            changed_at: u64::MAX,
+        };
+        let reschedule = ConsultationStatusChange {
+            reason: format!("Rescheduled to {}", format_timestamp(i64::MAX as u64)),
+            ..long_change.clone()
+        };
+        let mut consultation = VirtualConsultation {
+            id: u64::MAX,
+            status_history: vec![long_change.clone()],
+            ..Default::default()
+        };
+        while check_can_reschedule(&consultation).is_ok() {
+            consultation.status_history.push(reschedule.clone());
+        }
+        consultation
+            .status_history
+            .extend(vec![long_change; CONSULTATION_LIFECYCLE_CHANGES]);
+        assert_eq!(
+            consultation.status_history.len(),
+            MAX_CONSULTATION_STATUS_CHANGES
+        );
+        assert!(consultation.to_bytes().len() <= VirtualConsultation::MAX_SIZE as usize);
+    }
 
-    let participant = FitnessChallengeParticipant {
-        id,
-        challenge_id: payload.challenge_id,
-        user_id: payload.user_id,
-        progress: payload.progress,
-        updated_at: time(),
-    };
+    #[test]
+    fn largest_provider_profile_fits_its_storage_bound() {
+        use ProviderSpecialty::*;
+        let profile = ProviderProfile {
+            provider_id: u64::MAX,
+            specialties: vec![
+                GeneralPractice,
+                Geriatrics,
+                Physiotherapy,
+                OccupationalTherapy,
+                Cardiology,
+                Neurology,
+                Psychiatry,
+                Nutrition,
+                Nursing,
+                Other,
+            ],
+            languages: vec!["x".repeat(MAX_LANGUAGE_LENGTH); MAX_PROVIDER_LANGUAGES],
+            licence_number: "x".repeat(MAX_LICENCE_NUMBER_LENGTH),
+            bio: "x".repeat(MAX_PROVIDER_BIO_LENGTH),
+            consultation_fee: u64::MAX,
+            currency: "x".repeat(MAX_CURRENCY_LENGTH),
+            rating_count: u32::MAX,
+            average_rating: Some(5.0),
+            created_at: u64::MAX,
+            updated_at: u64::MAX,
+        };
+        assert!(profile.to_bytes().len() <= ProviderProfile::MAX_SIZE as usize);
+    }
 
-    FITNESS_CHALLENGE_PARTICIPANTS_STORAGE
-        .with(|storage| storage.borrow_mut().insert(id, participant.clone()));
-    Ok(participant)
-}
+    #[test]
+    fn largest_referral_fits_its_storage_bound() {
+        let change = ReferralStatusChange {
+            status: ReferralStatus::Completed,
+            note: "x".repeat(MAX_STATUS_REASON_LENGTH),
+            changed_by: u64::MAX,
+            changed_at: u64::MAX,
+        };
+        let referral = Referral {
+            id: u64::MAX,
+            user_id: u64::MAX,
+            referring_provider_id: u64::MAX,
+            receiving_provider_id: u64::MAX,
+            reason: "x".repeat(MAX_STATUS_REASON_LENGTH),
+            attachments: vec![
+                ReferralAttachment {
+                    record_type: ReferralRecordType::ConsultationNote,
+                    record_id: u64::MAX,
+                };
+                MAX_REFERRAL_ATTACHMENTS
+            ],
+            status: ReferralStatus::Completed,
+            // Sent, then Accepted or Declined, then Completed
+            status_history: vec![change; 3],
+            created_at: u64::MAX,
+            updated_at: u64::MAX,
+        };
+        assert!(referral.to_bytes().len() <= Referral::MAX_SIZE as usize);
+    }
 
-// Function to retrieve all fitness challenges
-#[ic_cdk::query]
-fn get_all_fitness_challenges() -> Result<Vec<FitnessChallenge>, String> {
-    FITNESS_CHALLENGES_STORAGE.with(|storage| {
-        let stable_btree_map = &*storage.borrow();
-        let records: Vec<FitnessChallenge> = stable_btree_map
-            .iter()
-            .map(|(_, record)| record.clone())
-            .collect();
-        if records.is_empty() {
-            Err("No fitness challenges found.".to_string())
-        } else {
-            Ok(records)
+    #[test]
+    fn largest_consultation_feedback_fits_its_storage_bound() {
+        let feedback = ConsultationFeedback {
+            id: u64::MAX,
+            consultation_id: u64::MAX,
+            provider_id: u64::MAX,
+            user_id: u64::MAX,
+            submitted_by: u64::MAX,
+            rating: 5,
+            comment: "é".repeat(MAX_FEEDBACK_COMMENT_LENGTH / 2),
+            hidden_at: Some(u64::MAX),
+            moderation_reason: "x".repeat(MAX_STATUS_REASON_LENGTH),
+            created_at: u64::MAX,
+        };
+        assert_eq!(feedback.comment.len(), MAX_FEEDBACK_COMMENT_LENGTH);
+        assert!(feedback.to_bytes().len() <= ConsultationFeedback::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn local_period_start_uses_the_local_day_and_week() {
+        // Wednesday 3 January 2024, 01:30 UTC
+        let wednesday = MONDAY + 2 * NANOS_PER_DAY + 90 * NANOS_PER_MINUTE;
+        let wednesday_start = MONDAY + 2 * NANOS_PER_DAY;
+        assert_eq!(
+            local_period_start(wednesday, 0, SummaryPeriod::Daily),
+            wednesday_start
+        );
+        assert_eq!(
+            local_period_start(wednesday, 0, SummaryPeriod::Weekly),
+            MONDAY
+        );
+
+        // At UTC-5 it is still Tuesday evening; the local day began at 05:00 UTC
+        let offset = -5 * 60;
+        assert_eq!(
+            local_period_start(wednesday, offset, SummaryPeriod::Daily),
+            MONDAY + NANOS_PER_DAY + 5 * 60 * NANOS_PER_MINUTE
+        );
+        assert_eq!(
+            local_period_start(wednesday, offset, SummaryPeriod::Weekly),
+            MONDAY + 5 * 60 * NANOS_PER_MINUTE
+        );
+
+        // At UTC+14 the local Monday began on Sunday at 10:00 UTC
+        assert_eq!(
+            local_period_start(MONDAY, 14 * 60, SummaryPeriod::Weekly),
+            MONDAY - 14 * 60 * NANOS_PER_MINUTE
+        );
+        assert_eq!(local_period_start(0, 14 * 60, SummaryPeriod::Daily), 0);
+    }
+
+    #[test]
+    fn servings_must_be_finite_and_bounded() {
+        for servings in [0.5, 1.0, MAX_SERVINGS] {
+            assert!(validate_servings(servings).is_ok(), "{}", servings);
         }
-    })
-}
+        for servings in [0.0, -1.0, MAX_SERVINGS + 1.0, f64::INFINITY, f64::NAN] {
+            assert!(validate_servings(servings).is_err(), "{}", servings);
+        }
+    }
 
-// Function to retrieve all fitness challenge participants
-#[ic_cdk::query]
-fn get_all_fitness_challenge_participants() -> Result<Vec<FitnessChallengeParticipant>, String> {
-    FITNESS_CHALLENGE_PARTICIPANTS_STORAGE.with(|storage| {
-        let stable_btree_map = &*storage.borrow();
-        let records: Vec<FitnessChallengeParticipant> = stable_btree_map
+    fn planned_meal_plan() -> MealPlan {
+        let item = PlannedMealItem {
+            food_item_id: u64::MAX,
+            food_name: "x".repeat(MAX_FOOD_NAME_LENGTH),
+            servings: MAX_SERVINGS,
+        };
+        let mut meals = Vec::new();
+        for day in ALL_WEEKDAYS {
+            for meal_type in [MealType::Breakfast, MealType::Lunch, MealType::Dinner] {
+                meals.push(PlannedMeal {
+                    day,
+                    meal_type,
+                    items: vec![item.clone(); MAX_PLANNED_MEAL_ITEMS],
+                });
+            }
+        }
+        MealPlan {
+            id: u64::MAX,
+            user_id: u64::MAX,
+            created_by: u64::MAX,
+            week_start: u64::MAX,
+            meals,
+            created_at: u64::MAX,
+            updated_at: u64::MAX,
+            ..Default::default()
+        }
+    }
+
+    // Longer than any conflict check_dietary_restrictions writes for catalogue
+    // items within MAX_FOOD_NAME_LENGTH and MAX_NUTRIENT_AMOUNT
+    fn longest_dietary_conflicts() -> Vec<DietaryConflict> {
+        vec![
+            DietaryConflict {
+                restriction: "x".repeat(20),
+                detail: "x".repeat(150),
+            };
+            MAX_DIETARY_CONFLICTS
+        ]
+    }
+
+    #[test]
+    fn largest_meal_plan_fits_its_storage_bound() {
+        let plan = MealPlan {
+            dietary_conflicts: Some(longest_dietary_conflicts()),
+            ..planned_meal_plan()
+        };
+        assert!(plan.to_bytes().len() <= MealPlan::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn largest_diet_record_and_review_flag_fit_their_storage_bounds() {
+        let item = DietRecordItem {
+            food_item_id: u64::MAX,
+            food_name: "x".repeat(MAX_FOOD_NAME_LENGTH),
+            servings: MAX_SERVINGS,
+            nutrients: Nutrients::default(),
+        };
+        let record = DietRecord {
+            id: u64::MAX,
+            user_id: u64::MAX,
+            items: vec![item; MAX_MEAL_ITEMS],
+            allergy_warnings: vec![
+                AllergyWarning {
+                    allergy_id: u64::MAX,
+                    severity: AllergySeverity::Severe,
+                };
+                MAX_ALLERGY_WARNINGS
+            ],
+            dietary_conflicts: Some(longest_dietary_conflicts()),
+            recorded_at: u64::MAX,
+            ..Default::default()
+        };
+        assert!(record.to_bytes().len() <= DietRecord::MAX_SIZE as usize);
+
+        let flag = DietReviewFlag {
+            id: u64::MAX,
+            user_id: u64::MAX,
+            entry_type: DietEntryType::MealPlan,
+            entry_id: u64::MAX,
+            conflicts: longest_dietary_conflicts(),
+            reviewed_by: Some(u64::MAX),
+            review_note: "x".repeat(MAX_STATUS_REASON_LENGTH),
+            reviewed_at: Some(u64::MAX),
+            created_at: u64::MAX,
+        };
+        assert!(flag.to_bytes().len() <= DietReviewFlag::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn iddsi_levels_match_the_kind_of_item() {
+        use IddsiLevel::*;
+        for level in [
+            Thin,
+            SlightlyThick,
+            MildlyThick,
+            ModeratelyThick,
+            ExtremelyThick,
+        ] {
+            assert!(check_iddsi_level(true, level).is_ok(), "{:?}", level);
+        }
+        for level in [MincedAndMoist, SoftAndBiteSized, Regular] {
+            assert!(check_iddsi_level(true, level).is_err(), "{:?}", level);
+        }
+        for level in [
+            ModeratelyThick,
+            ExtremelyThick,
+            MincedAndMoist,
+            SoftAndBiteSized,
+            Regular,
+        ] {
+            assert!(check_iddsi_level(false, level).is_ok(), "{:?}", level);
+        }
+        for level in [Thin, SlightlyThick, MildlyThick] {
+            assert!(check_iddsi_level(false, level).is_err(), "{:?}", level);
+        }
+    }
+
+    fn food(name: &str, is_drink: bool, iddsi_level: Option<IddsiLevel>, sugar: f64) -> FoodItem {
+        FoodItem {
+            name: name.to_string(),
+            is_drink: Some(is_drink),
+            iddsi_level,
+            nutrients_per_serving: Nutrients {
+                sugar_grams: sugar,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn dietary_restrictions_flag_textures_thin_drinks_and_diet_limits() {
+        assert!(
+            check_dietary_restrictions(1, "Lunch", &[(food("Steak", false, None, 0.0), 1.0)])
+                .is_empty()
+        );
+
+        DIETARY_RESTRICTIONS_STORAGE.with(|storage| {
+            storage.borrow_mut().insert(
+                1,
+                DietaryRestrictions {
+                    user_id: 1,
+                    diets: vec![DietType::Diabetic],
+                    max_food_texture: Some(IddsiLevel::MincedAndMoist),
+                    min_drink_thickness: Some(IddsiLevel::MildlyThick),
+                    ..Default::default()
+                },
+            )
+        });
+
+        let foods = [
+            (food("Steak", false, Some(IddsiLevel::Regular), 0.0), 1.0),
+            (
+                food("Mince", false, Some(IddsiLevel::MincedAndMoist), 0.0),
+                1.0,
+            ),
+            (food("Water", true, Some(IddsiLevel::Thin), 0.0), 1.0),
+            (
+                food("Custard", true, Some(IddsiLevel::ExtremelyThick), 0.0),
+                1.0,
+            ),
+            // Items without a recorded level are not texture checked
+            (food("Toast", false, None, 0.0), 1.0),
+        ];
+        let conflicts = check_dietary_restrictions(1, "Lunch", &foods);
+        let restrictions: Vec<&str> = conflicts
             .iter()
-            .map(|(_, record)| record.clone())
+            .map(|conflict| conflict.restriction.as_str())
             .collect();
-        if records.is_empty() {
-            Err("No fitness challenge participants found.".to_string())
-        } else {
-            Ok(records)
+        assert_eq!(restrictions, vec!["Food texture", "Drink thickness"]);
+        assert!(conflicts[0].detail.starts_with("Lunch: Steak"));
+        assert!(conflicts[1].detail.starts_with("Lunch: Water"));
+
+        // Sugar is totalled over the servings of the whole meal
+        let sweet = food("Jam", false, Some(IddsiLevel::Regular), 5.0);
+        let mut sweet_meal = [(sweet.clone(), 3.0)];
+        assert!(check_dietary_restrictions(1, "Lunch", &sweet_meal)
+            .iter()
+            .all(|conflict| conflict.restriction != "Diabetic diet"));
+        sweet_meal[0].1 = 3.5;
+        assert!(check_dietary_restrictions(1, "Lunch", &sweet_meal)
+            .iter()
+            .any(|conflict| conflict.restriction == "Diabetic diet"));
+    }
+
+    #[test]
+    fn dietary_conflicts_beyond_the_cap_are_counted() {
+        let conflict = DietaryConflict {
+            restriction: "Food texture".to_string(),
+            detail: String::new(),
+        };
+        let kept = cap_dietary_conflicts(vec![conflict.clone(); MAX_DIETARY_CONFLICTS]);
+        assert_eq!(kept.len(), MAX_DIETARY_CONFLICTS);
+        assert_eq!(kept[MAX_DIETARY_CONFLICTS - 1].restriction, "Food texture");
+
+        let capped = cap_dietary_conflicts(vec![conflict; MAX_DIETARY_CONFLICTS + 5]);
+        assert_eq!(capped.len(), MAX_DIETARY_CONFLICTS);
+        assert_eq!(
+            capped[MAX_DIETARY_CONFLICTS - 1].detail,
+            "6 more conflicts are not listed."
+        );
+    }
+
+    #[test]
+    fn percent_handles_large_parts_and_empty_totals() {
+        assert_eq!(percent(1, 3), 33);
+        assert_eq!(percent(0, 0), 100);
+        assert_eq!(percent(u32::MAX, u32::MAX), 100);
+        assert_eq!(percent(u32::MAX, 1), u32::MAX);
+    }
+
+    fn hydration_goal() -> HydrationGoal {
+        HydrationGoal {
+            user_id: 1,
+            daily_goal_ml: 1500,
+            check_time: TimeOfDay {
+                hour: 14,
+                minute: 30,
+            },
+            expected_percent_by_check_time: 50,
+            last_checked_day: MONDAY - NANOS_PER_DAY,
+            ..Default::default()
         }
-    })
-}
+    }
 
-// Error types
-#[derive(candid::CandidType, Deserialize, Serialize)]
-enum Error {
-    NotFound { msg: String },
-    UnAuthorized { msg: String },
-}
+    #[test]
+    fn hydration_check_runs_once_after_the_check_time() {
+        let goal = hydration_goal();
+        let check_at = MONDAY + 14 * NANOS_PER_HOUR + 30 * NANOS_PER_MINUTE;
+        assert!(!is_hydration_check_due(&goal, MONDAY, check_at - 1));
+        assert!(is_hydration_check_due(&goal, MONDAY, check_at));
+        assert!(is_hydration_check_due(
+            &goal,
+            MONDAY,
+            MONDAY + NANOS_PER_DAY - 1
+        ));
 
-// need this to generate candid
-ic_cdk::export_candid!();
+        let checked = HydrationGoal {
+            last_checked_day: MONDAY,
+            ..hydration_goal()
+        };
+        assert!(!is_hydration_check_due(&checked, MONDAY, check_at));
+        assert!(is_hydration_check_due(
+            &checked,
+            MONDAY + NANOS_PER_DAY,
+            check_at + NANOS_PER_DAY
+        ));
+    }
+
+    #[test]
+    fn hydration_shortfall_compares_intake_with_the_expected_pace() {
+        let goal = hydration_goal();
+        assert_eq!(hydration_shortfall(&goal, 0), Some(750));
+        assert_eq!(hydration_shortfall(&goal, 749), Some(750));
+        assert_eq!(hydration_shortfall(&goal, 750), None);
+
+        let largest = HydrationGoal {
+            daily_goal_ml: MAX_DAILY_FLUID_GOAL_ML,
+            expected_percent_by_check_time: 100,
+            ..hydration_goal()
+        };
+        assert_eq!(
+            hydration_shortfall(&largest, 0),
+            Some(MAX_DAILY_FLUID_GOAL_ML as u64)
+        );
+    }
+
+    fn weighing(weight_kg: f64, measured_at: u64, alert_id: Option<u64>) -> WeightEntry {
+        WeightEntry {
+            user_id: 1,
+            weight_kg,
+            alert_id,
+            measured_at,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rapid_weight_gain_is_found_from_earlier_weighings() {
+        let entries = [
+            weighing(70.0, MONDAY, None),
+            weighing(71.0, MONDAY + NANOS_PER_DAY, None),
+        ];
+        let day_3 = MONDAY + 3 * NANOS_PER_DAY;
+        assert_eq!(
+            find_rapid_weight_gain(&entries, 72.5, day_3),
+            Some(WeightGain {
+                from_kg: 70.0,
+                from_at: MONDAY,
+                to_kg: 72.5,
+                to_at: day_3,
+            })
+        );
+        assert_eq!(find_rapid_weight_gain(&entries, 72.0, day_3), None);
+        // A day later the 70 kg reading is out of the window, and 71 kg to
+        // 72.5 kg is within the threshold
+        assert_eq!(
+            find_rapid_weight_gain(&entries, 72.5, day_3 + NANOS_PER_DAY),
+            None
+        );
+    }
+
+    #[test]
+    fn back_dated_low_weighing_is_compared_with_later_ones() {
+        let day_2 = MONDAY + 2 * NANOS_PER_DAY;
+        let entries = [weighing(73.0, day_2, None)];
+        assert_eq!(
+            find_rapid_weight_gain(&entries, 70.5, MONDAY),
+            Some(WeightGain {
+                from_kg: 70.5,
+                from_at: MONDAY,
+                to_kg: 73.0,
+                to_at: day_2,
+            })
+        );
+        assert_eq!(find_rapid_weight_gain(&entries, 71.0, MONDAY), None);
+    }
+
+    #[test]
+    fn rapid_weight_gain_alerts_once_per_episode() {
+        let entries = [
+            weighing(70.0, MONDAY, None),
+            weighing(72.5, MONDAY + NANOS_PER_DAY, Some(7)),
+        ];
+        assert_eq!(
+            find_rapid_weight_gain(&entries, 73.0, MONDAY + 2 * NANOS_PER_DAY),
+            None
+        );
+        // A back-dated weighing before the alerted one is part of the same episode
+        assert_eq!(
+            find_rapid_weight_gain(&entries, 69.0, MONDAY - NANOS_PER_DAY),
+            None
+        );
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_only_when_needed() {
+        assert_eq!(escape_csv_field("Aspirin"), "Aspirin");
+        assert_eq!(escape_csv_field("1,000 mg"), "\"1,000 mg\"");
+        assert_eq!(
+            escape_csv_field("Take \"with food\""),
+            "\"Take \"\"with food\"\"\""
+        );
+        assert_eq!(escape_csv_field("line\r\nbreak"), "\"line\r\nbreak\"");
+        assert_eq!(escape_csv_field("line\nbreak"), "\"line\nbreak\"");
+
+        assert_eq!(
+            to_csv(
+                &["name", "note"],
+                vec![vec!["Aspirin".to_string(), "a,b".to_string()]]
+            ),
+            "name,note\r\nAspirin,\"a,b\"\r\n"
+        );
+    }
+
+    // Checks a message fits both the alert and the notifications it is copied into
+    fn assert_message_fits(message: String) {
+        let notification = Notification {
+            id: u64::MAX,
+            recipient_id: u64::MAX,
+            user_id: u64::MAX,
+            notification_type: NotificationType::DoseMissed,
+            reference_id: Some(u64::MAX),
+            message: message.clone(),
+            created_at: u64::MAX,
+            read_at: Some(u64::MAX),
+        };
+        assert!(
+            notification.to_bytes().len() <= Notification::MAX_SIZE as usize,
+            "{}",
+            message
+        );
+        let alert = Alert {
+            id: u64::MAX,
+            user_id: u64::MAX,
+            alert_type: AlertType::CriticalHealthStatus,
+            message,
+            acknowledged_at: Some(u64::MAX),
+            created_at: u64::MAX,
+        };
+        assert!(alert.to_bytes().len() <= Alert::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn largest_health_record_and_its_alert_fit_their_storage_bounds() {
+        let record = HealthRecord {
+            id: u64::MAX,
+            user_id: u64::MAX,
+            heart_rate: u8::MAX,
+            blood_pressure: "x".repeat(MAX_BLOOD_PRESSURE_LENGTH),
+            activity_level: "x".repeat(MAX_ACTIVITY_LEVEL_LENGTH),
+            status: HealthStatus::Critical,
+            recorded_at: u64::MAX,
+        };
+        assert!(record.to_bytes().len() <= HealthRecord::MAX_SIZE as usize);
+        assert_message_fits(critical_health_message(&record));
+    }
+
+    #[test]
+    fn largest_reminder_messages_fit_a_notification() {
+        let medication_name = "x".repeat(MAX_MEDICATION_NAME_LENGTH);
+        assert!(validate_medication_name(&medication_name).is_ok());
+        assert!(validate_medication_name(&format!("{}x", medication_name)).is_err());
+
+        // The longest renderings of quantities within the dosage bounds
+        for quantity in [0.0010000000000000002, 99999.99999999999] {
+            let reminder = MedicationReminder {
+                medication_name: medication_name.clone(),
+                dosage: dosage(quantity, DoseUnit::Mcg, DosageForm::Injection),
+                ..Default::default()
+            };
+            assert!(validate_dosage(&reminder.dosage).is_ok());
+            assert_message_fits(dose_due_message(&reminder, u64::MAX));
+            assert_message_fits(dose_missed_message(&reminder, u64::MAX));
+        }
+    }
+
+    #[test]
+    fn legacy_reminder_names_are_cut_to_the_name_limit() {
+        let long_name = "é".repeat(MAX_MEDICATION_NAME_LENGTH);
+        let reminder = convert_legacy_medication_reminder(LegacyMedicationReminder {
+            medication_name: long_name.clone(),
+            dosage: "500 mg".to_string(),
+            schedule: "every 8 hours".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(
+            reminder.medication_name,
+            "é".repeat(MAX_MEDICATION_NAME_LENGTH / 2)
+        );
+        assert!(reminder.legacy_entry.unwrap().contains(&long_name));
+    }
+
+    #[test]
+    fn dispatch_notifies_due_doses_once_and_marks_missed_doses() {
+        USERS_STORAGE.with(|storage| {
+            storage.borrow_mut().insert(
+                1,
+                User {
+                    id: 1,
+                    user_type: UserType::Elderly,
+                    ..Default::default()
+                },
+            )
+        });
+        store_reminder(2, 1, "Metformin");
+        let notifications = |notification_type: NotificationType| {
+            NOTIFICATIONS_STORAGE.with(|storage| {
+                storage
+                    .borrow()
+                    .iter()
+                    .filter(|(_, notification)| notification.notification_type == notification_type)
+                    .count()
+            })
+        };
+        let eight = MONDAY + 8 * NANOS_PER_HOUR;
+
+        // The first run only starts the cursor
+        set_time(MONDAY + 7 * NANOS_PER_HOUR);
+        dispatch_due_reminders();
+        assert_eq!(notifications(NotificationType::DoseDue), 0);
+
+        set_time(eight + NANOS_PER_MINUTE);
+        dispatch_due_reminders();
+        assert_eq!(notifications(NotificationType::DoseDue), 1);
+        assert!(find_dose_event(2, eight).is_none());
+
+        // A run that does not move the cursor sends nothing new
+        dispatch_due_reminders();
+        assert_eq!(notifications(NotificationType::DoseDue), 1);
+
+        set_time(eight + DOSE_GRACE_PERIOD_NANOS + NANOS_PER_MINUTE);
+        dispatch_due_reminders();
+        dispatch_due_reminders();
+        assert_eq!(notifications(NotificationType::DoseDue), 1);
+        assert_eq!(notifications(NotificationType::DoseMissed), 1);
+        let missed = find_dose_event(2, eight).unwrap();
+        assert_eq!(missed.status, DoseStatus::Missed);
+        assert_eq!(missed.recorded_by, None);
+    }
+
+    #[test]
+    fn largest_low_supply_alert_fits_its_storage_bound() {
+        let reminder = MedicationReminder {
+            medication_name: "x".repeat(MAX_MEDICATION_NAME_LENGTH),
+            ..Default::default()
+        };
+        assert_message_fits(low_supply_message(&reminder, u32::MAX, u32::MAX));
+    }
+
+    #[test]
+    fn ics_text_escapes_special_characters() {
+        assert_eq!(ics_text("a;b,c\\d"), "a\\;b\\,c\\\\d");
+        assert_eq!(
+            ics_text("one\r\ntwo\nthree\rfour"),
+            "one\\ntwo\\nthree\\nfour"
+        );
+        assert_eq!(ics_text("plain text"), "plain text");
+    }
+
+    #[test]
+    fn ics_fold_splits_lines_at_75_octets() {
+        let short = "x".repeat(75);
+        assert_eq!(ics_fold(&short), short);
+
+        let folded = ics_fold(&"x".repeat(160));
+        let lines: Vec<&str> = folded.split("\r\n").collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line.len() <= 75));
+        assert!(lines[1..].iter().all(|line| line.starts_with(' ')));
+        assert_eq!(folded.replace("\r\n ", ""), "x".repeat(160));
+    }
+
+    #[test]
+    fn ics_fold_keeps_multi_byte_characters_whole() {
+        let line = format!("SUMMARY:{}", "é".repeat(60));
+        let folded = ics_fold(&line);
+        let lines: Vec<&str> = folded.split("\r\n").collect();
+        // "SUMMARY:" plus 33 two-byte characters is 74 octets; a 34th would overflow
+        assert_eq!(lines[0].len(), 74);
+        assert!(lines.iter().all(|line| line.len() <= 75));
+        assert_eq!(folded.replace("\r\n ", ""), line);
+    }
+}